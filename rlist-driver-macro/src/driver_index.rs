@@ -2,7 +2,7 @@ use proc_macro::TokenStream;
 use quote::quote;
 use syn::punctuated::Punctuated;
 use syn::{
-    parse_macro_input, Data, DataEnum, DeriveInput, Expr, Lit, Meta, MetaNameValue, Token, Variant,
+    parse_macro_input, Data, DataEnum, DeriveInput, Expr, Lit, MetaNameValue, Token, Variant,
 };
 
 pub fn rlist_driver_index(_attr: TokenStream, item: TokenStream) -> TokenStream {
@@ -38,6 +38,11 @@ pub fn rlist_driver_index(_attr: TokenStream, item: TokenStream) -> TokenStream
     //      _ => Err(Error::custom("invalid driver")),
     // },
 
+    let mut build_match_arms = Vec::new();
+    // match self {
+    //     DriverIndex::ExampleDriver1(config) => { load_config, then new }     <--- this is the `build_match_arm`
+    // }
+
     let mut driver_enum_list = Vec::new();
     // items in the enum
     // #[rlist_driver_index]
@@ -76,31 +81,32 @@ pub fn rlist_driver_index(_attr: TokenStream, item: TokenStream) -> TokenStream
             })
             .collect::<Vec<_>>();
 
-        // try to get the driver name
-        let driver_name: Expr = args
-            .iter()
-            .find_map(|attr| {
-                // check if the attribute is `name`
-                // #[rlist_driver(name = "example_driver_1")]
-                //                        ^^^^^^^^^^^^^^^^
-                //                        check if the attribute is `name`
-                if let Meta::NameValue(MetaNameValue { path, value, .. }) =
-                    attr.parse_args().unwrap()
-                {
-                    // match arms are for deserialization
-                    // #[rlist_driver(name = "example_driver_1")]
-                    //                        ^^^^^^^^^^^^^^^^
-                    //                        this is the driver name also used in the match arm
-                    if path.is_ident("name") {
-                        Some(value)
-                    } else {
-                        panic!("Each driver must have a `name` in the `rlist_driver` attribute")
-                    }
-                } else {
-                    panic!("Each driver must have a `name` in the `rlist_driver` attribute")
-                }
-            })
-            .unwrap();
+        // try to get the driver name and the driver type
+        // #[rlist_driver(name = "example_driver_1", driver = ExampleDriver1)]
+        //                ^^^^^^^^^^^^^^^^^^^^^^^^^  ^^^^^^^^^^^^^^^^^^^^^^^
+        //                required                   optional, used to build the driver
+        let attr = args
+            .first()
+            .unwrap_or_else(|| panic!("Each driver must have a `rlist_driver` attribute"));
+        let name_values = attr
+            .parse_args_with(Punctuated::<MetaNameValue, Token![,]>::parse_terminated)
+            .unwrap_or_else(|_| {
+                panic!("Each driver must have a `name` in the `rlist_driver` attribute")
+            });
+        let mut driver_name: Option<Expr> = None;
+        let mut driver_type: Option<Expr> = None;
+        for MetaNameValue { path, value, .. } in name_values {
+            if path.is_ident("name") {
+                driver_name = Some(value);
+            } else if path.is_ident("driver") {
+                driver_type = Some(value);
+            } else {
+                panic!("Unknown key in the `rlist_driver` attribute, expected `name` or `driver`")
+            }
+        }
+        let driver_name = driver_name.unwrap_or_else(|| {
+            panic!("Each driver must have a `name` in the `rlist_driver` attribute")
+        });
 
         // check whether the `driver_name` above is like `"example_driver_1"`
         let driver_name = if let Expr::Lit(lit) = driver_name {
@@ -125,12 +131,42 @@ pub fn rlist_driver_index(_attr: TokenStream, item: TokenStream) -> TokenStream
             #driver_name => #type_of_first_field::deserialize(deserializer).map(|c| Box::new(c) as Box<dyn std::any::Any>),
         });
 
+        // fill the `build_match_arms`
+        if let Some(driver_type) = driver_type {
+            build_match_arms.push(quote! {
+                #name::#ident(config) => {
+                    let state = <#driver_type as rlist_vfs::driver::CloudDriver<#type_of_first_field, _>>::load_config(config).await;
+                    Box::new(<#driver_type as rlist_vfs::driver::CloudDriver<#type_of_first_field, _>>::new(state).await)
+                }
+            });
+        }
+
         // fill the `driver_enum_list`
         driver_enum_list.push(driver_name.value());
     } // end of for loop
 
+    // `BuildDriver` is only implemented if every driver has a `driver` type
+    let impl_build_driver = if build_match_arms.is_empty() {
+        quote! {}
+    } else if build_match_arms.len() == variants.len() {
+        quote! {
+            #[async_trait::async_trait]
+            impl rlist_vfs::config::BuildDriver for #name {
+                async fn build(self) -> Box<dyn rlist_vfs::driver::GetVfs> {
+                    match self {
+                        #(#build_match_arms)*
+                    }
+                }
+            }
+        }
+    } else {
+        quote! {
+            compile_error!("Either every driver or no driver must have a `driver` in the `rlist_driver` attribute");
+        }
+    };
+
     let helper = quote! {
-        const FIELDS: &'static [&'static str] = &["driver", "config"];
+        const FIELDS: &'static [&'static str] = &["driver", "config", "transforms", "mount_path", "proxy", "proxy_headers"];
         struct DriverIndexVisitor;
         struct ConfigDeserializer<'a> {
             driver: Option<&'a String>,
@@ -158,19 +194,23 @@ pub fn rlist_driver_index(_attr: TokenStream, item: TokenStream) -> TokenStream
 
     let visitor = quote! {
         impl<'de> Visitor<'de> for DriverIndexVisitor {
-            type Value = #name;
+            type Value = rlist_vfs::config::Entry<#name>;
 
             fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
                 let struct_prefix = String::from("struct ");
                 formatter.write_str(&(struct_prefix + #name_str))
             }
 
-            fn visit_map<A>(self, mut map: A) -> Result<DriverIndex, A::Error>
+            fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
                 where
                     A: MapAccess<'de>,
             {
                 let mut driver = None;
                 let mut config = None;
+                let mut transforms = None;
+                let mut mount_path = None;
+                let mut proxy = None;
+                let mut proxy_headers = None;
 
                 while let Some(key) = map.next_key::<String>()? {
                     match key.as_str() {
@@ -186,15 +226,46 @@ pub fn rlist_driver_index(_attr: TokenStream, item: TokenStream) -> TokenStream
                             }
                             config = Some(map.next_value_seed(ConfigDeserializer { driver: driver.as_ref() })?);
                         },
+                        "transforms" => {
+                            if transforms.is_some() {
+                                return Err(Error::duplicate_field("transforms"));
+                            }
+                            transforms = Some(map.next_value::<Vec<rlist_vfs::transform::Transform>>()?);
+                        },
+                        "mount_path" => {
+                            if mount_path.is_some() {
+                                return Err(Error::duplicate_field("mount_path"));
+                            }
+                            mount_path = Some(map.next_value::<String>()?);
+                        },
+                        "proxy" => {
+                            if proxy.is_some() {
+                                return Err(Error::duplicate_field("proxy"));
+                            }
+                            proxy = Some(map.next_value::<bool>()?);
+                        },
+                        "proxy_headers" => {
+                            if proxy_headers.is_some() {
+                                return Err(Error::duplicate_field("proxy_headers"));
+                            }
+                            proxy_headers = Some(map.next_value::<std::collections::HashMap<String, String>>()?);
+                        },
                         _ => return Err(Error::unknown_field(&key, FIELDS)),
                     }
                 }
 
                 if let (Some(driver), Some(config)) = (driver, config) {
-                    match driver.as_str() {
+                    let index = match driver.as_str() {
                         #(#visitor_match_arms)*
                         _ => Err(Error::custom("unknown driver")),
-                    }
+                    }?;
+                    Ok(rlist_vfs::config::Entry {
+                        index,
+                        transforms: transforms.unwrap_or_default(),
+                        mount_path,
+                        proxy: proxy.unwrap_or_default(),
+                        proxy_headers: proxy_headers.unwrap_or_default(),
+                    })
                 } else {
                     Err(Error::missing_field("driver or config"))
                 }
@@ -202,13 +273,25 @@ pub fn rlist_driver_index(_attr: TokenStream, item: TokenStream) -> TokenStream
         }
     }; // end of visitor
 
+    // `Deserialize` drops the fields next to `driver` and `config`,
+    // deserialize `rlist_vfs::config::Entry<#name>` to keep them
     let impl_deserialize = quote! {
+        impl rlist_vfs::config::DeserializeEntry for #name {
+            fn deserialize_entry<'de, D>(deserializer: D) -> Result<rlist_vfs::config::Entry<Self>, D::Error>
+                where
+                    D: Deserializer<'de>,
+            {
+                deserializer.deserialize_struct(#name_str, FIELDS, DriverIndexVisitor)
+            }
+        }
+
         impl<'de> Deserialize<'de> for #name {
             fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
                 where
                     D: Deserializer<'de>,
             {
-                deserializer.deserialize_struct(#name_str, FIELDS, DriverIndexVisitor)
+                <#name as rlist_vfs::config::DeserializeEntry>::deserialize_entry(deserializer)
+                    .map(|entry| entry.index)
             }
         }
     };
@@ -226,6 +309,7 @@ pub fn rlist_driver_index(_attr: TokenStream, item: TokenStream) -> TokenStream
         #de_seed
         #visitor
         #impl_deserialize
+        #impl_build_driver
     };
 
     TokenStream::from(expanded)
@@ -13,7 +13,13 @@ pub fn auto_get_vfs(input: TokenStream) -> TokenStream {
         #[async_trait::async_trait]
         impl rlist_vfs::driver::GetVfs for #name {
             async fn get_vfs(&self) -> Result<CombinableDir<StaticCombinableFile>, String> {
-                self.reload_vfs().await
+                use rlist_vfs::tracing::Instrument;
+                let span = rlist_vfs::tracing::info_span!("reload_vfs", driver = stringify!(#name));
+                self.reload_vfs().instrument(span).await
+            }
+
+            fn name(&self) -> &str {
+                stringify!(#name)
             }
         }
     };
@@ -10,12 +10,16 @@ pub fn derive_auto_static_combinable(input: TokenStream) -> TokenStream {
     let expanded = quote! {
         impl Into<rlist_vfs::static_combinable::StaticCombinableFile> for #name {
             fn into(self) -> rlist_vfs::static_combinable::StaticCombinableFile {
-                rlist_vfs::static_combinable::StaticCombinableFile {
-                    name: self.name().to_string(),
-                    size: self.size(),
-                    last_modified: self.last_modified(),
-                    links: self.links().clone(),
-                }
+                use rlist_vfs::static_combinable::StaticDownloadLinkFile;
+                rlist_vfs::static_combinable::StaticCombinableFile::new(
+                    self.name().to_string(),
+                    self.size(),
+                    self.last_modified(),
+                    self.links().clone(),
+                )
+                .with_visibility(StaticDownloadLinkFile::visibility(&self))
+                .with_proxy(StaticDownloadLinkFile::proxy(&self))
+                .with_sources(StaticDownloadLinkFile::sources(&self).to_vec())
             }
         }
     };
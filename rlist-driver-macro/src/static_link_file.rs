@@ -18,11 +18,8 @@ pub fn static_download_link_file_derive(input: TokenStream) -> TokenStream {
         if let Fields::Named(fields) = data.fields {
             for field in fields.named {
                 let name = field.ident.as_ref().map(|ident| ident.to_string());
-                if let Some(name) = name.as_deref() {
-                    match name {
-                        "links" => has_links = true,
-                        _ => {}
-                    }
+                if name.as_deref() == Some("links") {
+                    has_links = true;
                 }
             } // end of for field in fields.named
         } else {
@@ -0,0 +1,131 @@
+use rlist_driver_macro::rlist_driver_index;
+use rlist_vfs::config::{BuildDriver, Entry};
+use rlist_vfs::static_driver::{StaticDir, StaticDriver};
+use rlist_vfs::{VfsBasicMeta, VfsDirMeta};
+
+#[rlist_driver_index]
+pub enum DriverIndex {
+    #[rlist_driver(name = "static", driver = StaticDriver)]
+    Static(StaticDir),
+}
+
+#[tokio::test]
+async fn test_build_driver_from_index() {
+    let json = r#"
+    {
+        "driver": "static",
+        "config": {
+            "name": "root",
+            "size": 0,
+            "last_modified": "2021-01-01T00:00:00Z",
+            "files": [],
+            "subdirectories": []
+        }
+    }
+    "#;
+    let index: DriverIndex = serde_json::from_str(json).unwrap();
+    let driver = index.build().await;
+    let vfs = driver.get_vfs().await.unwrap();
+    assert_eq!(vfs.name(), "root");
+}
+
+#[test]
+fn test_unknown_driver() {
+    let json = r#"{"driver": "unknown", "config": {}}"#;
+    assert!(serde_json::from_str::<DriverIndex>(json).is_err());
+}
+
+#[tokio::test]
+async fn test_mount_path() {
+    let json = r#"
+    {
+        "driver": "static",
+        "config": {
+            "name": "root",
+            "size": 0,
+            "last_modified": "2021-01-01T00:00:00Z",
+            "files": [],
+            "subdirectories": []
+        },
+        "mount_path": "/mirrors/eu"
+    }
+    "#;
+    let entry: Entry<DriverIndex> = serde_json::from_str(json).unwrap();
+    assert_eq!(entry.mount_path.as_deref(), Some("/mirrors/eu"));
+    let vfs = entry.build().await.get_vfs().await.unwrap();
+    assert_eq!(vfs.name(), "root");
+    assert_eq!(vfs.subdirectories()[0].name(), "mirrors");
+}
+
+#[tokio::test]
+async fn test_transforms() {
+    let json = r#"
+    {
+        "driver": "static",
+        "config": {
+            "name": "root",
+            "size": 0,
+            "last_modified": "2021-01-01T00:00:00Z",
+            "files": [],
+            "subdirectories": [
+                {
+                    "name": "pub",
+                    "size": 0,
+                    "last_modified": "2021-01-01T00:00:00Z",
+                    "files": [],
+                    "subdirectories": [
+                        {
+                            "name": "inner",
+                            "size": 0,
+                            "last_modified": "2021-01-01T00:00:00Z",
+                            "files": [],
+                            "subdirectories": []
+                        }
+                    ]
+                }
+            ]
+        },
+        "transforms": [{"select": "/pub"}],
+        "mount_path": "/mirror"
+    }
+    "#;
+    let entry: Entry<DriverIndex> = serde_json::from_str(json).unwrap();
+    let vfs = entry.build().await.get_vfs().await.unwrap();
+    let mirror = &vfs.subdirectories()[0];
+    assert_eq!(mirror.name(), "mirror");
+    assert_eq!(mirror.subdirectories()[0].name(), "inner");
+}
+
+#[tokio::test]
+async fn test_proxy() {
+    let json = r#"
+    {
+        "driver": "static",
+        "config": {
+            "name": "root",
+            "size": 0,
+            "last_modified": "2021-01-01T00:00:00Z",
+            "files": [
+                {
+                    "name": "file",
+                    "size": 0,
+                    "last_modified": "2021-01-01T00:00:00Z",
+                    "links": ["https://example.com/file"]
+                }
+            ],
+            "subdirectories": []
+        },
+        "proxy": true,
+        "proxy_headers": {"authorization": "Bearer secret"}
+    }
+    "#;
+    let entry: Entry<DriverIndex> = serde_json::from_str(json).unwrap();
+    assert!(entry.proxy);
+    let driver = entry.build().await;
+    assert_eq!(
+        driver.proxy_headers(),
+        &[("authorization".to_string(), "Bearer secret".to_string())]
+    );
+    let vfs = driver.get_vfs().await.unwrap();
+    assert!(vfs.files()[0].proxy);
+}
@@ -0,0 +1,20 @@
+use rlist_driver_macro::GetVfs;
+use rlist_vfs::combinable_dir::CombinableDir;
+use rlist_vfs::driver::GetVfs;
+use rlist_vfs::static_combinable::StaticCombinableFile;
+
+#[derive(GetVfs)]
+struct EmptyDriver;
+
+impl EmptyDriver {
+    async fn reload_vfs(&self) -> Result<CombinableDir<StaticCombinableFile>, String> {
+        Ok(CombinableDir::new("root".to_string(), vec![], vec![]))
+    }
+}
+
+#[tokio::test]
+async fn test_get_vfs() {
+    let driver: Box<dyn GetVfs> = Box::new(EmptyDriver);
+    assert_eq!(driver.name(), "EmptyDriver");
+    assert!(driver.get_vfs().await.is_ok());
+}
@@ -0,0 +1,27 @@
+use rlist_driver_macro::{StaticCombinableFile, StaticDownloadLinkFile, VfsMeta};
+use rlist_vfs::static_combinable::{StaticCombinableFile, StaticDownloadLinkFile};
+use rlist_vfs::VfsBasicMeta;
+use std::time::SystemTime;
+
+#[derive(Clone, VfsMeta, StaticDownloadLinkFile, StaticCombinableFile)]
+struct MirrorFile {
+    name: String,
+    size: u64,
+    last_modified: SystemTime,
+    links: Vec<String>,
+}
+
+#[test]
+fn test_into_static_combinable_file() {
+    let file = MirrorFile::new(
+        "a.iso".to_string(),
+        1024,
+        SystemTime::UNIX_EPOCH,
+        vec!["https://example.com/a.iso".to_string()],
+    );
+    let file: StaticCombinableFile = file.into();
+    assert_eq!(file.name(), "a.iso");
+    assert_eq!(file.size, 1024);
+    assert_eq!(file.links, vec!["https://example.com/a.iso".to_string()]);
+    assert!(!file.proxy);
+}
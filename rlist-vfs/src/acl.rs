@@ -0,0 +1,494 @@
+use crate::password::Lookup;
+use crate::search::{Query, SearchResults};
+use crate::static_combinable::StaticCombinableFile;
+use crate::tree_json;
+use crate::without_link::DirWithoutLink;
+use crate::Wheel;
+use async_trait::async_trait;
+use chrono::Utc;
+use globset::{GlobBuilder, GlobMatcher};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Who is asking, as told by an [IdentityProvider].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct Identity {
+    /// `None` for anonymous callers.
+    pub user: Option<String>,
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
+
+impl Identity {
+    pub fn anonymous() -> Self {
+        Self::default()
+    }
+
+    pub fn user(name: impl Into<String>, groups: Vec<String>) -> Self {
+        Self {
+            user: Some(name.into()),
+            groups,
+        }
+    }
+}
+
+/// Tells who the caller is from the credentials it presented, like a bearer token.
+///
+/// Implement this trait to plug in the users of the embedding app.
+#[async_trait]
+pub trait IdentityProvider: Send + Sync {
+    /// `None` if the credentials are not valid.
+    async fn identify(&self, credentials: &str) -> Option<Identity>;
+}
+
+/// Identities listed in a config file, keyed by their token, like
+/// `{"secret-token": {"user": "alice", "groups": ["staff"]}}`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct StaticIdentities(HashMap<String, Identity>);
+
+impl StaticIdentities {
+    pub async fn load(path: &Path) -> Result<Self, String> {
+        let json = tokio::fs::read(path)
+            .await
+            .map_err(|e| format!("cannot read identities {}: {}", path.display(), e))?;
+        serde_json::from_slice(&json).map_err(|e| format!("invalid identities: {}", e))
+    }
+}
+
+#[async_trait]
+impl IdentityProvider for StaticIdentities {
+    async fn identify(&self, credentials: &str) -> Option<Identity> {
+        self.0.get(credentials).cloned()
+    }
+}
+
+/// Decides whether a caller may list or download a path.
+///
+/// Paths are relative to the root of the combined tree, like `/internal/report.pdf`.
+/// Implement this trait to plug in the permissions of the embedding app, or use [Acl].
+pub trait AccessPolicy: Send + Sync {
+    fn allows(&self, identity: &Identity, path: &str) -> bool;
+}
+
+/// Who a rule lets in, written `anyone`, `authenticated`, `user:<name>` or `group:<name>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Principal {
+    Anyone,
+    Authenticated,
+    User(String),
+    Group(String),
+}
+
+impl Principal {
+    fn parse(principal: &str) -> Result<Self, String> {
+        match principal.split_once(':') {
+            None if principal == "anyone" => Ok(Principal::Anyone),
+            None if principal == "authenticated" => Ok(Principal::Authenticated),
+            Some(("user", name)) if !name.is_empty() => Ok(Principal::User(name.to_string())),
+            Some(("group", name)) if !name.is_empty() => Ok(Principal::Group(name.to_string())),
+            _ => Err(format!("invalid principal {}", principal)),
+        }
+    }
+
+    fn includes(&self, identity: &Identity) -> bool {
+        match self {
+            Principal::Anyone => true,
+            Principal::Authenticated => identity.user.is_some(),
+            Principal::User(name) => identity.user.as_ref() == Some(name),
+            Principal::Group(name) => identity.groups.contains(name),
+        }
+    }
+}
+
+/// One rule of an [Acl], written like `/internal/** -> group:staff, user:bob`.
+///
+/// In the pattern `*` matches within one path segment and `**` across segments. A pattern
+/// like `/internal/**` also covers the `/internal` directory itself.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct AclRule {
+    pattern: GlobMatcher,
+    allow: Vec<Principal>,
+}
+
+impl AclRule {
+    pub fn new(pattern: &str, allow: Vec<Principal>) -> Result<Self, String> {
+        let pattern = GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| format!("invalid pattern {}: {}", pattern, e))?
+            .compile_matcher();
+        Ok(Self { pattern, allow })
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        self.pattern.is_match(path) || self.pattern.is_match(format!("{}/", path))
+    }
+}
+
+impl TryFrom<String> for AclRule {
+    type Error = String;
+
+    fn try_from(rule: String) -> Result<Self, Self::Error> {
+        let (pattern, allow) = rule.split_once("->").ok_or_else(|| {
+            format!(
+                "invalid rule {}: expected `<pattern> -> <principals>`",
+                rule
+            )
+        })?;
+        let allow = allow
+            .split(',')
+            .map(|x| Principal::parse(x.trim()))
+            .collect::<Result<_, _>>()?;
+        Self::new(pattern.trim(), allow)
+    }
+}
+
+/// Path based access rules, loaded from a JSON array like `["/internal/** -> group:staff"]`.
+///
+/// The first rule whose pattern matches a path decides who may access it, and paths that no
+/// rule matches are open to anyone. A path is only accessible if every directory above it is.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Acl(Vec<AclRule>);
+
+impl Acl {
+    pub fn new(rules: Vec<AclRule>) -> Self {
+        Self(rules)
+    }
+
+    pub async fn load(path: &Path) -> Result<Self, String> {
+        let json = tokio::fs::read(path)
+            .await
+            .map_err(|e| format!("cannot read acl {}: {}", path.display(), e))?;
+        serde_json::from_slice(&json).map_err(|e| format!("invalid acl: {}", e))
+    }
+}
+
+impl AccessPolicy for Acl {
+    fn allows(&self, identity: &Identity, path: &str) -> bool {
+        match self.0.iter().find(|rule| rule.matches(path)) {
+            Some(rule) => rule.allow.iter().any(|x| x.includes(identity)),
+            None => true,
+        }
+    }
+}
+
+/// The trees of a [Wheel] as seen by each caller.
+///
+/// Enforced by the HTTP service built with
+/// [RouterBuilder::with_access_control](crate::server::RouterBuilder::with_access_control).
+/// [webdav::router](crate::webdav::router) does not identify callers and enforces no
+/// [AccessPolicy], so every path it serves is open to anyone who knows its password.
+pub struct AccessControl {
+    wheel: Arc<Wheel>,
+    policy: Arc<dyn AccessPolicy>,
+}
+
+impl AccessControl {
+    pub fn new(wheel: Arc<Wheel>, policy: Arc<dyn AccessPolicy>) -> Self {
+        Self { wheel, policy }
+    }
+
+    /// The listed tree as JSON, without the entries `identity` may not access and with the
+    /// content of password protected directories left out.
+    ///
+    /// Written straight from the listing, like [Wheel::tree], without copying it.
+    pub fn tree(&self, identity: &Identity) -> String {
+        let mut out = Vec::new();
+        // writing to a `Vec` cannot fail
+        tree_json::write_filtered(
+            &mut out,
+            &self.wheel.listing.read(),
+            &self.wheel.passwords.read(),
+            self.allowed(identity),
+        )
+        .unwrap();
+        // `serde_json` only writes UTF-8
+        String::from_utf8(out).unwrap()
+    }
+
+    /// The file at `path`, a key of [Wheel::path_map], if `identity` may download it, see
+    /// [Wheel::lookup] for `password`.
+    ///
+    /// A file that exists but is not accessible is reported the same as a missing one.
+    pub async fn file(
+        &self,
+        identity: &Identity,
+        path: &str,
+        password: Option<&str>,
+    ) -> Lookup<StaticCombinableFile> {
+        if !self.accessible(identity, path) {
+            return Lookup::NotFound;
+        }
+        self.wheel.lookup(path, password).await
+    }
+
+    /// The listed directory at `path`, like [Wheel::list] without a password, with the entries
+    /// `identity` may not access left out like by [AccessControl::tree].
+    ///
+    /// `None` if the directory is missing, not accessible or password protected.
+    pub async fn list(&self, identity: &Identity, path: &str) -> Option<DirWithoutLink> {
+        self.dir(identity, path, None).await.found()
+    }
+
+    /// Like [AccessControl::list], see [Wheel::list] for `password`.
+    pub async fn dir(
+        &self,
+        identity: &Identity,
+        path: &str,
+        password: Option<&str>,
+    ) -> Lookup<DirWithoutLink> {
+        if !self.accessible(identity, path) {
+            return Lookup::NotFound;
+        }
+        let relative = relative(path);
+        let prefix = match relative.is_empty() {
+            true => String::new(),
+            false => format!("/{}", relative),
+        };
+        let allowed = self.allowed(identity);
+        self.wheel
+            .list(path, password)
+            .await
+            .map(|dir| prune(dir, &prefix, &allowed))
+    }
+
+    /// The listed files matching `query`, like [Wheel::search], without those `identity` may
+    /// not access.
+    pub fn search(&self, identity: &Identity, query: &Query) -> Result<SearchResults, String> {
+        self.wheel
+            .search_filtered(query, |path| self.accessible(identity, path))
+    }
+
+    /// Whether `identity` may access the paths of the listing, relative to its root.
+    fn allowed<'a>(&'a self, identity: &'a Identity) -> impl Fn(&str) -> bool + 'a {
+        |path: &str| self.policy.allows(identity, path)
+    }
+
+    /// Whether `identity` may access `path`, a key of [Wheel::path_map] or a directory, and
+    /// every directory above it.
+    fn accessible(&self, identity: &Identity, path: &str) -> bool {
+        let relative = relative(path);
+        if relative.is_empty() {
+            return true;
+        }
+        let allowed = self.allowed(identity);
+        let mut prefix = String::new();
+        relative.split('/').all(|segment| {
+            prefix = format!("{}/{}", prefix, segment);
+            allowed(&prefix)
+        })
+    }
+}
+
+/// `path` without the name of the root, its first segment.
+fn relative(path: &str) -> &str {
+    path.trim_matches('/')
+        .split_once('/')
+        .map_or("", |(_, x)| x)
+}
+
+/// `dir`, at `path` relative to the root, without the entries that are not `allowed`. The sizes
+/// only count the entries that are kept, like in [tree_json::write_filtered].
+fn prune(dir: DirWithoutLink, path: &str, allowed: &impl Fn(&str) -> bool) -> DirWithoutLink {
+    let count = dir.files.len() + dir.subdirectories.len();
+    let files = dir
+        .files
+        .into_iter()
+        .filter(|x| allowed(&format!("{}/{}", path, x.name)))
+        .collect::<Vec<_>>();
+    let subdirectories = dir
+        .subdirectories
+        .into_iter()
+        .filter_map(|x| {
+            let path = format!("{}/{}", path, x.name);
+            allowed(&path).then(|| prune(x, &path, allowed))
+        })
+        .collect::<Vec<_>>();
+    let kept = files.len() + subdirectories.len();
+    let (size, last_modified) = if kept == count {
+        (dir.size, dir.last_modified)
+    } else {
+        let size = files.iter().map(|x| x.size).sum::<u64>()
+            + subdirectories.iter().map(|x| x.size).sum::<u64>();
+        // emptied now, like an empty directory in `CombinableDir::new`
+        let last_modified = files
+            .iter()
+            .map(|x| x.last_modified)
+            .chain(subdirectories.iter().map(|x| x.last_modified))
+            .max()
+            .unwrap_or_else(Utc::now);
+        (size, last_modified)
+    };
+    DirWithoutLink {
+        name: dir.name,
+        files,
+        subdirectories,
+        size,
+        last_modified,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::combinable_dir::CombinableDir;
+    use crate::static_combinable::{test_file, TestDriver};
+
+    type Tree = CombinableDir<StaticCombinableFile>;
+
+    // root
+    // ├── readme
+    // ├── internal
+    // │   ├── report
+    // │   └── public
+    // │       └── notice
+    // └── bob
+    //     └── notes
+    fn generate_tree() -> Tree {
+        let public = CombinableDir::new(
+            "public".to_string(),
+            vec![test_file("notice", 1024)],
+            vec![],
+        );
+        let internal = CombinableDir::new(
+            "internal".to_string(),
+            vec![test_file("report", 1024)],
+            vec![public],
+        );
+        let bob = CombinableDir::new("bob".to_string(), vec![test_file("notes", 1024)], vec![]);
+        CombinableDir::new(
+            "root".to_string(),
+            vec![test_file("readme", 1024)],
+            vec![internal, bob],
+        )
+    }
+
+    fn acl() -> Acl {
+        serde_json::from_str(
+            r#"[
+                "/internal/public/** -> anyone",
+                "/internal/** -> group:staff",
+                "/bob/* -> user:bob"
+            ]"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_acl() {
+        let acl = acl();
+        let anonymous = Identity::anonymous();
+        let staff = Identity::user("alice", vec!["staff".to_string()]);
+        let bob = Identity::user("bob", vec![]);
+
+        assert!(acl.allows(&anonymous, "/readme"));
+        assert!(!acl.allows(&anonymous, "/internal"));
+        assert!(!acl.allows(&anonymous, "/internal/report"));
+        assert!(acl.allows(&staff, "/internal"));
+        assert!(acl.allows(&anonymous, "/internal/public/notice"));
+        assert!(acl.allows(&bob, "/bob/notes"));
+        assert!(!acl.allows(&anonymous, "/bob"));
+        assert!(!acl.allows(&staff, "/bob/notes"));
+    }
+
+    #[test]
+    fn test_invalid_rule() {
+        let result = serde_json::from_str::<Acl>(r#"["/internal/** -> staff"]"#);
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("invalid principal staff"));
+        let result = serde_json::from_str::<Acl>(r#"["/internal/**"]"#);
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_access_control() {
+        let wheel = Wheel::new(vec![Box::new(TestDriver::new(generate_tree()))]).await;
+        let access = AccessControl::new(wheel, Arc::new(acl()));
+        let anonymous = Identity::anonymous();
+        let staff = Identity::user("alice", vec!["staff".to_string()]);
+
+        let tree = access.tree(&anonymous);
+        assert!(tree.contains("readme"));
+        assert!(!tree.contains("internal") && !tree.contains("notice") && !tree.contains("bob"));
+        let json: serde_json::Value = serde_json::from_str(&tree).unwrap();
+        assert_eq!(json["size"], 1024);
+        assert_eq!(json["subdirectories"].as_array().unwrap().len(), 0);
+        let tree = access.tree(&staff);
+        assert!(tree.contains("report") && tree.contains("notice") && !tree.contains("notes"));
+        let json: serde_json::Value = serde_json::from_str(&tree).unwrap();
+        assert_eq!(json["size"], 3 * 1024);
+
+        assert!(access
+            .file(&anonymous, "root/readme", None)
+            .await
+            .found()
+            .is_some());
+        assert!(access
+            .file(&anonymous, "root/internal/report", None)
+            .await
+            .found()
+            .is_none());
+        assert!(access
+            .file(&staff, "root/internal/report", None)
+            .await
+            .found()
+            .is_some());
+        // allowed by its own rule, but the directory above is not
+        assert!(access
+            .file(&anonymous, "root/internal/public/notice", None)
+            .await
+            .found()
+            .is_none());
+        assert!(access
+            .file(&staff, "root/missing", None)
+            .await
+            .found()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_list() {
+        let wheel = Wheel::new(vec![Box::new(TestDriver::new(generate_tree()))]).await;
+        let access = AccessControl::new(wheel, Arc::new(acl()));
+        let anonymous = Identity::anonymous();
+        let staff = Identity::user("alice", vec!["staff".to_string()]);
+        let bob = Identity::user("bob", vec![]);
+
+        let root = access.list(&anonymous, "root").await.unwrap();
+        assert_eq!(root.files.len(), 1);
+        assert!(root.subdirectories.is_empty());
+        assert_eq!(root.size, 1024);
+        let root = access.list(&bob, "root").await.unwrap();
+        assert_eq!(root.subdirectories[0].name, "bob");
+        assert_eq!(root.size, 2 * 1024);
+        let root = access.list(&staff, "root").await.unwrap();
+        assert_eq!(root.subdirectories.len(), 1);
+        assert_eq!(root.size, 3 * 1024);
+
+        assert!(access.list(&anonymous, "root/internal").await.is_none());
+        assert!(access
+            .list(&anonymous, "root/internal/public")
+            .await
+            .is_none());
+        let internal = access.list(&staff, "root/internal").await.unwrap();
+        assert_eq!(internal.files[0].name, "report");
+        assert_eq!(internal.subdirectories[0].files[0].name, "notice");
+        assert!(access.list(&staff, "root/missing").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_static_identities() {
+        let identities: StaticIdentities =
+            serde_json::from_str(r#"{"token": {"user": "alice", "groups": ["staff"]}}"#).unwrap();
+        assert_eq!(
+            identities.identify("token").await,
+            Some(Identity::user("alice", vec!["staff".to_string()]))
+        );
+        assert_eq!(identities.identify("other").await, None);
+    }
+}
@@ -1,5 +1,6 @@
 use crate::combinable::Combinable;
 use crate::static_combinable::StaticDownloadLinkFile;
+use crate::visibility::Visibility;
 use crate::{VfsBasicMeta, VfsDirMeta};
 use std::collections::HashMap;
 use std::time::SystemTime;
@@ -11,6 +12,7 @@ pub struct CombinableDir<File: StaticDownloadLinkFile> {
     subdirectories: Vec<CombinableDir<File>>,
     size: u64,
     last_modified: SystemTime,
+    visibility: Visibility,
 }
 
 impl<File: StaticDownloadLinkFile> CombinableDir<File> {
@@ -34,9 +36,19 @@ impl<File: StaticDownloadLinkFile> CombinableDir<File> {
             subdirectories,
             size,
             last_modified,
+            visibility: Visibility::Public,
         }
     }
 
+    pub fn visibility(&self) -> Visibility {
+        self.visibility
+    }
+
+    pub fn with_visibility(mut self, visibility: Visibility) -> Self {
+        self.visibility = visibility;
+        self
+    }
+
     /// Destructor, returns
     /// 1. name
     /// 2. files
@@ -45,6 +57,29 @@ impl<File: StaticDownloadLinkFile> CombinableDir<File> {
         (self.name, self.files, self.subdirectories)
     }
 
+    /// Like [destruct](CombinableDir::destruct), but the visibility of this directory is
+    /// passed on to its content, for when the content outlives the directory.
+    pub fn into_content(self) -> (Vec<File>, Vec<CombinableDir<File>>) {
+        let visibility = self.visibility;
+        let files = self
+            .files
+            .into_iter()
+            .map(|x| {
+                let restricted = x.visibility().max(visibility);
+                x.with_visibility(restricted)
+            })
+            .collect();
+        let subdirectories = self
+            .subdirectories
+            .into_iter()
+            .map(|x| {
+                let restricted = x.visibility.max(visibility);
+                x.with_visibility(restricted)
+            })
+            .collect();
+        (files, subdirectories)
+    }
+
     /// Move the root to the given path
     pub fn mount(self, path: Vec<String>) -> CombinableDir<File> {
         let path_reverse = path.into_iter().rev().collect::<Vec<_>>();
@@ -52,7 +87,7 @@ impl<File: StaticDownloadLinkFile> CombinableDir<File> {
         for name in path_reverse {
             dir = CombinableDir::new(name, vec![], vec![dir]);
         }
-        return dir;
+        dir
     }
 
     pub fn compress_path(self) -> HashMap<String, File> {
@@ -104,38 +139,43 @@ impl<File: StaticDownloadLinkFile> VfsDirMeta<File> for CombinableDir<File> {
 }
 
 impl<File: StaticDownloadLinkFile> Combinable for CombinableDir<File> {
+    /// The combined directory has the least restricted visibility of `from`, and the content
+    /// of each directory keeps the visibility of the directory it came from, see
+    /// [into_content](CombinableDir::into_content), so a directory restricted by one driver
+    /// does not restrict what the others put in it.
     fn combine(from: Vec<Self>) -> Self {
-        let from = from.into_iter().map(|x| x.destruct()).collect::<Vec<_>>();
-        let new_name = from[0].0.clone();
+        let visibility = from.iter().map(|x| x.visibility).min().unwrap_or_default();
+        let new_name = from[0].name.clone();
         let (files, subdirectories): (Vec<Vec<File>>, Vec<Vec<CombinableDir<File>>>) =
-            from.into_iter().map(|x| (x.1, x.2)).unzip();
+            from.into_iter().map(|x| x.into_content()).unzip();
         let files = files.into_iter().flatten().collect::<Vec<_>>();
         let subdirectories = subdirectories.into_iter().flatten().collect::<Vec<_>>();
         let files = divide_by_name(files);
-        let files = files
-            .into_iter()
-            .map(|(_, files)| File::combine(files))
-            .collect::<Vec<_>>();
+        let files = files.into_iter().map(File::combine).collect::<Vec<_>>();
         let subdirectories = divide_by_name(subdirectories);
         let subdirectories = subdirectories
             .into_iter()
-            .map(|(_, subdirectories)| CombinableDir::combine(subdirectories))
+            .map(CombinableDir::combine)
             .collect::<Vec<_>>();
-        return CombinableDir::new(new_name, files, subdirectories);
+        CombinableDir::new(new_name, files, subdirectories).with_visibility(visibility)
     }
 }
 
-fn divide_by_name<T: VfsBasicMeta>(items: Vec<T>) -> HashMap<String, Vec<T>> {
-    let mut map: HashMap<String, Vec<T>> = HashMap::new();
+/// Group items by name, groups keep the order in which their names first appear.
+fn divide_by_name<T: VfsBasicMeta>(items: Vec<T>) -> Vec<Vec<T>> {
+    let mut index: HashMap<String, usize> = HashMap::new();
+    let mut groups: Vec<Vec<T>> = Vec::new();
     for item in items {
         let name = item.name().to_string();
-        if map.contains_key(&name) {
-            map.get_mut(&name).unwrap().push(item);
-        } else {
-            map.insert(name, vec![item]);
+        match index.get(&name) {
+            Some(&i) => groups[i].push(item),
+            None => {
+                index.insert(name, groups.len());
+                groups.push(vec![item]);
+            }
         }
     }
-    map
+    groups
 }
 
 #[cfg(test)]
@@ -156,12 +196,7 @@ mod tests {
             .map(|x| format!("{}/{}", x, name))
             .collect();
 
-        StaticCombinableFile {
-            name: name.to_string(),
-            size,
-            last_modified: time,
-            links,
-        }
+        StaticCombinableFile::new(name.to_string(), size, time, links)
     }
 
     #[test]
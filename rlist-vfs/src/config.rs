@@ -0,0 +1,384 @@
+use crate::driver::GetVfs;
+use crate::transform::{Transform, Transformed};
+use crate::{DriverId, Wheel};
+use async_trait::async_trait;
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Duration};
+
+#[async_trait]
+/// Build the driver described by one entry of the config file.
+///
+/// You should implement this trait by using `#[rlist_driver_index]` with a `driver` for every
+/// variant, like `#[rlist_driver(name = "onedrive", driver = OnedriveDriver)]`.
+pub trait BuildDriver: Send {
+    /// Load the state from the config and create the driver, see [CloudDriver](crate::driver::CloudDriver).
+    async fn build(self) -> Box<dyn GetVfs>;
+}
+
+/// One entry of the config file, like
+/// `{"driver": "onedrive", "config": {...}, "transforms": [{"select": "/pub"}], "mount_path": "/mirrors/eu", "proxy": true,
+/// "proxy_headers": {"Authorization": "Bearer ..."}}`.
+pub struct Entry<Index> {
+    pub index: Index,
+    /// Applied in order to the driver's tree, see [Transform].
+    pub transforms: Vec<Transform>,
+    /// Where the content of the driver's root is exposed after `transforms`, the root if `None`.
+    pub mount_path: Option<String>,
+    /// Stream the driver's files through rlist instead of redirecting to them, see
+    /// [Transform::Proxy].
+    pub proxy: bool,
+    /// Sent with every request to the links of the driver when its files are proxied, see
+    /// [GetVfs::proxy_headers].
+    pub proxy_headers: HashMap<String, String>,
+}
+
+/// Deserialize an [Entry] together with the fields next to `driver` and `config`.
+///
+/// You should implement this trait by using `#[rlist_driver_index]`.
+pub trait DeserializeEntry: Sized {
+    fn deserialize_entry<'de, D>(deserializer: D) -> Result<Entry<Self>, D::Error>
+    where
+        D: Deserializer<'de>;
+}
+
+impl<'de, Index: DeserializeEntry> Deserialize<'de> for Entry<Index> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Index::deserialize_entry(deserializer)
+    }
+}
+
+impl<Index: BuildDriver> Entry<Index> {
+    /// Build the driver, with `transforms`, then `mount_path` and `proxy` applied to its tree,
+    /// and `proxy_headers` sent to its links.
+    pub async fn build(self) -> Box<dyn GetVfs> {
+        let driver = self.index.build().await;
+        let mut transforms = self.transforms;
+        transforms.extend(self.mount_path.map(Transform::Mount));
+        if self.proxy {
+            transforms.push(Transform::Proxy);
+        }
+        if transforms.is_empty() && self.proxy_headers.is_empty() {
+            return driver;
+        }
+        let proxy_headers = self.proxy_headers.into_iter().collect();
+        Box::new(Transformed::new(driver, transforms).with_proxy_headers(proxy_headers))
+    }
+}
+
+/// What a [ConfigReloader::reload] changed.
+#[derive(Debug, Default)]
+pub struct Reload {
+    pub added: Vec<DriverId>,
+    pub removed: Vec<DriverId>,
+    /// Entries whose driver could not provide its tree, they are retried on the next reload.
+    pub errors: Vec<String>,
+}
+
+/// Keeps the drivers of a [Wheel] in sync with a config file.
+///
+/// The file is a JSON array of [Entry], like `[{"driver": "onedrive", "config": {...}}]`.
+/// Drivers that were not added by the reloader are left alone.
+pub struct ConfigReloader<Index> {
+    path: PathBuf,
+    wheel: Arc<Wheel>,
+    /// Canonical JSON of every applied entry and the driver built from it.
+    applied: Vec<(String, DriverId)>,
+    /// Content of the file at the last reload that had no errors.
+    last_content: Option<Vec<u8>>,
+    _index: PhantomData<fn() -> Index>,
+}
+
+impl<Index> ConfigReloader<Index>
+where
+    Index: BuildDriver + DeserializeEntry + 'static,
+{
+    pub fn new(wheel: Arc<Wheel>, path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            wheel,
+            applied: Vec::new(),
+            last_content: None,
+            _index: PhantomData,
+        }
+    }
+
+    /// Read the config file and reconcile the drivers of the [Wheel] with it.
+    ///
+    /// Unchanged entries keep their driver, new entries are built and registered, and removed
+    /// entries are unregistered, all in one update of the [Wheel], see [Wheel::replace_drivers].
+    /// An entry whose config changed counts as removed and new, so its old driver is served
+    /// until the new one has listed its tree.
+    ///
+    /// If the file cannot be read or any entry is invalid, nothing is changed. If a new entry
+    /// cannot provide its tree, the others are registered but no driver is removed, so a
+    /// failed replacement never takes a mount away.
+    pub async fn reload(&mut self) -> Result<Reload, String> {
+        let content = tokio::fs::read(&self.path)
+            .await
+            .map_err(|e| format!("cannot read config {}: {}", self.path.display(), e))?;
+        if self.last_content.as_ref() == Some(&content) {
+            return Ok(Reload::default());
+        }
+        let entries = parse_entries::<Index>(&content)?;
+
+        let mut kept = vec![false; self.applied.len()];
+        let mut new_entries = Vec::new();
+        for (key, entry) in entries {
+            let unchanged = self
+                .applied
+                .iter()
+                .enumerate()
+                .position(|(i, (applied, _))| !kept[i] && *applied == key);
+            match unchanged {
+                Some(i) => kept[i] = true,
+                None => new_entries.push((key, entry)),
+            }
+        }
+
+        let mut drivers = Vec::with_capacity(new_entries.len());
+        let mut keys = Vec::with_capacity(new_entries.len());
+        for (key, entry) in new_entries {
+            drivers.push(entry.build().await);
+            keys.push(key);
+        }
+        let mut stale = vec![];
+        for ((key, id), kept) in std::mem::take(&mut self.applied).into_iter().zip(kept) {
+            match kept {
+                true => self.applied.push((key, id)),
+                false => stale.push((key, id)),
+            }
+        }
+        let ids = stale.iter().map(|(_, id)| *id).collect::<Vec<_>>();
+        let (added, removed) = self.wheel.replace_drivers(&ids, drivers).await;
+
+        let mut reload = Reload {
+            removed,
+            ..Reload::default()
+        };
+        // kept until every new entry is loaded, and removed on the next reload
+        self.applied.extend(
+            stale
+                .into_iter()
+                .filter(|(_, id)| !reload.removed.contains(id)),
+        );
+        for (key, result) in keys.into_iter().zip(added) {
+            match result {
+                Ok(id) => {
+                    self.applied.push((key, id));
+                    reload.added.push(id);
+                }
+                Err(e) => reload.errors.push(e),
+            }
+        }
+        // retry the failed entries next time even if the file does not change
+        self.last_content = reload.errors.is_empty().then_some(content);
+        Ok(reload)
+    }
+
+    /// Reload now, then every `interval`, until the returned task is aborted.
+    pub fn watch(mut self, interval: Duration) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                match self.reload().await {
+                    Ok(reload) => {
+                        for error in reload.errors {
+                            tracing::warn!(%error, "config entry not loaded");
+                        }
+                    }
+                    Err(error) => tracing::warn!(%error, "cannot reload config"),
+                }
+                time::sleep(interval).await;
+            }
+        })
+    }
+}
+
+/// Deserialize every entry and pair it with its canonical JSON, which identifies it.
+fn parse_entries<Index: DeserializeEntry>(
+    content: &[u8],
+) -> Result<Vec<(String, Entry<Index>)>, String> {
+    let values: Vec<serde_json::Value> =
+        serde_json::from_slice(content).map_err(|e| format!("invalid config: {}", e))?;
+    values
+        .into_iter()
+        .enumerate()
+        .map(|(i, value)| {
+            let key = canonical(&value);
+            let entry = serde_json::from_value(value)
+                .map_err(|e| format!("invalid config entry {}: {}", i, e))?;
+            Ok((key, entry))
+        })
+        .collect()
+}
+
+/// `value` as JSON with the keys of every object sorted, so equal entries give equal strings
+/// whatever order they are written and kept in.
+fn canonical(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::Object(map) => {
+            let mut entries = map.iter().collect::<Vec<_>>();
+            entries.sort_by_key(|(key, _)| *key);
+            let entries = entries
+                .into_iter()
+                .map(|(key, value)| {
+                    format!(
+                        "{}:{}",
+                        serde_json::Value::from(key.as_str()),
+                        canonical(value)
+                    )
+                })
+                .collect::<Vec<_>>();
+            format!("{{{}}}", entries.join(","))
+        }
+        serde_json::Value::Array(values) => {
+            let values = values.iter().map(canonical).collect::<Vec<_>>();
+            format!("[{}]", values.join(","))
+        }
+        value => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::static_combinable::{test_paths, TestDriver};
+
+    /// Same shape as the deserializer generated by `#[rlist_driver_index]`.
+    #[derive(Deserialize)]
+    #[serde(tag = "driver", content = "config")]
+    enum DriverIndex {
+        #[serde(rename = "file")]
+        File(String),
+    }
+
+    impl DeserializeEntry for DriverIndex {
+        fn deserialize_entry<'de, D>(deserializer: D) -> Result<Entry<Self>, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            #[derive(Deserialize)]
+            struct Raw {
+                #[serde(flatten)]
+                index: DriverIndex,
+                #[serde(default)]
+                transforms: Vec<Transform>,
+                mount_path: Option<String>,
+                #[serde(default)]
+                proxy: bool,
+                #[serde(default)]
+                proxy_headers: HashMap<String, String>,
+            }
+            let raw = Raw::deserialize(deserializer)?;
+            Ok(Entry {
+                index: raw.index,
+                transforms: raw.transforms,
+                mount_path: raw.mount_path,
+                proxy: raw.proxy,
+                proxy_headers: raw.proxy_headers,
+            })
+        }
+    }
+
+    #[async_trait]
+    impl BuildDriver for DriverIndex {
+        async fn build(self) -> Box<dyn GetVfs> {
+            match self {
+                DriverIndex::File(name) if name == "broken" => {
+                    Box::new(TestDriver::failing("broken driver"))
+                }
+                DriverIndex::File(name) => Box::new(TestDriver::file(&name)),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let path = std::env::temp_dir().join(format!("rlist-config-{}.json", std::process::id()));
+        let write = |content: &'static str| {
+            let path = path.clone();
+            async move { tokio::fs::write(&path, content).await.unwrap() }
+        };
+        let wheel = Wheel::new(vec![]).await;
+        let mut reloader = ConfigReloader::<DriverIndex>::new(wheel.clone(), &path);
+
+        write(r#"[{"driver":"file","config":"a"},{"driver":"file","config":"b"}]"#).await;
+        let reload = reloader.reload().await.unwrap();
+        assert_eq!(reload.added.len(), 2);
+        assert_eq!(test_paths(&wheel.path_map.read()), vec!["root/a", "root/b"]);
+        let b = reload.added[1];
+
+        // unchanged file
+        let reload = reloader.reload().await.unwrap();
+        assert!(reload.added.is_empty() && reload.removed.is_empty());
+
+        // `a` removed, `b` kept, `c` added
+        write(r#"[{"config":"b","driver":"file"},{"driver":"file","config":"c"}]"#).await;
+        let reload = reloader.reload().await.unwrap();
+        assert_eq!(reload.removed.len(), 1);
+        assert_eq!(reload.added.len(), 1);
+        assert!(wheel.driver_ids().contains(&b));
+        assert_eq!(test_paths(&wheel.path_map.read()), vec!["root/b", "root/c"]);
+
+        // invalid entries change nothing
+        write(r#"[{"driver":"unknown","config":"d"}]"#).await;
+        assert!(reloader.reload().await.is_err());
+        assert_eq!(test_paths(&wheel.path_map.read()), vec!["root/b", "root/c"]);
+
+        // failing drivers are reported and retried, and replace nothing until they load
+        write(r#"[{"driver":"file","config":"broken"},{"driver":"file","config":"d"}]"#).await;
+        let reload = reloader.reload().await.unwrap();
+        assert_eq!(reload.errors, vec!["broken driver"]);
+        assert_eq!(reload.added.len(), 1);
+        assert!(reload.removed.is_empty());
+        assert_eq!(
+            test_paths(&wheel.path_map.read()),
+            vec!["root/b", "root/c", "root/d"]
+        );
+        let reload = reloader.reload().await.unwrap();
+        assert_eq!(reload.errors.len(), 1);
+        assert!(reload.added.is_empty());
+
+        write(r#"[{"driver":"file","config":"d"}]"#).await;
+        let reload = reloader.reload().await.unwrap();
+        assert_eq!(reload.removed.len(), 2);
+        assert_eq!(test_paths(&wheel.path_map.read()), vec!["root/d"]);
+
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+
+    #[test]
+    fn test_canonical() {
+        let value = serde_json::json!({"b": 1, "a": {"d": [{"f": 1, "e": "x"}], "c": null}});
+        assert_eq!(
+            canonical(&value),
+            r#"{"a":{"c":null,"d":[{"e":"x","f":1}]},"b":1}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mount_path() {
+        let path = std::env::temp_dir().join(format!("rlist-mount-{}.json", std::process::id()));
+        tokio::fs::write(
+            &path,
+            r#"[{"driver":"file","config":"a","mount_path":"/mirrors/eu"},{"driver":"file","config":"b"}]"#,
+        )
+        .await
+        .unwrap();
+        let wheel = Wheel::new(vec![]).await;
+        let mut reloader = ConfigReloader::<DriverIndex>::new(wheel.clone(), &path);
+        reloader.reload().await.unwrap();
+        tokio::fs::remove_file(&path).await.unwrap();
+        assert_eq!(
+            test_paths(&wheel.path_map.read()),
+            vec!["root/b", "root/mirrors/eu/a"]
+        );
+    }
+}
@@ -0,0 +1,171 @@
+use crate::combinable_dir::CombinableDir;
+use crate::static_combinable::StaticCombinableFile;
+use crate::{VfsBasicMeta, VfsDirMeta};
+use std::collections::HashMap;
+
+type Tree = CombinableDir<StaticCombinableFile>;
+
+/// The files that differ between two trees, by path, like the keys of
+/// [Wheel::path_map](crate::Wheel::path_map).
+#[derive(Default)]
+pub struct TreeDiff<'a> {
+    pub added: Vec<(String, &'a StaticCombinableFile)>,
+    /// Files whose size, modification time, links, visibility or proxying changed.
+    pub modified: Vec<(String, &'a StaticCombinableFile)>,
+    pub removed: Vec<String>,
+}
+
+impl TreeDiff<'_> {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.modified.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// What changed from `old` to `new`.
+///
+/// Directories are matched by name, so a renamed directory is removed and added again. The
+/// walk visits every entry of both trees, so it costs time in proportion to their size, but
+/// does not allocate for unchanged files.
+pub fn diff<'a>(old: &Tree, new: &'a Tree) -> TreeDiff<'a> {
+    let mut diff = TreeDiff::default();
+    if old.name() == new.name() {
+        diff_dir(old.name(), old, new, &mut diff);
+    } else {
+        removed(old.name(), old, &mut diff);
+        added(new.name(), new, &mut diff);
+    }
+    diff
+}
+
+fn diff_dir<'a>(path: &str, old: &Tree, new: &'a Tree, diff: &mut TreeDiff<'a>) {
+    let mut old_files = old
+        .files()
+        .iter()
+        .map(|x| (x.name(), x))
+        .collect::<HashMap<_, _>>();
+    for file in new.files() {
+        match old_files.remove(file.name()) {
+            Some(old_file) if same(old_file, file) => {}
+            Some(_) => diff.modified.push((join(path, file.name()), file)),
+            None => diff.added.push((join(path, file.name()), file)),
+        }
+    }
+    diff.removed
+        .extend(old_files.into_keys().map(|name| join(path, name)));
+
+    let mut old_subdirectories = old
+        .subdirectories()
+        .iter()
+        .map(|x| (x.name(), x))
+        .collect::<HashMap<_, _>>();
+    for subdirectory in new.subdirectories() {
+        let subpath = join(path, subdirectory.name());
+        match old_subdirectories.remove(subdirectory.name()) {
+            Some(old_subdirectory) => diff_dir(&subpath, old_subdirectory, subdirectory, diff),
+            None => added(&subpath, subdirectory, diff),
+        }
+    }
+    for (name, subdirectory) in old_subdirectories {
+        removed(&join(path, name), subdirectory, diff);
+    }
+}
+
+fn added<'a>(path: &str, dir: &'a Tree, diff: &mut TreeDiff<'a>) {
+    for file in dir.files() {
+        diff.added.push((join(path, file.name()), file));
+    }
+    for subdirectory in dir.subdirectories() {
+        added(&join(path, subdirectory.name()), subdirectory, diff);
+    }
+}
+
+fn removed(path: &str, dir: &Tree, diff: &mut TreeDiff) {
+    for file in dir.files() {
+        diff.removed.push(join(path, file.name()));
+    }
+    for subdirectory in dir.subdirectories() {
+        removed(&join(path, subdirectory.name()), subdirectory, diff);
+    }
+}
+
+fn same(a: &StaticCombinableFile, b: &StaticCombinableFile) -> bool {
+    a.size == b.size
+        && a.last_modified == b.last_modified
+        && a.links == b.links
+        && a.visibility == b.visibility
+        && a.proxy == b.proxy
+}
+
+fn join(path: &str, name: &str) -> String {
+    format!("{}/{}", path, name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::static_combinable::test_file;
+
+    fn paths(files: &[(String, &StaticCombinableFile)]) -> Vec<String> {
+        let mut paths = files.iter().map(|(x, _)| x.clone()).collect::<Vec<_>>();
+        paths.sort();
+        paths
+    }
+
+    // root                     root
+    // ├── kept                 ├── kept
+    // ├── resized              ├── resized (2048)
+    // ├── gone                 ├── new
+    // ├── same                 ├── same
+    // │   └── file             │   └── file
+    // └── old                  └── added
+    //     └── file                 └── sub
+    //                                  └── file
+    #[test]
+    fn test_diff() {
+        let same = CombinableDir::new("same".to_string(), vec![test_file("file", 1)], vec![]);
+        let old = CombinableDir::new(
+            "root".to_string(),
+            vec![
+                test_file("kept", 1),
+                test_file("resized", 1024),
+                test_file("gone", 1),
+            ],
+            vec![
+                same.clone(),
+                CombinableDir::new("old".to_string(), vec![test_file("file", 1)], vec![]),
+            ],
+        );
+        let sub = CombinableDir::new("sub".to_string(), vec![test_file("file", 1)], vec![]);
+        let new = CombinableDir::new(
+            "root".to_string(),
+            vec![
+                test_file("kept", 1),
+                test_file("resized", 2048),
+                test_file("new", 1),
+            ],
+            vec![
+                same,
+                CombinableDir::new("added".to_string(), vec![], vec![sub]),
+            ],
+        );
+
+        let diff = diff(&old, &new);
+        assert_eq!(paths(&diff.added), vec!["root/added/sub/file", "root/new"]);
+        assert_eq!(paths(&diff.modified), vec!["root/resized"]);
+        assert_eq!(diff.modified[0].1.size, 2048);
+        let mut removed = diff.removed;
+        removed.sort();
+        assert_eq!(removed, vec!["root/gone", "root/old/file"]);
+
+        assert!(super::diff(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn test_renamed_root() {
+        let old = CombinableDir::new("old".to_string(), vec![test_file("file", 1)], vec![]);
+        let new = CombinableDir::new("new".to_string(), vec![test_file("file", 1)], vec![]);
+        let diff = diff(&old, &new);
+        assert_eq!(paths(&diff.added), vec!["new/file"]);
+        assert_eq!(diff.removed, vec!["old/file"]);
+    }
+}
@@ -44,4 +44,15 @@ where
 pub trait GetVfs: Send + Sync {
     /// You should implement this trait by using `#[derive(GetVfs)]`.
     async fn get_vfs(&self) -> Result<CombinableDir<StaticCombinableFile>, String>;
+
+    /// The name of the driver in logs, the type name by default.
+    fn name(&self) -> &str {
+        std::any::type_name::<Self>()
+    }
+
+    /// Headers sent with every request to the links of this driver when its files are
+    /// proxied, like credentials, see `Proxy::with_headers`. None by default.
+    fn proxy_headers(&self) -> &[(String, String)] {
+        &[]
+    }
 }
@@ -0,0 +1,361 @@
+use crate::combinable_dir::CombinableDir;
+use crate::static_combinable::{StaticCombinableFile, StaticDownloadLinkFile};
+use crate::visibility::Visibility;
+use crate::{VfsBasicMeta, VfsDirMeta};
+use chrono::{DateTime, Utc};
+use globset::{Glob, GlobMatcher};
+use regex::Regex;
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::time::SystemTime;
+
+type Tree = CombinableDir<StaticCombinableFile>;
+
+/// One predicate of a [Filter], written like `{"glob": "**/.DS_Store"}` or `{"smaller_than": 1}`.
+///
+/// Paths are matched relative to the driver's root and without a leading slash, like
+/// `pub/releases/file.iso`. A `*` in a glob also matches `/`, so `*.part` matches at any depth.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rule {
+    Glob(String),
+    Regex(String),
+    /// Files larger than this many bytes.
+    LargerThan(u64),
+    /// Files smaller than this many bytes.
+    SmallerThan(u64),
+    /// Files modified after this time.
+    NewerThan(DateTime<Utc>),
+    /// Files modified before this time.
+    OlderThan(DateTime<Utc>),
+}
+
+/// The rules of a [Filter] as written in the config file.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct FilterRules {
+    /// If not empty, only files matching one of these rules are kept.
+    #[serde(default)]
+    pub include: Vec<Rule>,
+    /// Files and directories matching one of these rules are dropped.
+    #[serde(default)]
+    pub exclude: Vec<Rule>,
+    /// Files and directories matching one of these rules become [Visibility::Hidden].
+    #[serde(default)]
+    pub hide: Vec<Rule>,
+    /// Files and directories matching one of these rules become [Visibility::Private].
+    #[serde(default)]
+    pub private: Vec<Rule>,
+}
+
+#[derive(Debug, Clone)]
+enum Matcher {
+    Glob(GlobMatcher),
+    Regex(Regex),
+    LargerThan(u64),
+    SmallerThan(u64),
+    NewerThan(SystemTime),
+    OlderThan(SystemTime),
+}
+
+impl Matcher {
+    fn new(rule: &Rule) -> Result<Self, String> {
+        Ok(match rule {
+            Rule::Glob(glob) => Matcher::Glob(
+                Glob::new(glob)
+                    .map_err(|e| format!("invalid glob {}: {}", glob, e))?
+                    .compile_matcher(),
+            ),
+            Rule::Regex(regex) => Matcher::Regex(
+                Regex::new(regex).map_err(|e| format!("invalid regex {}: {}", regex, e))?,
+            ),
+            Rule::LargerThan(size) => Matcher::LargerThan(*size),
+            Rule::SmallerThan(size) => Matcher::SmallerThan(*size),
+            Rule::NewerThan(time) => Matcher::NewerThan((*time).into()),
+            Rule::OlderThan(time) => Matcher::OlderThan((*time).into()),
+        })
+    }
+
+    fn matches_path(&self, path: &str) -> bool {
+        match self {
+            Matcher::Glob(glob) => glob.is_match(path),
+            Matcher::Regex(regex) => regex.is_match(path),
+            _ => false,
+        }
+    }
+
+    fn matches_file(&self, path: &str, file: &StaticCombinableFile) -> bool {
+        match self {
+            Matcher::LargerThan(size) => file.size > *size,
+            Matcher::SmallerThan(size) => file.size < *size,
+            Matcher::NewerThan(time) => file.last_modified > *time,
+            Matcher::OlderThan(time) => file.last_modified < *time,
+            _ => self.matches_path(path),
+        }
+    }
+}
+
+/// Drops unwanted files and directories from a tree before it is combined, and restricts the
+/// [Visibility] of others.
+///
+/// Size and time rules only apply to files, and `include` rules only select files, so
+/// directories are kept as long as something in them is. A directory that only had dropped
+/// entries is dropped too, which keeps directory sizes in line with what is served.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(try_from = "FilterRules")]
+pub struct Filter {
+    rules: FilterRules,
+    include: Vec<Matcher>,
+    exclude: Vec<Matcher>,
+    hide: Vec<Matcher>,
+    private: Vec<Matcher>,
+}
+
+impl Filter {
+    pub fn new(rules: FilterRules) -> Result<Self, String> {
+        let compile = |rules: &[Rule]| rules.iter().map(Matcher::new).collect::<Result<_, _>>();
+        Ok(Self {
+            include: compile(&rules.include)?,
+            exclude: compile(&rules.exclude)?,
+            hide: compile(&rules.hide)?,
+            private: compile(&rules.private)?,
+            rules,
+        })
+    }
+
+    pub fn rules(&self) -> &FilterRules {
+        &self.rules
+    }
+
+    /// Whether this filter keeps every tree unchanged.
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty()
+            && self.exclude.is_empty()
+            && self.hide.is_empty()
+            && self.private.is_empty()
+    }
+
+    /// Remove the filtered entries from `root`, the root itself is always kept.
+    pub fn apply(&self, root: Tree) -> Tree {
+        if self.is_empty() {
+            return root;
+        }
+        self.filter(&root)
+    }
+
+    /// Like [apply](Filter::apply), but only the kept entries are copied out of `root`, and
+    /// nothing is when this filter is empty.
+    pub fn apply_ref<'a>(&self, root: &'a Tree) -> Cow<'a, Tree> {
+        if self.is_empty() {
+            return Cow::Borrowed(root);
+        }
+        Cow::Owned(self.filter(root))
+    }
+
+    fn filter(&self, root: &Tree) -> Tree {
+        let (files, subdirectories) = self.filter_content("", root.files(), root.subdirectories());
+        CombinableDir::new(root.name().to_string(), files, subdirectories)
+            .with_visibility(root.visibility())
+    }
+
+    fn keep_file(&self, path: &str, file: &StaticCombinableFile) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|x| x.matches_file(path, file)))
+            && !self.exclude.iter().any(|x| x.matches_file(path, file))
+    }
+
+    fn file_visibility(&self, path: &str, file: &StaticCombinableFile) -> Visibility {
+        let restricted = if self.private.iter().any(|x| x.matches_file(path, file)) {
+            Visibility::Private
+        } else if self.hide.iter().any(|x| x.matches_file(path, file)) {
+            Visibility::Hidden
+        } else {
+            Visibility::Public
+        };
+        restricted.max(file.visibility())
+    }
+
+    fn dir_visibility(&self, path: &str, dir: &Tree) -> Visibility {
+        let restricted = if self.private.iter().any(|x| x.matches_path(path)) {
+            Visibility::Private
+        } else if self.hide.iter().any(|x| x.matches_path(path)) {
+            Visibility::Hidden
+        } else {
+            Visibility::Public
+        };
+        restricted.max(dir.visibility())
+    }
+
+    fn filter_content(
+        &self,
+        prefix: &str,
+        files: &[StaticCombinableFile],
+        subdirectories: &[Tree],
+    ) -> (Vec<StaticCombinableFile>, Vec<Tree>) {
+        let files = files
+            .iter()
+            .filter_map(|file| {
+                let path = format!("{}{}", prefix, file.name());
+                if !self.keep_file(&path, file) {
+                    return None;
+                }
+                let visibility = self.file_visibility(&path, file);
+                Some(file.clone().with_visibility(visibility))
+            })
+            .collect();
+        let subdirectories = subdirectories
+            .iter()
+            .filter_map(|dir| {
+                let path = format!("{}{}", prefix, dir.name());
+                if self.exclude.iter().any(|x| x.matches_path(&path)) {
+                    return None;
+                }
+                let was_empty = dir.files().is_empty() && dir.subdirectories().is_empty();
+                let visibility = self.dir_visibility(&path, dir);
+                let (files, subdirectories) =
+                    self.filter_content(&format!("{}/", path), dir.files(), dir.subdirectories());
+                if !was_empty && files.is_empty() && subdirectories.is_empty() {
+                    return None;
+                }
+                Some(
+                    CombinableDir::new(dir.name().to_string(), files, subdirectories)
+                        .with_visibility(visibility),
+                )
+            })
+            .collect();
+        (files, subdirectories)
+    }
+}
+
+impl TryFrom<FilterRules> for Filter {
+    type Error = String;
+
+    fn try_from(rules: FilterRules) -> Result<Self, Self::Error> {
+        Self::new(rules)
+    }
+}
+
+impl PartialEq for Filter {
+    fn eq(&self, other: &Self) -> bool {
+        self.rules == other.rules
+    }
+}
+
+impl Eq for Filter {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::static_combinable::test_file;
+    use std::time::Duration;
+
+    /// Like [test_file], modified `days` days after the Unix epoch.
+    fn dated_file(name: &str, size: u64, days: u64) -> StaticCombinableFile {
+        let mut file = test_file(name, size);
+        file.last_modified += Duration::from_secs(days * 86400);
+        file
+    }
+
+    // root
+    // ├── .DS_Store
+    // ├── readme.md
+    // ├── iso
+    // │   ├── old.iso
+    // │   ├── new.iso
+    // │   └── new.iso.part
+    // ├── .git
+    // │   └── HEAD
+    // └── empty
+    fn generate_tree() -> Tree {
+        let iso = CombinableDir::new(
+            "iso".to_string(),
+            vec![
+                dated_file("old.iso", 4096, 1),
+                dated_file("new.iso", 8192, 20000),
+                dated_file("new.iso.part", 1024, 20000),
+            ],
+            vec![],
+        );
+        let git = CombinableDir::new(".git".to_string(), vec![dated_file("HEAD", 0, 1)], vec![]);
+        let empty = CombinableDir::new("empty".to_string(), vec![], vec![]);
+        CombinableDir::new(
+            "root".to_string(),
+            vec![
+                dated_file(".DS_Store", 16, 1),
+                dated_file("readme.md", 128, 1),
+            ],
+            vec![iso, git, empty],
+        )
+    }
+
+    fn filter(json: &str) -> Filter {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn paths(tree: Tree) -> Vec<String> {
+        let mut paths = tree.compress_path().into_keys().collect::<Vec<_>>();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn test_exclude() {
+        let filter = filter(
+            r#"{"exclude": [{"glob": "**/.DS_Store"}, {"glob": "**/.git"}, {"regex": "\\.part$"}]}"#,
+        );
+        let filtered = filter.apply(generate_tree());
+        assert_eq!(filtered.size(), 128 + 4096 + 8192);
+        assert_eq!(
+            paths(filtered),
+            vec!["root/iso/new.iso", "root/iso/old.iso", "root/readme.md"]
+        );
+    }
+
+    #[test]
+    fn test_include() {
+        let filter =
+            filter(r#"{"include": [{"glob": "*.iso"}], "exclude": [{"larger_than": 4096}]}"#);
+        let filtered = filter.apply(generate_tree());
+        assert_eq!(paths(filtered.clone()), vec!["root/iso/old.iso"]);
+        // emptied directories are dropped, empty ones are kept
+        let names = filtered
+            .subdirectories()
+            .iter()
+            .map(|x| x.name().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["iso", "empty"]);
+    }
+
+    #[test]
+    fn test_size_and_time() {
+        let filter =
+            filter(r#"{"exclude": [{"smaller_than": 1}, {"older_than": "2000-01-01T00:00:00Z"}]}"#);
+        assert_eq!(
+            paths(filter.apply(generate_tree())),
+            vec!["root/iso/new.iso", "root/iso/new.iso.part"]
+        );
+    }
+
+    #[test]
+    fn test_invalid_rule() {
+        let result = serde_json::from_str::<Filter>(r#"{"exclude": [{"regex": "("}]}"#);
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .starts_with("invalid regex ("));
+    }
+
+    #[test]
+    fn test_visibility() {
+        let filter = filter(
+            r#"{"hide": [{"glob": "**/.git"}, {"glob": "*.part"}], "private": [{"glob": "**/.DS_Store"}]}"#,
+        );
+        let filtered = filter.apply(generate_tree());
+        assert_eq!(filtered.files()[0].visibility(), Visibility::Private);
+        assert_eq!(filtered.files()[1].visibility(), Visibility::Public);
+        let iso = &filtered.subdirectories()[0];
+        assert_eq!(iso.files()[2].visibility(), Visibility::Hidden);
+        assert_eq!(
+            filtered.subdirectories()[1].visibility(),
+            Visibility::Hidden
+        );
+    }
+}
@@ -0,0 +1,416 @@
+use crate::proxy::{LinkHeaders, LinkSelector};
+use crate::rcu::ReadCopyUpdate;
+use crate::static_combinable::{StaticCombinableFile, StaticDownloadLinkFile};
+use crate::{DriverId, Wheel};
+use axum::http::{header, HeaderMap};
+use chrono::{DateTime, Utc};
+use futures::stream::{self, StreamExt};
+use serde::{Serialize, Serializer};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tokio::time::{self, MissedTickBehavior};
+
+/// What the last probe of a link found.
+#[derive(Debug, Clone, Serialize)]
+pub struct LinkHealth {
+    /// The HTTP status, `None` if the link could not be reached in time.
+    pub status: Option<u16>,
+    /// Until the head of the response arrived, or the request failed.
+    #[serde(rename = "latency_ms", serialize_with = "as_millis")]
+    pub latency: Duration,
+    pub checked: DateTime<Utc>,
+}
+
+impl LinkHealth {
+    /// Whether the link answered with a success.
+    pub fn is_healthy(&self) -> bool {
+        self.status.is_some_and(|x| (200..300).contains(&x))
+    }
+}
+
+fn as_millis<S: Serializer>(latency: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(latency.as_millis() as u64)
+}
+
+/// How a link is probed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeMethod {
+    Head,
+    /// `GET` of the first byte, for links that do not answer `HEAD`.
+    RangedGet,
+}
+
+/// Probes every link of the files of a [Wheel] and remembers how each one answered.
+///
+/// As a [LinkSelector], it puts the healthy links first, the fastest first, then the links not
+/// probed yet, then the broken ones, so [Proxy](crate::proxy::Proxy) and the redirects of the
+/// [server](crate::server::router_with_health) stop handing out dead mirrors.
+pub struct HealthChecker {
+    wheel: Arc<Wheel>,
+    client: reqwest::Client,
+    method: ProbeMethod,
+    /// How many probes may be in flight at once.
+    concurrency: usize,
+    /// The least time between the start of two probes.
+    gap: Duration,
+    timeout: Duration,
+    health: ReadCopyUpdate<HashMap<String, LinkHealth>>,
+}
+
+impl HealthChecker {
+    /// Probe with `HEAD`, 4 at a time, at most 10 per second, with a timeout of 10 seconds.
+    pub fn new(wheel: Arc<Wheel>) -> Self {
+        Self {
+            wheel,
+            client: reqwest::Client::default(),
+            method: ProbeMethod::Head,
+            concurrency: 4,
+            gap: Duration::from_millis(100),
+            timeout: Duration::from_secs(10),
+            health: ReadCopyUpdate::default(),
+        }
+    }
+
+    pub fn with_client(self, client: reqwest::Client) -> Self {
+        Self { client, ..self }
+    }
+
+    pub fn with_method(self, method: ProbeMethod) -> Self {
+        Self { method, ..self }
+    }
+
+    pub fn with_concurrency(self, concurrency: usize) -> Self {
+        Self {
+            concurrency: concurrency.max(1),
+            ..self
+        }
+    }
+
+    /// Start at most `per_second` probes per second.
+    pub fn with_rate_limit(self, per_second: u32) -> Self {
+        Self {
+            gap: Duration::from_secs(1) / per_second.max(1),
+            ..self
+        }
+    }
+
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        Self { timeout, ..self }
+    }
+
+    /// Probe every link now, then again `every` after each round, until the task is aborted.
+    pub fn spawn(self: &Arc<Self>, every: Duration) -> JoinHandle<()> {
+        let checker = self.clone();
+        tokio::spawn(async move {
+            loop {
+                checker.check_all().await;
+                time::sleep(every).await;
+            }
+        })
+    }
+
+    /// Probe every link of the files of the [Wheel] once, with the
+    /// [proxy_headers](crate::driver::GetVfs::proxy_headers) of its driver.
+    ///
+    /// A link listed by several drivers is probed once, with the headers of the one registered
+    /// first.
+    /// Links that left the tree are forgotten.
+    pub async fn check_all(&self) {
+        let mut links = HashMap::<String, Option<DriverId>>::new();
+        for file in self.wheel.path_map.read().values() {
+            for (i, link) in file.links.iter().enumerate() {
+                let driver = file.sources().get(i).copied();
+                let first = links.entry(link.clone()).or_insert(driver);
+                *first = first.iter().copied().chain(driver).min();
+            }
+        }
+        let ticks = Mutex::new(self.ticks());
+        let ticks = &ticks;
+        let results = stream::iter(links.clone())
+            .map(|(link, driver)| async move {
+                ticks.lock().await.tick().await;
+                let headers = driver.map(|x| self.wheel.headers(x)).unwrap_or_default();
+                let health = self.probe(&link, headers).await;
+                (link, health)
+            })
+            .buffer_unordered(self.concurrency)
+            .collect::<Vec<_>>()
+            .await;
+        self.health.rcu(|health| {
+            let mut health = health
+                .iter()
+                .filter(|(link, _)| links.contains_key(*link))
+                .map(|(link, x)| (link.clone(), x.clone()))
+                .collect::<HashMap<_, _>>();
+            health.extend(results.iter().cloned());
+            health
+        });
+    }
+
+    /// When probes may start, see [HealthChecker::with_rate_limit].
+    fn ticks(&self) -> time::Interval {
+        let mut ticks = time::interval(self.gap);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        ticks
+    }
+
+    /// Probe `link`, sending `headers`, without recording the result.
+    pub async fn probe(&self, link: &str, headers: HeaderMap) -> LinkHealth {
+        let request = match self.method {
+            ProbeMethod::Head => self.client.head(link),
+            ProbeMethod::RangedGet => self.client.get(link).header(header::RANGE, "bytes=0-0"),
+        };
+        let request = request.headers(headers);
+        let checked = Utc::now();
+        let start = Instant::now();
+        // the body, if any, is dropped unread
+        let status = request.timeout(self.timeout).send().await.ok();
+        LinkHealth {
+            status: status.map(|x| x.status().as_u16()),
+            latency: start.elapsed(),
+            checked,
+        }
+    }
+
+    /// The last probe of `link`, if it was probed.
+    pub fn health(&self, link: &str) -> Option<LinkHealth> {
+        self.health.read().get(link).cloned()
+    }
+
+    /// The last probe of every link, hidden and proxied ones included, so only show it to
+    /// administrators.
+    pub fn report(&self) -> Arc<HashMap<String, LinkHealth>> {
+        self.health.read()
+    }
+}
+
+impl LinkSelector for HealthChecker {
+    fn select(&self, file: &StaticCombinableFile) -> Vec<usize> {
+        let health = self.health.read();
+        let mut links = (0..file.links.len()).collect::<Vec<_>>();
+        // stable, so links that rank the same keep their order
+        links.sort_by_key(|i| match health.get(&file.links[*i]) {
+            Some(x) if x.is_healthy() => (0, x.latency),
+            None => (1, Duration::ZERO),
+            Some(_) => (2, Duration::ZERO),
+        });
+        links
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::static_combinable::{test_tree, StaticDownloadLinkFile, TestDriver};
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::get;
+    use axum::Router;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::SystemTime;
+    use tokio::net::TcpListener;
+    use tokio::sync::Barrier;
+
+    fn file(links: Vec<String>) -> StaticCombinableFile {
+        StaticCombinableFile::new("file".to_string(), 1024, SystemTime::UNIX_EPOCH, links)
+    }
+
+    /// Requests in flight at the stub, and the most seen at once.
+    #[derive(Default)]
+    struct InFlight {
+        now: AtomicUsize,
+        max: AtomicUsize,
+    }
+
+    impl InFlight {
+        async fn track<T>(&self, request: impl std::future::Future<Output = T>) -> T {
+            let now = self.now.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max.fetch_max(now, Ordering::SeqCst);
+            let result = request.await;
+            self.now.fetch_sub(1, Ordering::SeqCst);
+            result
+        }
+    }
+
+    /// Start a server with a fast, a slow, a missing and a `GET`-only link, and return its
+    /// address.
+    async fn start_stub() -> String {
+        start_stub_with(Arc::default()).await
+    }
+
+    /// Like [start_stub], with `/counted` tracked by `in_flight`, and `/together` answering
+    /// once 3 requests are in flight.
+    async fn start_stub_with(in_flight: Arc<InFlight>) -> String {
+        let barrier = Arc::new(Barrier::new(3));
+        let counted = in_flight.clone();
+        let router = Router::new()
+            .route(
+                "/counted",
+                get(move || async move {
+                    counted
+                        .track(async {
+                            tokio::task::yield_now().await;
+                            StatusCode::OK
+                        })
+                        .await
+                }),
+            )
+            .route(
+                "/together",
+                get(move || async move {
+                    in_flight
+                        .track(async {
+                            barrier.wait().await;
+                            StatusCode::OK
+                        })
+                        .await
+                }),
+            )
+            .route("/fast", get(|| async { StatusCode::OK }))
+            .route(
+                "/slow",
+                get(|| async {
+                    time::sleep(Duration::from_millis(200)).await;
+                    StatusCode::OK
+                }),
+            )
+            .route("/missing", get(|| async { StatusCode::NOT_FOUND }))
+            .route(
+                "/private",
+                get(|headers: HeaderMap| async move {
+                    match headers.get(header::AUTHORIZATION).map(|x| x.as_bytes()) {
+                        Some(b"Bearer secret") => StatusCode::OK,
+                        _ => StatusCode::UNAUTHORIZED,
+                    }
+                }),
+            )
+            .route(
+                "/ranged",
+                get(|headers: HeaderMap| async move {
+                    match headers.get(header::RANGE) {
+                        Some(_) => StatusCode::PARTIAL_CONTENT,
+                        None => StatusCode::FORBIDDEN,
+                    }
+                }),
+            );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        format!("http://{}", address)
+    }
+
+    async fn checker(links: Vec<String>) -> HealthChecker {
+        let wheel = Wheel::new(vec![Box::new(TestDriver::new(test_tree(vec![file(
+            links,
+        )])))])
+        .await;
+        HealthChecker::new(wheel).with_rate_limit(1000)
+    }
+
+    #[tokio::test]
+    async fn test_probe() {
+        let stub = start_stub().await;
+        let checker = checker(vec![]).await;
+        let fast = checker
+            .probe(&format!("{}/fast", stub), HeaderMap::new())
+            .await;
+        assert_eq!(fast.status, Some(200));
+        assert!(fast.is_healthy());
+        let slow = checker
+            .probe(&format!("{}/slow", stub), HeaderMap::new())
+            .await;
+        assert!(slow.latency >= Duration::from_millis(200));
+        let missing = checker
+            .probe(&format!("{}/missing", stub), HeaderMap::new())
+            .await;
+        assert_eq!(missing.status, Some(404));
+        assert!(!missing.is_healthy());
+        let unreachable = checker
+            .probe("http://127.0.0.1:1/file", HeaderMap::new())
+            .await;
+        assert_eq!(unreachable.status, None);
+        assert!(!unreachable.is_healthy());
+
+        let ranged = format!("{}/ranged", stub);
+        assert_eq!(
+            checker.probe(&ranged, HeaderMap::new()).await.status,
+            Some(403)
+        );
+        let checker = checker.with_method(ProbeMethod::RangedGet);
+        assert_eq!(
+            checker.probe(&ranged, HeaderMap::new()).await.status,
+            Some(206)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_select() {
+        let stub = start_stub().await;
+        let links = ["missing", "slow", "fast"]
+            .iter()
+            .map(|x| format!("{}/{}", stub, x))
+            .collect::<Vec<_>>();
+        let checker = checker(links.clone()).await;
+        // nothing probed yet
+        assert_eq!(checker.select(&file(links.clone())), vec![0, 1, 2]);
+
+        checker.check_all().await;
+        assert_eq!(checker.report().len(), 3);
+        assert_eq!(checker.health(&links[0]).unwrap().status, Some(404));
+        let unknown = format!("{}/unknown", stub);
+        let mut with_unknown = links.clone();
+        with_unknown.push(unknown.clone());
+        assert_eq!(checker.select(&file(with_unknown)), vec![2, 1, 3, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_limits() {
+        let in_flight = Arc::new(InFlight::default());
+        let stub = start_stub_with(in_flight.clone()).await;
+        let links = (0..3).map(|i| format!("{}/together?{}", stub, i)).collect();
+        let together = checker(links).await.with_concurrency(3);
+        together.check_all().await;
+        // the stub only answers once all 3 are in flight
+        assert!(together.report().values().all(|x| x.is_healthy()));
+        assert_eq!(in_flight.max.load(Ordering::SeqCst), 3);
+
+        let in_flight = Arc::new(InFlight::default());
+        let stub = start_stub_with(in_flight.clone()).await;
+        let links = (0..6).map(|i| format!("{}/counted?{}", stub, i)).collect();
+        let one_by_one = checker(links).await.with_concurrency(1);
+        one_by_one.check_all().await;
+        assert_eq!(one_by_one.report().len(), 6);
+        assert_eq!(in_flight.max.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let stub = start_stub().await;
+        let links = (0..5).map(|i| format!("{}/fast?{}", stub, i)).collect();
+        let checker = checker(links).await.with_concurrency(5).with_rate_limit(20);
+        let start = Instant::now();
+        checker.check_all().await;
+        assert!(checker.report().values().all(|x| x.is_healthy()));
+        // the first probe starts at once, then one every 50 ms
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn test_driver_headers() {
+        let stub = start_stub().await;
+        let link = format!("{}/private", stub);
+        let driver = TestDriver::new(test_tree(vec![file(vec![link.clone()])])).with_proxy_headers(
+            vec![("authorization".to_string(), "Bearer secret".to_string())],
+        );
+        let wheel = Wheel::new(vec![Box::new(driver)]).await;
+        let checker = HealthChecker::new(wheel).with_rate_limit(1000);
+        assert_eq!(
+            checker.probe(&link, HeaderMap::new()).await.status,
+            Some(401)
+        );
+        checker.check_all().await;
+        assert!(checker.health(&link).unwrap().is_healthy());
+    }
+}
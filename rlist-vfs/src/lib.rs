@@ -9,7 +9,10 @@ pub mod combinable;
 
 /// # `CombinableDir` is a directory that can be combined
 pub mod combinable_dir;
-mod rcu;
+
+/// # Read-copy-update cell
+/// Lock-free reads of a value that is replaced as a whole, like the combined tree in [Wheel].
+pub mod rcu;
 
 /// # traits that driver must implement
 ///
@@ -18,6 +21,81 @@ mod rcu;
 /// - [GetVfs](driver::GetVfs)
 pub mod driver;
 
+/// # Driver set loaded from a config file
+/// Builds the drivers listed in a config file and keeps [Wheel] in sync when it changes.
+pub mod config;
+
+/// # Transforms applied to a driver's tree before it is combined
+pub mod transform;
+
+/// # Who can list and download an entry
+/// Hidden entries are left out of the listed tree but stay downloadable, private ones are neither.
+pub mod visibility;
+
+/// # Who may list and download which paths
+/// Filters the trees of [Wheel] for each caller, with pluggable identities and rules, see
+/// [acl::AccessControl].
+pub mod acl;
+
+/// # Password protected directories
+/// Directories whose listing and downloads need a password, stored as argon2 or bcrypt hashes.
+pub mod password;
+
+/// # Signed, expiring download URLs
+/// Share files without handing out the links of the drivers behind them.
+pub mod signed_url;
+
+/// # Statistics of the combined tree
+/// File counts, storage by driver and extension, and mirrored content, see [Wheel::stats].
+pub mod stats;
+
+/// # Differences between two trees
+/// Which files were added, modified or removed, so indexes only update what changed. Finding
+/// the changes still walks both trees.
+pub mod diff;
+
+/// # JSON of the listed tree
+/// Written straight from the combined tree, in chunks or from precomputed fragments, see
+/// [Wheel::tree_chunks].
+pub mod tree_json;
+
+/// # Search of the listed files
+/// Substring, prefix, glob and fuzzy matching on names and paths, see [Wheel::search].
+pub mod search;
+
+/// # Include and exclude rules for the files of a tree
+/// Keeps junk like `.DS_Store` or partial uploads out of the combined tree.
+pub mod filter;
+
+/// # Snapshot of the combined tree
+/// Lets [Wheel] serve the last known tree while the drivers are still loading.
+pub mod snapshot;
+
+/// # HTTP service exposing the [Wheel]
+/// Enabled by the `server` feature.
+#[cfg(feature = "server")]
+pub mod server;
+
+/// # Downloads streamed through rlist
+/// For links that need credentials or that clients cannot reach. Enabled by the `server` feature.
+#[cfg(feature = "server")]
+pub mod proxy;
+
+/// # Background health checks of the links
+/// Finds dead mirrors so the working ones are handed out first. Enabled by the `server` feature.
+#[cfg(feature = "server")]
+pub mod health;
+
+/// # Prometheus metrics of refreshes, drivers and downloads
+/// Enabled by the `metrics` feature, served at `/metrics` with the `server` feature.
+#[cfg(feature = "metrics")]
+pub mod metrics;
+
+/// # Read-only WebDAV service of the [Wheel]
+/// Lets file managers and media players mount the tree. Enabled by the `server` feature.
+#[cfg(feature = "server")]
+pub mod webdav;
+
 mod wheel;
 mod without_link;
 
@@ -26,7 +104,11 @@ mod without_link;
 pub mod static_driver;
 
 /// The state of rList server
-pub use wheel::Wheel;
+pub use wheel::{DriverId, Provenance, Wheel};
+
+/// For the spans of `#[derive(GetVfs)]`.
+#[doc(hidden)]
+pub use tracing;
 
 /// Basic VFS (Virtual File System) traits
 pub trait VfsBasicMeta
@@ -40,7 +122,8 @@ where
 
 /// File in VFS
 pub trait VfsFileMeta: VfsBasicMeta {
-    fn on_download(&self) -> String;
+    /// A link to download the file from, `None` if it has none.
+    fn on_download(&self) -> Option<String>;
 }
 
 /// Directory in VFS
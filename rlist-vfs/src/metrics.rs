@@ -0,0 +1,273 @@
+use crate::DriverId;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Upper bounds of the buckets of driver refreshes, in seconds.
+const REFRESH_BUCKETS: &[f64] = &[0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0];
+/// Upper bounds of the buckets of RCU swaps, in seconds.
+const SWAP_BUCKETS: &[f64] = &[0.000_001, 0.000_01, 0.000_1, 0.001, 0.01, 0.1];
+
+/// Swaps of every [ReadCopyUpdate](crate::rcu::ReadCopyUpdate) of the process, which do not
+/// belong to any [Wheel](crate::Wheel).
+static SWAP_SECONDS: Mutex<Option<Histogram>> = Mutex::new(None);
+
+/// Record that replacing the value of a [ReadCopyUpdate](crate::rcu::ReadCopyUpdate) took
+/// `elapsed`.
+pub(crate) fn rcu_swapped(elapsed: Duration) {
+    lock(&SWAP_SECONDS)
+        .get_or_insert_with(|| Histogram::new(SWAP_BUCKETS))
+        .observe(elapsed);
+}
+
+/// Counters of a [Wheel](crate::Wheel), see [Wheel::metrics](crate::Wheel::metrics), rendered
+/// in the Prometheus text format by [Metrics::render]:
+/// - `rlist_driver_refresh_seconds{driver}`: how long `get_vfs` took, see
+///   [GetVfs](crate::driver::GetVfs)
+/// - `rlist_driver_get_vfs_total{driver, result}`: `get_vfs` calls by `ok` or `error`
+/// - `rlist_tree_files` and `rlist_tree_bytes`: the size of the combined tree
+/// - `rlist_tree_generation_total`: how many trees were published
+/// - `rlist_link_selected_total{origin}`: links clients were sent to by
+///   [Proxy::pick](crate::proxy::Proxy::pick) or
+///   [SignedUrls::resolve](crate::signed_url::SignedUrls::resolve), by origin like
+///   `https://example.com`, so there is a series per mirror rather than per file
+/// - `rlist_rcu_swap_seconds`: how long replacing the value of a
+///   [ReadCopyUpdate](crate::rcu::ReadCopyUpdate) took, grace period included, shared by the
+///   whole process
+///
+/// Enabled by the `metrics` feature.
+#[derive(Default)]
+pub struct Metrics {
+    refresh_seconds: Mutex<BTreeMap<String, Histogram>>,
+    get_vfs: Mutex<BTreeMap<(String, &'static str), u64>>,
+    tree_files: AtomicU64,
+    tree_bytes: AtomicU64,
+    generation: AtomicU64,
+    link_selected: Mutex<BTreeMap<String, u64>>,
+}
+
+impl Metrics {
+    /// Record a `get_vfs` of `driver` that took `elapsed`.
+    pub fn driver_refreshed(&self, driver: DriverId, elapsed: Duration, ok: bool) {
+        let driver = driver.to_string();
+        let result = if ok { "ok" } else { "error" };
+        lock(&self.refresh_seconds)
+            .entry(driver.clone())
+            .or_insert_with(|| Histogram::new(REFRESH_BUCKETS))
+            .observe(elapsed);
+        *lock(&self.get_vfs).entry((driver, result)).or_default() += 1;
+    }
+
+    /// Record that a tree of `files` files and `bytes` bytes was published.
+    pub fn tree_published(&self, files: u64, bytes: u64) {
+        self.tree_files.store(files, Ordering::Relaxed);
+        self.tree_bytes.store(bytes, Ordering::Relaxed);
+        self.generation.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record that a client was sent to `link`.
+    pub fn link_selected(&self, link: &str) {
+        *lock(&self.link_selected).entry(origin(link)).or_default() += 1;
+    }
+
+    /// Every metric in the Prometheus text format, version 0.0.4.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        header(
+            &mut out,
+            "rlist_driver_refresh_seconds",
+            "histogram",
+            "Time taken by a driver to provide its tree.",
+        );
+        for (driver, histogram) in lock(&self.refresh_seconds).iter() {
+            let labels = format!("driver=\"{}\"", escape(driver));
+            histogram.render(&mut out, "rlist_driver_refresh_seconds", &labels);
+        }
+        header(
+            &mut out,
+            "rlist_driver_get_vfs_total",
+            "counter",
+            "Trees requested from drivers, by result.",
+        );
+        for ((driver, result), count) in lock(&self.get_vfs).iter() {
+            let _ = writeln!(
+                out,
+                "rlist_driver_get_vfs_total{{driver=\"{}\",result=\"{}\"}} {}",
+                escape(driver),
+                result,
+                count
+            );
+        }
+        let values = [
+            (
+                "rlist_tree_files",
+                "gauge",
+                "Files in the combined tree.",
+                &self.tree_files,
+            ),
+            (
+                "rlist_tree_bytes",
+                "gauge",
+                "Bytes in the combined tree.",
+                &self.tree_bytes,
+            ),
+            (
+                "rlist_tree_generation_total",
+                "counter",
+                "Trees published since the start.",
+                &self.generation,
+            ),
+        ];
+        for (name, kind, help, value) in values {
+            header(&mut out, name, kind, help);
+            let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+        }
+        header(
+            &mut out,
+            "rlist_link_selected_total",
+            "counter",
+            "Download links picked, by origin.",
+        );
+        for (origin, count) in lock(&self.link_selected).iter() {
+            let _ = writeln!(
+                out,
+                "rlist_link_selected_total{{origin=\"{}\"}} {}",
+                escape(origin),
+                count
+            );
+        }
+        header(
+            &mut out,
+            "rlist_rcu_swap_seconds",
+            "histogram",
+            "Time taken to replace a value shared with readers.",
+        );
+        if let Some(histogram) = lock(&SWAP_SECONDS).as_ref() {
+            histogram.render(&mut out, "rlist_rcu_swap_seconds", "");
+        }
+        out
+    }
+}
+
+struct Histogram {
+    bounds: &'static [f64],
+    /// Observations per bucket, not cumulative, the last one is `+Inf`.
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len() + 1],
+            sum: 0.0,
+        }
+    }
+
+    fn observe(&mut self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|x| seconds <= *x)
+            .unwrap_or(self.bounds.len());
+        self.counts[bucket] += 1;
+        self.sum += seconds;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        let bounds = self.bounds.iter().map(|x| x.to_string());
+        for (bound, count) in bounds.chain(["+Inf".to_string()]).zip(&self.counts) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}{}le=\"{}\"}} {}",
+                name, labels, separator, bound, cumulative
+            );
+        }
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels)
+        };
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, labels, cumulative);
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Metrics are best effort, a panic while recording one must not stop the others.
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// `https://example.com/a/b` becomes `https://example.com`.
+fn origin(link: &str) -> String {
+    let start = link.find("://").map_or(0, |x| x + 3);
+    match link[start..].find('/') {
+        Some(end) => link[..start + end].to_string(),
+        None => link.to_string(),
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_origin() {
+        assert_eq!(origin("https://example.com/a/b"), "https://example.com");
+        assert_eq!(origin("https://example.com"), "https://example.com");
+        assert_eq!(origin("relative/path"), "relative");
+    }
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.driver_refreshed(DriverId(0), Duration::from_millis(30), true);
+        metrics.driver_refreshed(DriverId(0), Duration::from_secs(2), false);
+        metrics.tree_published(3, 1024);
+        metrics.tree_published(4, 2048);
+        metrics.link_selected("https://example.com/a");
+        metrics.link_selected("https://example.com/b");
+        rcu_swapped(Duration::from_micros(5));
+
+        let text = metrics.render();
+        let lines = text.lines().collect::<Vec<_>>();
+        for line in [
+            "# TYPE rlist_driver_refresh_seconds histogram",
+            "rlist_driver_refresh_seconds_bucket{driver=\"driver-0\",le=\"0.01\"} 0",
+            "rlist_driver_refresh_seconds_bucket{driver=\"driver-0\",le=\"0.05\"} 1",
+            "rlist_driver_refresh_seconds_bucket{driver=\"driver-0\",le=\"+Inf\"} 2",
+            "rlist_driver_refresh_seconds_count{driver=\"driver-0\"} 2",
+            "rlist_driver_get_vfs_total{driver=\"driver-0\",result=\"error\"} 1",
+            "rlist_driver_get_vfs_total{driver=\"driver-0\",result=\"ok\"} 1",
+            "rlist_tree_files 4",
+            "rlist_tree_bytes 2048",
+            "# TYPE rlist_tree_generation_total counter",
+            "rlist_tree_generation_total 2",
+            "rlist_link_selected_total{origin=\"https://example.com\"} 2",
+        ] {
+            assert!(lines.contains(&line), "missing {}", line);
+        }
+        // other tests swap values too
+        assert!(text.contains("rlist_rcu_swap_seconds_bucket{le=\"0.00001\"} "));
+        assert!(text.contains("rlist_rcu_swap_seconds_count "));
+    }
+}
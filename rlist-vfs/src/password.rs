@@ -0,0 +1,388 @@
+use crate::combinable_dir::CombinableDir;
+use crate::static_combinable::StaticCombinableFile;
+use crate::{VfsBasicMeta, VfsDirMeta};
+use argon2::password_hash::PasswordVerifier;
+use argon2::Argon2;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::RwLock;
+use std::time::SystemTime;
+
+type Tree = CombinableDir<StaticCombinableFile>;
+
+/// A password hash in the argon2 PHC format, like `$argon2id$v=19$...`, or the bcrypt format,
+/// like `$2b$12$...`. The plain password is never stored.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct PasswordHash(String);
+
+impl PasswordHash {
+    pub fn verify(&self, password: &str) -> bool {
+        if self.0.starts_with("$argon2") {
+            // checked in `try_from`
+            let hash = argon2::PasswordHash::new(&self.0).unwrap();
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        } else {
+            bcrypt::verify(password, &self.0).unwrap_or(false)
+        }
+    }
+
+    /// [verify](PasswordHash::verify) on a blocking thread, so a slow hash does not hold up
+    /// the runtime.
+    pub async fn verify_blocking(&self, password: &str) -> bool {
+        let (hash, password) = (self.clone(), password.to_string());
+        tokio::task::spawn_blocking(move || hash.verify(&password))
+            .await
+            .unwrap_or(false)
+    }
+}
+
+/// The passwords that opened a protected directory, so each is hashed once and not for every
+/// request that carries it.
+///
+/// Only an HMAC of the directory, its hash and the password is kept, under a key drawn when
+/// the cache is created, so the cache gives nothing away about the passwords. A directory
+/// whose hash changes does not match what was verified with the old one.
+pub(crate) struct Unlocked {
+    key: [u8; 32],
+    verified: RwLock<HashSet<[u8; 32]>>,
+}
+
+impl Default for Unlocked {
+    fn default() -> Self {
+        Self {
+            key: rand::random(),
+            verified: RwLock::default(),
+        }
+    }
+}
+
+impl Unlocked {
+    /// Whether `password` opens the directory `protected`, whose password hash is `hash`.
+    pub async fn unlocks(&self, protected: &str, hash: &PasswordHash, password: &str) -> bool {
+        let digest = self.digest(protected, hash, password);
+        if self.verified.read().unwrap().contains(&digest) {
+            return true;
+        }
+        let unlocks = hash.verify_blocking(password).await;
+        if unlocks {
+            self.verified.write().unwrap().insert(digest);
+        }
+        unlocks
+    }
+
+    /// Forget every verified password.
+    pub fn clear(&self) {
+        self.verified.write().unwrap().clear();
+    }
+
+    fn digest(&self, protected: &str, hash: &PasswordHash, password: &str) -> [u8; 32] {
+        // HMAC accepts keys of any length
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).unwrap();
+        // length prefixed, so fields cannot run into each other
+        for field in [protected, &hash.0, password] {
+            mac.update(&(field.len() as u64).to_be_bytes());
+            mac.update(field.as_bytes());
+        }
+        mac.finalize().into_bytes().into()
+    }
+}
+
+impl TryFrom<String> for PasswordHash {
+    type Error = String;
+
+    fn try_from(hash: String) -> Result<Self, Self::Error> {
+        let valid = if hash.starts_with("$argon2") {
+            argon2::PasswordHash::new(&hash).is_ok()
+        } else {
+            hash.parse::<bcrypt::HashParts>().is_ok()
+        };
+        if !valid {
+            return Err(format!("invalid password hash {}", hash));
+        }
+        Ok(Self(hash))
+    }
+}
+
+/// Which directories need a password, loaded from a JSON object like
+/// `{"/internal": "$argon2id$v=19$...", "/internal/hr": "$2b$12$..."}`.
+///
+/// Paths are relative to the root of the combined tree. A password protects the directory and
+/// everything below it, and when protected directories are nested the deepest one decides.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(from = "HashMap<String, PasswordHash>")]
+pub struct Passwords(Vec<(String, PasswordHash)>);
+
+impl Passwords {
+    pub fn new(rules: Vec<(String, PasswordHash)>) -> Self {
+        let mut rules = rules
+            .into_iter()
+            .map(|(path, hash)| (normalize(&path), hash))
+            .collect::<Vec<_>>();
+        // deepest first, so the first match is the one that decides
+        rules.sort_by_key(|(path, _)| std::cmp::Reverse(path.len()));
+        Self(rules)
+    }
+
+    pub async fn load(path: &Path) -> Result<Self, String> {
+        let json = tokio::fs::read(path)
+            .await
+            .map_err(|e| format!("cannot read passwords {}: {}", path.display(), e))?;
+        serde_json::from_slice(&json).map_err(|e| format!("invalid passwords: {}", e))
+    }
+
+    /// The protected directory `path` is in, if any, and its password hash.
+    pub fn protecting(&self, path: &str) -> Option<(&str, &PasswordHash)> {
+        let path = normalize(path);
+        self.0
+            .iter()
+            .find(|(dir, _)| {
+                dir.is_empty() || path == *dir || path.starts_with(&format!("{}/", dir))
+            })
+            .map(|(dir, hash)| (dir.as_str(), hash))
+    }
+
+    /// Whether `password` opens `path`, which it always does if `path` is not protected.
+    pub fn unlocks(&self, path: &str, password: Option<&str>) -> bool {
+        match self.protecting(path) {
+            Some((_, hash)) => password.is_some_and(|x| hash.verify(x)),
+            None => true,
+        }
+    }
+
+    /// Empty every protected directory of the tree `root`, as seen without any password.
+    ///
+    /// The protected directories stay in the tree, so callers know there is something to
+    /// unlock, but their content and size do not.
+    pub fn lock(&self, root: Tree) -> Tree {
+        match self.protecting("") {
+            Some(_) => empty(root),
+            None => self.lock_below(None, "", root),
+        }
+    }
+
+    /// Like [lock](Passwords::lock), for the directory `dir` at `prefix` whose password was
+    /// given, so only the protected directories below it with their own rule are emptied.
+    pub fn lock_opened(&self, prefix: &str, dir: Tree) -> Tree {
+        if self.0.is_empty() {
+            return dir;
+        }
+        let opened = self.protecting(prefix).map(|(x, _)| x.to_string());
+        self.lock_below(opened.as_deref(), prefix, dir)
+    }
+
+    /// The size and modification time of the directory `dir` at `path` once it is locked like
+    /// by [lock_opened](Passwords::lock_opened) with the protected directory `opened` open,
+    /// without copying it.
+    pub fn usage(&self, opened: Option<&str>, path: &str, dir: &Tree) -> (u64, SystemTime) {
+        if self.0.is_empty() {
+            return (dir.size(), dir.last_modified());
+        }
+        if self.locks(opened, path) {
+            // like `empty`
+            return (0, SystemTime::now());
+        }
+        let mut size = 0;
+        let mut latest = None;
+        for file in dir.files() {
+            size += file.size();
+            latest = latest.max(Some(file.last_modified()));
+        }
+        for subdirectory in dir.subdirectories() {
+            let path = format!("{}/{}", path, subdirectory.name());
+            let (x, y) = self.usage(opened, &path, subdirectory);
+            size += x;
+            latest = latest.max(Some(y));
+        }
+        (size, latest.unwrap_or(dir.last_modified()))
+    }
+
+    /// Whether the directory at `path` is emptied when only the protected directory `opened`
+    /// is open.
+    fn locks(&self, opened: Option<&str>, path: &str) -> bool {
+        matches!(self.protecting(path), Some((protected, _)) if Some(protected) != opened)
+    }
+
+    fn lock_below(&self, opened: Option<&str>, prefix: &str, dir: Tree) -> Tree {
+        let visibility = dir.visibility();
+        let (name, files, subdirectories) = dir.destruct();
+        let subdirectories = subdirectories
+            .into_iter()
+            .map(|dir| {
+                let path = format!("{}/{}", prefix, dir.name());
+                if self.locks(opened, &path) {
+                    empty(dir)
+                } else {
+                    self.lock_below(opened, &path, dir)
+                }
+            })
+            .collect();
+        CombinableDir::new(name, files, subdirectories).with_visibility(visibility)
+    }
+}
+
+impl From<HashMap<String, PasswordHash>> for Passwords {
+    fn from(rules: HashMap<String, PasswordHash>) -> Self {
+        Self::new(rules.into_iter().collect())
+    }
+}
+
+fn empty(dir: Tree) -> Tree {
+    let visibility = dir.visibility();
+    CombinableDir::new(dir.name().to_string(), vec![], vec![]).with_visibility(visibility)
+}
+
+/// `/a/b/` and `a/b` both become `/a/b`, and the root becomes an empty string.
+fn normalize(path: &str) -> String {
+    path.split('/')
+        .filter(|x| !x.is_empty())
+        .map(|x| format!("/{}", x))
+        .collect()
+}
+
+/// The answer to a lookup on a tree that may have password protected directories.
+#[derive(Debug, Clone)]
+pub enum Lookup<T> {
+    Found(T),
+    NotFound,
+    /// The path is in the protected directory `protected`, and the password was missing or
+    /// wrong. Protected paths that do not exist give this too, so they cannot be probed.
+    PasswordRequired {
+        protected: String,
+    },
+}
+
+impl<T> Lookup<T> {
+    pub fn found(self) -> Option<T> {
+        match self {
+            Lookup::Found(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn is_password_required(&self) -> bool {
+        matches!(self, Lookup::PasswordRequired { .. })
+    }
+
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> Lookup<U> {
+        match self {
+            Lookup::Found(x) => Lookup::Found(f(x)),
+            Lookup::NotFound => Lookup::NotFound,
+            Lookup::PasswordRequired { protected } => Lookup::PasswordRequired { protected },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::static_combinable::test_file;
+    use argon2::password_hash::{PasswordHasher, SaltString};
+
+    fn argon2_hash(password: &str) -> String {
+        let salt = SaltString::encode_b64(b"rlist-test-salt").unwrap();
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .unwrap()
+            .to_string()
+    }
+
+    fn passwords() -> Passwords {
+        let rules = HashMap::from([
+            ("/internal/".to_string(), argon2_hash("staff")),
+            ("internal/hr".to_string(), bcrypt::hash("hr", 4).unwrap()),
+        ]);
+        serde_json::from_value(serde_json::to_value(rules).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_verify() {
+        let argon2 = PasswordHash::try_from(argon2_hash("secret")).unwrap();
+        assert!(argon2.verify("secret"));
+        assert!(!argon2.verify("wrong"));
+        let bcrypt = PasswordHash::try_from(bcrypt::hash("secret", 4).unwrap()).unwrap();
+        assert!(bcrypt.verify("secret"));
+        assert!(!bcrypt.verify("wrong"));
+        assert!(PasswordHash::try_from("secret".to_string()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unlocked() {
+        let unlocked = Unlocked::default();
+        let verified = || unlocked.verified.read().unwrap().len();
+        let hash = PasswordHash::try_from(bcrypt::hash("secret", 4).unwrap()).unwrap();
+        assert!(!unlocked.unlocks("/internal", &hash, "wrong").await);
+        assert_eq!(verified(), 0);
+        assert!(unlocked.unlocks("/internal", &hash, "secret").await);
+        assert!(unlocked.unlocks("/internal", &hash, "secret").await);
+        assert_eq!(verified(), 1);
+        // not for another directory or another hash
+        assert!(unlocked.unlocks("/public", &hash, "secret").await);
+        assert_eq!(verified(), 2);
+        let changed = PasswordHash::try_from(bcrypt::hash("changed", 4).unwrap()).unwrap();
+        assert!(!unlocked.unlocks("/internal", &changed, "secret").await);
+        unlocked.clear();
+        assert_eq!(verified(), 0);
+    }
+
+    #[test]
+    fn test_protecting() {
+        let passwords = passwords();
+        assert!(passwords.protecting("/readme").is_none());
+        assert!(passwords.protecting("/internals").is_none());
+        assert_eq!(passwords.protecting("/internal").unwrap().0, "/internal");
+        assert_eq!(passwords.protecting("/internal/a").unwrap().0, "/internal");
+        assert_eq!(
+            passwords.protecting("/internal/hr/a").unwrap().0,
+            "/internal/hr"
+        );
+
+        assert!(passwords.unlocks("/readme", None));
+        assert!(!passwords.unlocks("/internal/a", None));
+        assert!(passwords.unlocks("/internal/a", Some("staff")));
+        // the deepest directory decides
+        assert!(!passwords.unlocks("/internal/hr/a", Some("staff")));
+        assert!(passwords.unlocks("/internal/hr/a", Some("hr")));
+    }
+
+    // root
+    // ├── readme
+    // └── internal
+    //     ├── report
+    //     └── hr
+    //         └── salaries
+    #[test]
+    fn test_lock() {
+        let hr = CombinableDir::new("hr".to_string(), vec![test_file("salaries", 1024)], vec![]);
+        let internal = CombinableDir::new(
+            "internal".to_string(),
+            vec![test_file("report", 1024)],
+            vec![hr],
+        );
+        let root = CombinableDir::new(
+            "root".to_string(),
+            vec![test_file("readme", 1024)],
+            vec![internal.clone()],
+        );
+
+        assert_eq!(passwords().usage(None, "", &root).0, 1024);
+        let opened = Some("/internal");
+        assert_eq!(passwords().usage(opened, "/internal", &internal).0, 1024);
+        let locked = passwords().lock(root);
+        assert_eq!(locked.size(), 1024);
+        let internal_locked = &locked.subdirectories()[0];
+        assert_eq!(internal_locked.name(), "internal");
+        assert!(internal_locked.files().is_empty());
+        assert!(internal_locked.subdirectories().is_empty());
+
+        // opening /internal does not open /internal/hr
+        let opened = passwords().lock_opened("/internal", internal);
+        assert_eq!(opened.files().len(), 1);
+        assert!(opened.subdirectories()[0].files().is_empty());
+    }
+}
@@ -0,0 +1,659 @@
+use crate::static_combinable::{StaticCombinableFile, StaticDownloadLinkFile};
+use crate::{DriverId, VfsBasicMeta, Wheel};
+use axum::body::{Body, Bytes};
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode};
+use axum::response::{IntoResponse, Response};
+use futures::channel::mpsc;
+use futures::{SinkExt, StreamExt};
+use rand::{thread_rng, Rng};
+use std::io;
+use std::sync::Arc;
+
+/// Streams files through rlist instead of redirecting clients to their links, for links that
+/// need credentials or that clients cannot reach, see
+/// [StaticDownloadLinkFile::proxy](crate::static_combinable::StaticDownloadLinkFile::proxy).
+///
+/// Links are tried in the order of the [LinkSelector], skipping those that cannot be reached
+/// or answer with an error. If a link breaks during the transfer, the rest is requested with a
+/// `Range` from the next links, so the client gets one uninterrupted body.
+///
+/// Each link is requested with the headers of the driver it comes from, see
+/// [Proxy::with_headers], so the credentials of a driver only go to its own links.
+#[derive(Clone)]
+pub struct Proxy {
+    client: reqwest::Client,
+    selector: Arc<dyn LinkSelector>,
+    headers: Option<Arc<dyn LinkHeaders>>,
+    /// Where [Proxy::pick] records the links it picks.
+    #[cfg(feature = "metrics")]
+    metrics: Option<Arc<crate::metrics::Metrics>>,
+}
+
+impl Default for Proxy {
+    fn default() -> Self {
+        Self::new(reqwest::Client::default())
+    }
+}
+
+/// The order in which [Proxy] tries the links of a file.
+pub trait LinkSelector: Send + Sync {
+    /// The index in `file.links` of every link that may be tried, the preferred first.
+    ///
+    /// Links are picked by index, so the same link from two drivers keeps the headers of each.
+    fn select(&self, file: &StaticCombinableFile) -> Vec<usize>;
+}
+
+impl<T: LinkSelector + ?Sized> LinkSelector for Arc<T> {
+    fn select(&self, file: &StaticCombinableFile) -> Vec<usize> {
+        (**self).select(file)
+    }
+}
+
+/// The headers sent with the requests to the links of a driver, like credentials.
+pub trait LinkHeaders: Send + Sync {
+    fn headers(&self, driver: DriverId) -> HeaderMap;
+}
+
+/// The [GetVfs::proxy_headers](crate::driver::GetVfs::proxy_headers) of the registered
+/// drivers, leaving out those that are not valid headers.
+impl LinkHeaders for Wheel {
+    fn headers(&self, driver: DriverId) -> HeaderMap {
+        let drivers = self.drivers();
+        let Some((_, driver)) = drivers.iter().find(|(id, _)| *id == driver) else {
+            return HeaderMap::new();
+        };
+        driver
+            .proxy_headers()
+            .iter()
+            .filter_map(|(name, value)| {
+                let name = HeaderName::try_from(name).ok()?;
+                let value = HeaderValue::try_from(value).ok()?;
+                Some((name, value))
+            })
+            .collect()
+    }
+}
+
+/// A random link, like [VfsFileMeta::on_download](crate::VfsFileMeta::on_download), then the others in their order in the file.
+pub struct OnDownloadFirst;
+
+impl LinkSelector for OnDownloadFirst {
+    fn select(&self, file: &StaticCombinableFile) -> Vec<usize> {
+        if file.links.is_empty() {
+            return vec![];
+        }
+        let first = thread_rng().gen_range(0..file.links.len());
+        let rest = (0..file.links.len()).filter(|x| *x != first);
+        std::iter::once(first).chain(rest).collect()
+    }
+}
+
+/// An inclusive range of bytes of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ByteRange {
+    start: u64,
+    end: u64,
+}
+
+impl ByteRange {
+    fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+}
+
+impl Proxy {
+    pub fn new(client: reqwest::Client) -> Self {
+        Self {
+            client,
+            selector: Arc::new(OnDownloadFirst),
+            headers: None,
+            #[cfg(feature = "metrics")]
+            metrics: None,
+        }
+    }
+
+    /// Record the links picked by [Proxy::pick] in `metrics`, usually those of the
+    /// [Wheel](crate::Wheel) the files come from.
+    #[cfg(feature = "metrics")]
+    pub fn with_metrics(self, metrics: Arc<crate::metrics::Metrics>) -> Self {
+        Self {
+            metrics: Some(metrics),
+            ..self
+        }
+    }
+
+    /// Send the headers of `headers` for the driver of each link, usually the
+    /// [Wheel](crate::Wheel) the files come from. Links whose driver is not known, like those
+    /// of files combined from trees that were not all tagged, get none.
+    pub fn with_headers(self, headers: Arc<dyn LinkHeaders>) -> Self {
+        Self {
+            headers: Some(headers),
+            ..self
+        }
+    }
+
+    pub fn with_selector(self, selector: impl LinkSelector + 'static) -> Self {
+        Self {
+            selector: Arc::new(selector),
+            ..self
+        }
+    }
+
+    /// The link preferred by the [LinkSelector], for clients sent to the file instead, `None`
+    /// if there is none to try.
+    pub fn pick(&self, file: &StaticCombinableFile) -> Option<String> {
+        let index = *self.selector.select(file).first()?;
+        let link = file.links.get(index)?.clone();
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &self.metrics {
+            metrics.link_selected(&link);
+        }
+        Some(link)
+    }
+
+    /// Redirect to the link of `file` picked by [Proxy::pick], or answer `502 Bad Gateway` if
+    /// it has none.
+    pub fn redirect(&self, file: &StaticCombinableFile) -> Response {
+        match self.pick(file) {
+            Some(link) => (StatusCode::FOUND, [(header::LOCATION, link)]).into_response(),
+            None => StatusCode::BAD_GATEWAY.into_response(),
+        }
+    }
+
+    /// Answer a `GET` or `HEAD` of `file`, honouring a single range in the `Range` header.
+    ///
+    /// The size of `file` is trusted to be the size of the content behind its links. `HEAD`
+    /// is answered without contacting any link.
+    pub async fn serve(
+        &self,
+        file: &StaticCombinableFile,
+        method: &Method,
+        headers: &HeaderMap,
+    ) -> Response {
+        let size = file.size();
+        let range = match headers.get(header::RANGE).and_then(|x| x.to_str().ok()) {
+            Some(range) => match parse_range(range, size) {
+                Ok(range) => range,
+                Err(()) => {
+                    let content_range = format!("bytes */{}", size);
+                    return (
+                        StatusCode::RANGE_NOT_SATISFIABLE,
+                        [(header::CONTENT_RANGE, content_range)],
+                    )
+                        .into_response();
+                }
+            },
+            None => None,
+        };
+        let (status, content) = match range {
+            Some(range) => (StatusCode::PARTIAL_CONTENT, range),
+            None if size == 0 => return empty(StatusCode::OK, None, 0),
+            None => (
+                StatusCode::OK,
+                ByteRange {
+                    start: 0,
+                    end: size - 1,
+                },
+            ),
+        };
+        if method == Method::HEAD {
+            return empty(status, range, size);
+        }
+
+        let links = self
+            .selector
+            .select(file)
+            .into_iter()
+            .filter_map(|i| {
+                let link = file.links.get(i)?.clone();
+                Some((link, file.sources().get(i).copied()))
+            })
+            .collect::<Vec<_>>();
+        let mut next = 0;
+        let Some(first) = self.open(&links, &mut next, content, size).await else {
+            return StatusCode::BAD_GATEWAY.into_response();
+        };
+        let content_type = first.headers().get(header::CONTENT_TYPE).cloned();
+        let (sender, receiver) = mpsc::channel(4);
+        let transfer = self
+            .clone()
+            .transfer(first, links, next, content, size, sender);
+        tokio::spawn(transfer);
+
+        let mut response = (status, Body::from_stream(receiver)).into_response();
+        let headers = response.headers_mut();
+        headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+        headers.insert(header::CONTENT_LENGTH, content.len().into());
+        if let Some(range) = range {
+            headers.insert(header::CONTENT_RANGE, content_range(range, size));
+        }
+        if let Some(content_type) = content_type {
+            headers.insert(header::CONTENT_TYPE, content_type);
+        }
+        response
+    }
+
+    /// Forward `content` to `sender`, resuming from the next links when a link breaks.
+    async fn transfer(
+        self,
+        mut response: reqwest::Response,
+        links: Vec<(String, Option<DriverId>)>,
+        mut next: usize,
+        content: ByteRange,
+        size: u64,
+        mut sender: mpsc::Sender<Result<Bytes, io::Error>>,
+    ) {
+        let mut sent = 0;
+        loop {
+            let mut stream = response.bytes_stream();
+            while let Some(Ok(mut chunk)) = stream.next().await {
+                let remaining = content.len() - sent;
+                if chunk.len() as u64 > remaining {
+                    chunk.truncate(remaining as usize);
+                }
+                sent += chunk.len() as u64;
+                if sender.send(Ok(chunk)).await.is_err() {
+                    // the client went away
+                    return;
+                }
+                if sent == content.len() {
+                    return;
+                }
+            }
+            let rest = ByteRange {
+                start: content.start + sent,
+                end: content.end,
+            };
+            response = match self.open(&links, &mut next, rest, size).await {
+                Some(response) => response,
+                None => {
+                    let error = io::Error::new(io::ErrorKind::UnexpectedEof, "every link failed");
+                    let _ = sender.send(Err(error)).await;
+                    return;
+                }
+            };
+        }
+    }
+
+    /// Request `range` from the first link from `next` on that provides it, and move `next`
+    /// past that link.
+    async fn open(
+        &self,
+        links: &[(String, Option<DriverId>)],
+        next: &mut usize,
+        range: ByteRange,
+        size: u64,
+    ) -> Option<reqwest::Response> {
+        while let Some((link, driver)) = links.get(*next) {
+            *next += 1;
+            if let Some(response) = self.connect(link, *driver, range, size).await {
+                return Some(response);
+            }
+        }
+        None
+    }
+
+    /// Request `range` of the file at `link` of `driver`, `None` if the link cannot be
+    /// reached, answers with an error, or cannot provide the range.
+    async fn connect(
+        &self,
+        link: &str,
+        driver: Option<DriverId>,
+        range: ByteRange,
+        size: u64,
+    ) -> Option<reqwest::Response> {
+        let mut request = self.client.get(link);
+        if let (Some(headers), Some(driver)) = (&self.headers, driver) {
+            request = request.headers(headers.headers(driver));
+        }
+        let ranged = range.start > 0 || range.end + 1 < size;
+        if ranged {
+            let value = format!("bytes={}-{}", range.start, range.end);
+            request = request.header(header::RANGE, value);
+        }
+        let response = request.send().await.ok()?;
+        match response.status() {
+            StatusCode::PARTIAL_CONTENT if ranged => Some(response),
+            // a link that ignores `Range` still works from the start, the end is cut off
+            StatusCode::OK if range.start == 0 => Some(response),
+            _ => None,
+        }
+    }
+}
+
+/// A response with the headers of the content, but without the content.
+fn empty(status: StatusCode, range: Option<ByteRange>, size: u64) -> Response {
+    let length = range.map_or(size, |x| x.len());
+    let mut response = status.into_response();
+    let headers = response.headers_mut();
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    headers.insert(header::CONTENT_LENGTH, length.into());
+    if let Some(range) = range {
+        headers.insert(header::CONTENT_RANGE, content_range(range, size));
+    }
+    response
+}
+
+fn content_range(range: ByteRange, size: u64) -> HeaderValue {
+    let value = format!("bytes {}-{}/{}", range.start, range.end, size);
+    HeaderValue::from_str(&value).unwrap()
+}
+
+/// Parse a `Range` header like `bytes=0-499`, `bytes=500-` or `bytes=-500`.
+///
+/// Headers that are not a single range of bytes are ignored, as HTTP allows, and `Err` means
+/// the range is outside of the file.
+fn parse_range(range: &str, size: u64) -> Result<Option<ByteRange>, ()> {
+    let Some(range) = range.trim().strip_prefix("bytes=") else {
+        return Ok(None);
+    };
+    if range.contains(',') {
+        return Ok(None);
+    }
+    let Some((start, end)) = range.trim().split_once('-') else {
+        return Ok(None);
+    };
+    let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+        (Ok(start), Ok(end)) if start <= end => ByteRange {
+            start,
+            end: end.min(size.saturating_sub(1)),
+        },
+        (Ok(start), Err(_)) if end.is_empty() => ByteRange {
+            start,
+            end: size.saturating_sub(1),
+        },
+        (Err(_), Ok(suffix)) if start.is_empty() => {
+            if suffix == 0 {
+                return Err(());
+            }
+            ByteRange {
+                start: size.saturating_sub(suffix),
+                end: size.saturating_sub(1),
+            }
+        }
+        _ => return Ok(None),
+    };
+    if range.start >= size {
+        return Err(());
+    }
+    Ok(Some(range))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::driver::GetVfs;
+    use crate::static_combinable::{test_tree, TestDriver};
+    use axum::body::to_bytes;
+    use axum::extract::Path;
+    use axum::routing::get;
+    use axum::Router;
+    use std::time::SystemTime;
+    use tokio::net::TcpListener;
+
+    fn content() -> Vec<u8> {
+        (0..600).map(|x| x as u8).collect()
+    }
+
+    /// Serves `content` with `Range` support, but breaks after sending `limit` bytes.
+    async fn stub(Path(limit): Path<usize>, headers: HeaderMap) -> Response {
+        let content = content();
+        let range = headers.get(header::RANGE).and_then(|x| x.to_str().ok());
+        let (status, range) = match range.map(|x| parse_range(x, content.len() as u64)) {
+            Some(Ok(Some(range))) => (StatusCode::PARTIAL_CONTENT, range),
+            _ => (StatusCode::OK, ByteRange { start: 0, end: 599 }),
+        };
+        let body = content[range.start as usize..=range.end as usize].to_vec();
+        if body.len() <= limit {
+            return (status, body).into_response();
+        }
+        let sent = futures::stream::once(async move { Ok(body[..limit].to_vec()) });
+        // break once the head and the first bytes are out, or the client never sees them
+        let broken = futures::stream::once(async {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            Err::<Vec<u8>, _>(io::Error::other("broken"))
+        });
+        (status, Body::from_stream(sent.chain(broken))).into_response()
+    }
+
+    /// Serves `content` to the bearer of the token `secret` only.
+    async fn private(headers: HeaderMap) -> Response {
+        match headers.get(header::AUTHORIZATION).map(|x| x.as_bytes()) {
+            Some(b"Bearer secret") => content().into_response(),
+            _ => StatusCode::UNAUTHORIZED.into_response(),
+        }
+    }
+
+    /// Start the stub server and return its address.
+    async fn start_stub() -> String {
+        let router = Router::new()
+            .route("/file/:limit", get(stub))
+            .route("/missing", get(|| async { StatusCode::NOT_FOUND }))
+            .route("/private", get(private))
+            .route(
+                "/unavailable",
+                get(|| async { StatusCode::SERVICE_UNAVAILABLE }),
+            );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        format!("http://{}", address)
+    }
+
+    fn file(links: Vec<String>) -> StaticCombinableFile {
+        StaticCombinableFile::new("file".to_string(), 600, SystemTime::UNIX_EPOCH, links)
+            .with_proxy(true)
+    }
+
+    /// Serves the file `name` at `link`, to be requested with `proxy_headers`.
+    fn private_driver(
+        name: &str,
+        link: &str,
+        proxy_headers: Vec<(String, String)>,
+    ) -> Box<dyn GetVfs> {
+        let file = StaticCombinableFile::new(
+            name.to_string(),
+            600,
+            SystemTime::UNIX_EPOCH,
+            vec![link.to_string()],
+        );
+        Box::new(TestDriver::new(test_tree(vec![file])).with_proxy_headers(proxy_headers))
+    }
+
+    /// The links in their order in the file.
+    struct InOrder;
+
+    impl LinkSelector for InOrder {
+        fn select(&self, file: &StaticCombinableFile) -> Vec<usize> {
+            (0..file.links.len()).collect()
+        }
+    }
+
+    fn range(range: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::RANGE, range.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_parse_range() {
+        let range = |start, end| Ok(Some(ByteRange { start, end }));
+        assert_eq!(parse_range("bytes=0-99", 600), range(0, 99));
+        assert_eq!(parse_range("bytes=500-", 600), range(500, 599));
+        assert_eq!(parse_range("bytes=500-1000", 600), range(500, 599));
+        assert_eq!(parse_range("bytes=-100", 600), range(500, 599));
+        assert_eq!(parse_range("bytes=-1000", 600), range(0, 599));
+        assert_eq!(parse_range("bytes=0-1,5-6", 600), Ok(None));
+        assert_eq!(parse_range("items=0-1", 600), Ok(None));
+        assert_eq!(parse_range("bytes=9-1", 600), Ok(None));
+        assert_eq!(parse_range("bytes=600-", 600), Err(()));
+        assert_eq!(parse_range("bytes=-0", 600), Err(()));
+    }
+
+    #[tokio::test]
+    async fn test_stream() {
+        let stub = start_stub().await;
+        let file = file(vec![format!("{}/file/1000", stub)]);
+        let response = Proxy::default()
+            .serve(&file, &Method::GET, &HeaderMap::new())
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "600");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body.to_vec(), content());
+    }
+
+    #[tokio::test]
+    async fn test_driver_headers() {
+        let stub = start_stub().await;
+        let link = format!("{}/private", stub);
+        let wheel = Wheel::new(vec![
+            private_driver(
+                "a",
+                &link,
+                vec![("authorization".to_string(), "Bearer secret".to_string())],
+            ),
+            private_driver("b", &link, vec![]),
+        ])
+        .await;
+        let proxy = Proxy::default().with_headers(wheel.clone());
+        let serve = |path: &'static str| {
+            let (wheel, proxy) = (wheel.clone(), proxy.clone());
+            async move {
+                let file = wheel.lookup(path, None).await.found().unwrap();
+                proxy.serve(&file, &Method::GET, &HeaderMap::new()).await
+            }
+        };
+
+        let response = serve("root/a").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body.to_vec(), content());
+        // the headers of a driver only go to its own links
+        assert_eq!(serve("root/b").await.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn test_shared_link_headers() {
+        let stub = start_stub().await;
+        let link = format!("{}/private", stub);
+        // both drivers list the same link, only the second has the credentials
+        let wheel = Wheel::new(vec![
+            private_driver("a", &link, vec![]),
+            private_driver(
+                "a",
+                &link,
+                vec![("authorization".to_string(), "Bearer secret".to_string())],
+            ),
+        ])
+        .await;
+        let file = wheel.lookup("root/a", None).await.found().unwrap();
+        assert_eq!(file.links.len(), 2);
+        let response = Proxy::default()
+            .with_selector(InOrder)
+            .with_headers(wheel.clone())
+            .serve(&file, &Method::GET, &HeaderMap::new())
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_no_links() {
+        let file = file(vec![]);
+        let proxy = Proxy::default();
+        assert_eq!(proxy.pick(&file), None);
+        assert_eq!(proxy.redirect(&file).status(), StatusCode::BAD_GATEWAY);
+        let response = proxy.serve(&file, &Method::GET, &HeaderMap::new()).await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn test_range() {
+        let stub = start_stub().await;
+        let file = file(vec![format!("{}/file/1000", stub)]);
+        let proxy = Proxy::default();
+        let response = proxy
+            .serve(&file, &Method::GET, &range("bytes=100-199"))
+            .await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            response.headers()[header::CONTENT_RANGE],
+            "bytes 100-199/600"
+        );
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body.to_vec(), content()[100..200].to_vec());
+
+        let response = proxy.serve(&file, &Method::GET, &range("bytes=600-")).await;
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes */600");
+    }
+
+    #[tokio::test]
+    async fn test_head() {
+        // never contacted
+        let file = file(vec!["http://127.0.0.1:1/file".to_string()]);
+        let response = Proxy::default()
+            .serve(&file, &Method::HEAD, &range("bytes=-100"))
+            .await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "100");
+        assert_eq!(
+            response.headers()[header::CONTENT_RANGE],
+            "bytes 500-599/600"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resume_on_next_link() {
+        let stub = start_stub().await;
+        // whichever link is picked first, the two together provide the whole file
+        let file = file(vec![
+            format!("{}/file/300", stub),
+            format!("{}/file/400", stub),
+        ]);
+        let response = Proxy::default()
+            .serve(&file, &Method::GET, &HeaderMap::new())
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body.to_vec(), content());
+    }
+
+    #[tokio::test]
+    async fn test_failover() {
+        let stub = start_stub().await;
+        let file = file(vec![
+            "http://127.0.0.1:1/file".to_string(),
+            format!("{}/missing", stub),
+            format!("{}/unavailable", stub),
+            format!("{}/file/250", stub),
+            format!("{}/missing", stub),
+            format!("{}/file/200", stub),
+            format!("{}/file/1000", stub),
+        ]);
+        let response = Proxy::default()
+            .with_selector(InOrder)
+            .serve(&file, &Method::GET, &range("bytes=50-"))
+            .await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body.to_vec(), content()[50..].to_vec());
+    }
+
+    #[tokio::test]
+    async fn test_every_link_fails() {
+        let stub = start_stub().await;
+        let broken = file(vec![format!("{}/file/100", stub)]);
+        let response = Proxy::default()
+            .serve(&broken, &Method::GET, &HeaderMap::new())
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(to_bytes(response.into_body(), usize::MAX).await.is_err());
+
+        let missing = file(vec![format!("{}/missing", stub)]);
+        let response = Proxy::default()
+            .serve(&missing, &Method::GET, &HeaderMap::new())
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    }
+}
@@ -1,33 +1,175 @@
-use std::sync::atomic::{AtomicPtr, Ordering};
+#[cfg(loom)]
+use loom::{
+    sync::atomic::{fence, AtomicPtr, AtomicUsize, Ordering},
+    sync::{Mutex, MutexGuard},
+    thread::yield_now,
+};
+use std::fmt;
+use std::marker::PhantomData;
 use std::sync::Arc;
+#[cfg(not(loom))]
+use std::{
+    sync::atomic::{fence, AtomicPtr, AtomicUsize, Ordering},
+    sync::{Mutex, MutexGuard},
+    thread::yield_now,
+};
 
-#[derive(Debug)]
-pub struct ReadCopyUpdate<T>(AtomicPtr<Arc<T>>);
+/// A read-copy-update cell.
+///
+/// Readers get an `Arc` to the current value without blocking. Writers publish a new value
+/// and wait for a grace period before releasing their reference to the old one, so a reader
+/// that loaded the old pointer is guaranteed to have taken its own reference first.
+///
+/// The grace period is tracked by two reader counters. A reader registers in the slot chosen
+/// by `epoch` before loading the pointer, and a writer drains both slots after swapping it.
+/// The `SeqCst` fences on both sides make sure that either the writer sees the reader's
+/// registration or the reader sees the new pointer. The writer flips `epoch` before draining
+/// each slot, so new readers never keep the slot being drained busy.
+///
+/// The cell hands out `Arc<T>` across threads, so it is `Send` and `Sync` only when `T` is:
+///
+/// ```compile_fail
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<rlist_vfs::rcu::ReadCopyUpdate<std::rc::Rc<u8>>>();
+/// ```
+pub struct ReadCopyUpdate<T> {
+    /// Raw pointer produced by `Arc::into_raw`, owns one strong count.
+    current: AtomicPtr<T>,
+    epoch: AtomicUsize,
+    readers: [AtomicUsize; 2],
+    /// Serializes writers, readers never touch it.
+    writer: Mutex<()>,
+    /// `AtomicPtr` is `Send + Sync` for any `T`, the cell must behave like the `Arc` it owns.
+    _owns: PhantomData<Arc<T>>,
+}
 
 impl<T> ReadCopyUpdate<T> {
     pub fn new(value: T) -> Self {
-        ReadCopyUpdate(AtomicPtr::new(Box::into_raw(Box::new(Arc::new(value)))))
+        ReadCopyUpdate {
+            current: AtomicPtr::new(Arc::into_raw(Arc::new(value)) as *mut T),
+            epoch: AtomicUsize::new(0),
+            readers: [AtomicUsize::new(0), AtomicUsize::new(0)],
+            writer: Mutex::new(()),
+            _owns: PhantomData,
+        }
     }
 
+    /// Get the current value.
     pub fn read(&self) -> Arc<T> {
-        unsafe { Arc::clone(&*self.0.load(Ordering::Relaxed)) }
+        let slot = self.epoch.load(Ordering::Relaxed) & 1;
+        self.readers[slot].fetch_add(1, Ordering::Relaxed);
+        fence(Ordering::SeqCst);
+        let ptr = self.current.load(Ordering::Acquire);
+        // SAFETY: `ptr` came from `Arc::into_raw`, and the writer that replaces it waits for
+        // `readers[slot]` to drain before giving up its strong count.
+        let value = unsafe {
+            Arc::increment_strong_count(ptr);
+            Arc::from_raw(ptr)
+        };
+        self.readers[slot].fetch_sub(1, Ordering::Release);
+        value
     }
 
+    /// Replace the current value.
     pub fn update(&self, value: T) {
+        self.swap(value);
+    }
+
+    /// Replace the current value and return the previous one.
+    pub fn swap(&self, value: T) -> Arc<T> {
         let new = Arc::new(value);
-        let old = self.0.swap(Box::into_raw(Box::new(new)), Ordering::Relaxed);
-        unsafe {
-            drop(Box::from_raw(old));
+        let _writer = self.lock_writer();
+        self.publish(new)
+    }
+
+    /// Read-modify-write: build the new value from the current one and publish it.
+    ///
+    /// Writers are serialized, so no other update can slip in between reading the current
+    /// value and publishing the result, and `f` is called exactly once. Returns the previous
+    /// value.
+    ///
+    /// `f` runs while the writer lock is held, so it must not write to the same cell: a nested
+    /// [update](ReadCopyUpdate::update), [swap](ReadCopyUpdate::swap), `rcu` or
+    /// [compare_and_swap](ReadCopyUpdate::compare_and_swap) deadlocks. Reading it is fine.
+    pub fn rcu<F>(&self, f: F) -> Arc<T>
+    where
+        F: FnOnce(&T) -> T,
+    {
+        let _writer = self.lock_writer();
+        let new = Arc::new(f(&self.read()));
+        self.publish(new)
+    }
+
+    /// Publish `new` only if the current value is still `current` (compared by pointer).
+    ///
+    /// Returns the previous value on success, or the actual current value on failure.
+    pub fn compare_and_swap(&self, current: &Arc<T>, new: T) -> Result<Arc<T>, Arc<T>> {
+        let _writer = self.lock_writer();
+        let actual = self.read();
+        if !Arc::ptr_eq(&actual, current) {
+            return Err(actual);
         }
+        Ok(self.publish(Arc::new(new)))
+    }
+
+    fn lock_writer(&self) -> MutexGuard<'_, ()> {
+        self.writer.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Swap in `new` and take back the cell's strong count of the old value.
+    ///
+    /// Must be called with `writer` held.
+    fn publish(&self, new: Arc<T>) -> Arc<T> {
+        #[cfg(all(feature = "metrics", not(loom)))]
+        let start = std::time::Instant::now();
+        let new = Arc::into_raw(new) as *mut T;
+        let old = self.current.swap(new, Ordering::AcqRel);
+        self.synchronize();
+        #[cfg(all(feature = "metrics", not(loom)))]
+        crate::metrics::rcu_swapped(start.elapsed());
+        // SAFETY: no reader can still be between loading `old` and taking its own reference.
+        unsafe { Arc::from_raw(old) }
+    }
+
+    /// Wait until every reader that may have seen the previous pointer is done with it.
+    ///
+    /// Must be called with `writer` held.
+    fn synchronize(&self) {
+        for _ in 0..2 {
+            let epoch = self.epoch.load(Ordering::Relaxed);
+            self.epoch.store(epoch.wrapping_add(1), Ordering::Relaxed);
+            fence(Ordering::SeqCst);
+            while self.readers[epoch & 1].load(Ordering::Acquire) != 0 {
+                yield_now();
+            }
+        }
+    }
+}
+
+impl<T> Drop for ReadCopyUpdate<T> {
+    fn drop(&mut self) {
+        let ptr = self.current.load(Ordering::Acquire);
+        // SAFETY: `&mut self` means there are no readers left, the cell owns one strong count.
+        unsafe { drop(Arc::from_raw(ptr)) };
     }
 }
 
-unsafe impl<T> Sync for ReadCopyUpdate<T> {}
+impl<T: fmt::Debug> fmt::Debug for ReadCopyUpdate<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ReadCopyUpdate").field(&self.read()).finish()
+    }
+}
+
+impl<T: Default> Default for ReadCopyUpdate<T> {
+    fn default() -> Self {
+        ReadCopyUpdate::new(T::default())
+    }
+}
 
-#[cfg(test)]
+#[cfg(all(test, not(loom)))]
 mod tests {
     use super::*;
-    use std::sync::atomic::{AtomicBool, AtomicUsize};
+    use std::sync::atomic::AtomicBool;
 
     #[test]
     fn test_read_copy_update_1() {
@@ -65,4 +207,215 @@ mod tests {
 
         should_break_copy.store(true, Ordering::Relaxed);
     }
+
+    #[test]
+    fn test_drop_releases_values() {
+        let first = Arc::new(1);
+        let second = Arc::new(2);
+        let rcu = ReadCopyUpdate::new(first.clone());
+        let reader = rcu.read();
+        rcu.update(second.clone());
+        assert_eq!(Arc::strong_count(&first), 2);
+        drop(reader);
+        assert_eq!(Arc::strong_count(&first), 1);
+        drop(rcu);
+        assert_eq!(Arc::strong_count(&second), 1);
+    }
+
+    #[test]
+    fn test_concurrent_readers_and_writers() {
+        let rcu = Arc::new(ReadCopyUpdate::new(vec![0usize; 64]));
+        let handles = (0..4)
+            .map(|i| {
+                let rcu = rcu.clone();
+                std::thread::spawn(move || {
+                    for j in 0..1000 {
+                        if i == 0 {
+                            rcu.update(vec![j; 64]);
+                        } else {
+                            let value = rcu.read();
+                            assert!(value.iter().all(|x| *x == value[0]));
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(*rcu.read(), vec![999; 64]);
+    }
+
+    #[test]
+    fn test_swap() {
+        let rcu = ReadCopyUpdate::new(1);
+        assert_eq!(*rcu.swap(2), 1);
+        assert_eq!(*rcu.read(), 2);
+    }
+
+    #[test]
+    fn test_rcu() {
+        let rcu = Arc::new(ReadCopyUpdate::new(0usize));
+        let handles = (0..4)
+            .map(|_| {
+                let rcu = rcu.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..1000 {
+                        rcu.rcu(|x| x + 1);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(*rcu.read(), 4000);
+    }
+
+    #[test]
+    fn test_compare_and_swap() {
+        let rcu = ReadCopyUpdate::new(String::from("a"));
+        let seen = rcu.read();
+        let previous = rcu.compare_and_swap(&seen, String::from("b")).unwrap();
+        assert!(Arc::ptr_eq(&previous, &seen));
+        assert_eq!(*rcu.read(), "b");
+
+        // `seen` is stale now, even though an equal value is compared
+        let actual = rcu.compare_and_swap(&seen, String::from("c")).unwrap_err();
+        assert_eq!(*actual, "b");
+        assert_eq!(*rcu.read(), "b");
+    }
+
+    #[test]
+    fn test_send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<ReadCopyUpdate<Vec<u8>>>();
+        assert_send_sync::<ReadCopyUpdate<std::sync::Mutex<u8>>>();
+    }
+}
+
+/// Run with `RUSTFLAGS="--cfg loom" cargo test --release -p rlist_vfs --lib rcu`.
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+    use loom::sync::atomic::AtomicBool;
+    use loom::thread;
+
+    /// Records in `freed` when it is dropped, so readers can tell if they got a dead value.
+    struct Tracked {
+        id: usize,
+        freed: loom::sync::Arc<Vec<AtomicBool>>,
+    }
+
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            assert!(
+                !self.freed[self.id].swap(true, Ordering::SeqCst),
+                "double free"
+            );
+        }
+    }
+
+    fn freed_flags(n: usize) -> loom::sync::Arc<Vec<AtomicBool>> {
+        loom::sync::Arc::new((0..n).map(|_| AtomicBool::new(false)).collect())
+    }
+
+    #[test]
+    fn read_during_update() {
+        loom::model(|| {
+            let freed = freed_flags(2);
+            let rcu = loom::sync::Arc::new(ReadCopyUpdate::new(Tracked {
+                id: 0,
+                freed: freed.clone(),
+            }));
+
+            let reader = {
+                let rcu = rcu.clone();
+                let freed = freed.clone();
+                thread::Builder::new()
+                    .stack_size(1 << 20)
+                    .spawn(move || {
+                        let value = rcu.read();
+                        assert!(!freed[value.id].load(Ordering::SeqCst));
+                        value.id
+                    })
+                    .unwrap()
+            };
+
+            rcu.update(Tracked {
+                id: 1,
+                freed: freed.clone(),
+            });
+            let id = reader.join().unwrap();
+            assert!(id <= 1);
+            assert!(freed[0].load(Ordering::SeqCst));
+            assert!(!freed[1].load(Ordering::SeqCst));
+            drop(rcu);
+            assert!(freed[1].load(Ordering::SeqCst));
+        });
+    }
+
+    #[test]
+    fn concurrent_updates() {
+        let mut builder = loom::model::Builder::new();
+        builder.preemption_bound = Some(3);
+        builder.check(|| {
+            let freed = freed_flags(3);
+            let rcu = loom::sync::Arc::new(ReadCopyUpdate::new(Tracked {
+                id: 0,
+                freed: freed.clone(),
+            }));
+
+            let writer = {
+                let rcu = rcu.clone();
+                let freed = freed.clone();
+                thread::spawn(move || rcu.update(Tracked { id: 1, freed }))
+            };
+            let reader = {
+                let rcu = rcu.clone();
+                let freed = freed.clone();
+                thread::spawn(move || {
+                    let value = rcu.read();
+                    assert!(!freed[value.id].load(Ordering::SeqCst));
+                })
+            };
+
+            rcu.update(Tracked {
+                id: 2,
+                freed: freed.clone(),
+            });
+            writer.join().unwrap();
+            reader.join().unwrap();
+
+            let alive = (0..3)
+                .filter(|i| !freed[*i].load(Ordering::SeqCst))
+                .collect::<Vec<_>>();
+            assert_eq!(alive.len(), 1);
+            assert_eq!(rcu.read().id, alive[0]);
+            drop(rcu);
+            assert!(freed.iter().all(|x| x.load(Ordering::SeqCst)));
+        });
+    }
+
+    #[test]
+    fn concurrent_read_modify_write() {
+        loom::model(|| {
+            let rcu = loom::sync::Arc::new(ReadCopyUpdate::new(0));
+            let seen = rcu.read();
+
+            let incrementer = {
+                let rcu = rcu.clone();
+                thread::spawn(move || {
+                    rcu.rcu(|x| x + 1);
+                })
+            };
+            let swapped = rcu.compare_and_swap(&seen, 10).is_ok();
+            incrementer.join().unwrap();
+
+            // either the swap saw the initial value and the increment ran after it,
+            // or the increment ran first and the swap lost
+            let expected = if swapped { 11 } else { 1 };
+            assert_eq!(*rcu.read(), expected);
+        });
+    }
 }
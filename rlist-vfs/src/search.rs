@@ -0,0 +1,549 @@
+use crate::combinable_dir::CombinableDir;
+use crate::diff::TreeDiff;
+use crate::static_combinable::StaticCombinableFile;
+use crate::without_link::FileWithoutLink;
+use chrono::{DateTime, Utc};
+use globset::{GlobBuilder, GlobMatcher};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+
+type Tree = CombinableDir<StaticCombinableFile>;
+
+/// How many hits a [Query] returns when it does not say.
+pub const DEFAULT_LIMIT: usize = 50;
+
+/// How the text of a [Query] is matched, always ignoring case.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    #[default]
+    Substring,
+    Prefix,
+    /// Like `*.iso`, where `*` also matches `/`.
+    Glob,
+    /// The characters of the text appear in order, like `ubtu` in `ubuntu`. Hits are ranked,
+    /// the closest first.
+    Fuzzy,
+}
+
+/// A search of the files of a [SearchIndex], like
+/// `{"text": "ubuntu", "mode": "fuzzy", "extensions": "iso,img", "limit": 20}`.
+///
+/// Every field is optional, an empty query finds every file.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Query {
+    pub text: String,
+    pub mode: Mode,
+    /// Match the path, like `root/pub/a.iso`, instead of the name.
+    pub in_path: bool,
+    /// Files of at least this many bytes.
+    pub min_size: Option<u64>,
+    /// Files of at most this many bytes.
+    pub max_size: Option<u64>,
+    pub modified_after: Option<DateTime<Utc>>,
+    pub modified_before: Option<DateTime<Utc>>,
+    /// Files with one of these extensions, without the dot and ignoring case. Written as a
+    /// comma separated list, like `iso,img`.
+    #[serde(deserialize_with = "comma_separated")]
+    pub extensions: Vec<String>,
+    /// How many hits to skip.
+    pub offset: usize,
+    /// How many hits to return at most, [DEFAULT_LIMIT] if not set.
+    pub limit: Option<usize>,
+}
+
+fn comma_separated<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    let list = String::deserialize(deserializer)?;
+    Ok(list
+        .split(',')
+        .map(|x| x.trim().trim_start_matches('.').to_lowercase())
+        .filter(|x| !x.is_empty())
+        .collect())
+}
+
+/// One page of the files found by a [Query].
+#[derive(Clone, Serialize)]
+pub struct SearchResults {
+    /// How many files were found, on every page.
+    pub total: usize,
+    pub hits: Vec<Hit>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct Hit {
+    /// A key of [Wheel::path_map](crate::Wheel::path_map).
+    pub path: String,
+    #[serde(flatten)]
+    pub file: FileWithoutLink,
+}
+
+#[derive(Clone)]
+struct Entry {
+    /// The lowercase path, the name is its last segment.
+    lowercase: String,
+    file: FileWithoutLink,
+}
+
+impl Entry {
+    fn new(path: &str, file: StaticCombinableFile) -> Self {
+        Self {
+            lowercase: path.to_lowercase(),
+            file: file.into(),
+        }
+    }
+
+    fn name(&self) -> &str {
+        self.lowercase.rsplit('/').next().unwrap_or_default()
+    }
+
+    fn extension(&self) -> Option<&str> {
+        self.name().rsplit_once('.').map(|(_, x)| x)
+    }
+}
+
+/// Three consecutive characters of a lowercase path.
+type Trigram = [char; 3];
+
+/// The distinct trigrams of `text`.
+fn trigrams(text: &str) -> HashSet<Trigram> {
+    let chars = text.chars().collect::<Vec<_>>();
+    chars.windows(3).map(|x| [x[0], x[1], x[2]]).collect()
+}
+
+/// The files of a tree by path, ready to be searched.
+///
+/// Build it once with [SearchIndex::new], then keep it up to date with [SearchIndex::apply],
+/// which only touches the files that changed.
+///
+/// Every trigram of the lowercase paths has the list of paths it appears in, so a
+/// [Mode::Substring] or [Mode::Prefix] search of at least 3 characters only looks at the
+/// files that contain all of its trigrams. Other searches look at every file. Cloning the
+/// index shares the entries and the lists, which are only copied once changed.
+#[derive(Clone, Default)]
+pub struct SearchIndex {
+    entries: BTreeMap<Arc<str>, Arc<Entry>>,
+    postings: HashMap<Trigram, Arc<HashSet<Arc<str>>>>,
+}
+
+impl SearchIndex {
+    /// Index every file of `root`, whatever its visibility.
+    pub fn new(root: &Tree) -> Self {
+        let mut index = Self::default();
+        for (path, file) in root.clone().compress_path() {
+            index.insert(&path, file);
+        }
+        index
+    }
+
+    /// Update the index of the old tree of `diff` to the new one.
+    pub fn apply(&mut self, diff: &TreeDiff) {
+        for path in &diff.removed {
+            self.remove(path);
+        }
+        for (path, file) in diff.added.iter().chain(&diff.modified) {
+            self.insert(path, (*file).clone());
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn insert(&mut self, path: &str, file: StaticCombinableFile) {
+        self.remove(path);
+        let path: Arc<str> = Arc::from(path);
+        let entry = Entry::new(&path, file);
+        for trigram in trigrams(&entry.lowercase) {
+            let paths = self.postings.entry(trigram).or_default();
+            Arc::make_mut(paths).insert(path.clone());
+        }
+        self.entries.insert(path, Arc::new(entry));
+    }
+
+    fn remove(&mut self, path: &str) {
+        let Some(entry) = self.entries.remove(path) else {
+            return;
+        };
+        for trigram in trigrams(&entry.lowercase) {
+            if let Some(paths) = self.postings.get_mut(&trigram) {
+                Arc::make_mut(paths).remove(path);
+                if paths.is_empty() {
+                    self.postings.remove(&trigram);
+                }
+            }
+        }
+    }
+
+    /// The paths that contain every trigram of `text`, in path order, or `None` if `text` is
+    /// too short to have any.
+    fn candidates(&self, text: &str) -> Option<Vec<&Arc<str>>> {
+        let trigrams = trigrams(text);
+        if trigrams.is_empty() {
+            return None;
+        }
+        let mut lists = Vec::new();
+        for trigram in &trigrams {
+            match self.postings.get(trigram) {
+                Some(paths) => lists.push(paths),
+                None => return Some(vec![]),
+            }
+        }
+        lists.sort_by_key(|x| x.len());
+        let (shortest, rest) = lists.split_first()?;
+        let mut paths = shortest
+            .iter()
+            .filter(|path| rest.iter().all(|x| x.contains(*path)))
+            .collect::<Vec<_>>();
+        paths.sort();
+        Some(paths)
+    }
+
+    /// The files matching `query`, in path order, or the closest first for [Mode::Fuzzy].
+    ///
+    /// `Err` if the glob of the query is invalid.
+    pub fn search(&self, query: &Query) -> Result<SearchResults, String> {
+        self.search_filtered(query, |_| true)
+    }
+
+    /// Like [search](SearchIndex::search), with only the files whose path is `allowed`, so
+    /// the others are not counted either.
+    pub fn search_filtered(
+        &self,
+        query: &Query,
+        allowed: impl Fn(&str) -> bool,
+    ) -> Result<SearchResults, String> {
+        let text = query.text.to_lowercase();
+        let glob = match query.mode {
+            Mode::Glob => Some(compile_glob(&query.text)?),
+            _ => None,
+        };
+        let candidates = match query.mode {
+            Mode::Substring | Mode::Prefix => self.candidates(&text),
+            Mode::Glob | Mode::Fuzzy => None,
+        };
+        let entries: Box<dyn Iterator<Item = (&Arc<str>, &Arc<Entry>)>> = match candidates {
+            Some(paths) => Box::new(
+                paths
+                    .into_iter()
+                    .filter_map(|path| self.entries.get_key_value(path)),
+            ),
+            None => Box::new(self.entries.iter()),
+        };
+        let mut found = entries
+            .filter(|(path, entry)| filter(query, entry) && allowed(path))
+            .filter_map(|(path, entry)| {
+                let haystack = match query.in_path {
+                    true => entry.lowercase.as_str(),
+                    false => entry.name(),
+                };
+                let score = match (&query.mode, &glob) {
+                    (Mode::Substring, _) => haystack.contains(&text).then_some(0),
+                    (Mode::Prefix, _) => haystack.starts_with(&text).then_some(0),
+                    (Mode::Glob, Some(glob)) => glob.is_match(haystack).then_some(0),
+                    (Mode::Fuzzy, _) => fuzzy_score(&text, haystack),
+                    (Mode::Glob, None) => unreachable!(),
+                };
+                score.map(|x| (x, path, entry))
+            })
+            .collect::<Vec<_>>();
+        if query.mode == Mode::Fuzzy {
+            // stable, so equal scores stay in path order
+            found.sort_by_key(|(score, _, _)| std::cmp::Reverse(*score));
+        }
+        let hits = found
+            .iter()
+            .skip(query.offset)
+            .take(query.limit.unwrap_or(DEFAULT_LIMIT))
+            .map(|(_, path, entry)| Hit {
+                path: path.to_string(),
+                file: entry.file.clone(),
+            })
+            .collect();
+        Ok(SearchResults {
+            total: found.len(),
+            hits,
+        })
+    }
+}
+
+fn compile_glob(glob: &str) -> Result<GlobMatcher, String> {
+    GlobBuilder::new(glob)
+        .case_insensitive(true)
+        .build()
+        .map(|x| x.compile_matcher())
+        .map_err(|e| format!("invalid glob {}: {}", glob, e))
+}
+
+/// Whether `entry` passes the size, time and extension filters of `query`.
+fn filter(query: &Query, entry: &Entry) -> bool {
+    let file = &entry.file;
+    query.min_size.is_none_or(|x| file.size >= x)
+        && query.max_size.is_none_or(|x| file.size <= x)
+        && query.modified_after.is_none_or(|x| file.last_modified > x)
+        && query.modified_before.is_none_or(|x| file.last_modified < x)
+        && (query.extensions.is_empty()
+            || entry
+                .extension()
+                .is_some_and(|x| query.extensions.iter().any(|y| y == x)))
+}
+
+/// How closely `haystack` matches `needle`, both lowercase, or `None` if the characters of
+/// `needle` do not appear in `haystack` in order.
+///
+/// Every matched character scores, more if it follows the previous match or starts a word, and
+/// every character of `haystack` left unmatched costs a little.
+fn fuzzy_score(needle: &str, haystack: &str) -> Option<i64> {
+    let mut score = 0;
+    let mut needle = needle.chars().peekable();
+    let mut previous: Option<char> = None;
+    let mut consecutive = false;
+    let mut unmatched = 0;
+    for c in haystack.chars() {
+        if needle.peek() == Some(&c) {
+            needle.next();
+            score += 10;
+            if consecutive {
+                score += 15;
+            }
+            if previous.is_none_or(|x| matches!(x, '/' | '.' | '_' | '-' | ' ')) {
+                score += 10;
+            }
+            consecutive = true;
+        } else {
+            consecutive = false;
+            unmatched += 1;
+        }
+        previous = Some(c);
+    }
+    match needle.peek() {
+        Some(_) => None,
+        None => Some(score - unmatched),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::static_combinable::test_file;
+    use std::time::Duration;
+
+    /// Like [test_file], modified `days` days after the Unix epoch.
+    fn dated_file(name: &str, size: u64, days: u64) -> StaticCombinableFile {
+        let mut file = test_file(name, size);
+        file.last_modified += Duration::from_secs(days * 86400);
+        file
+    }
+
+    // root
+    // ├── README.md
+    // ├── pub
+    // │   ├── ubuntu-24.04.iso
+    // │   ├── debian-12.iso
+    // │   └── ubuntu-24.04.iso.sha256
+    // └── docs
+    //     └── ubuntu-guide.pdf
+    fn index() -> SearchIndex {
+        let public = CombinableDir::new(
+            "pub".to_string(),
+            vec![
+                dated_file("ubuntu-24.04.iso", 6000, 3),
+                dated_file("debian-12.iso", 4000, 2),
+                dated_file("ubuntu-24.04.iso.sha256", 64, 3),
+            ],
+            vec![],
+        );
+        let docs = CombinableDir::new(
+            "docs".to_string(),
+            vec![dated_file("ubuntu-guide.pdf", 100, 1)],
+            vec![],
+        );
+        let root = CombinableDir::new(
+            "root".to_string(),
+            vec![dated_file("README.md", 10, 0)],
+            vec![public, docs],
+        );
+        SearchIndex::new(&root)
+    }
+
+    fn query(text: &str, mode: Mode) -> Query {
+        Query {
+            text: text.to_string(),
+            mode,
+            ..Query::default()
+        }
+    }
+
+    fn paths(results: SearchResults) -> Vec<String> {
+        results.hits.into_iter().map(|x| x.path).collect()
+    }
+
+    #[test]
+    fn test_modes() {
+        let index = index();
+        assert_eq!(index.len(), 5);
+        assert_eq!(
+            paths(index.search(&query("UBUNTU", Mode::Substring)).unwrap()),
+            vec![
+                "root/docs/ubuntu-guide.pdf",
+                "root/pub/ubuntu-24.04.iso",
+                "root/pub/ubuntu-24.04.iso.sha256"
+            ]
+        );
+        assert_eq!(
+            paths(index.search(&query("read", Mode::Prefix)).unwrap()),
+            vec!["root/README.md"]
+        );
+        assert_eq!(
+            paths(index.search(&query("*.iso", Mode::Glob)).unwrap()),
+            vec!["root/pub/debian-12.iso", "root/pub/ubuntu-24.04.iso"]
+        );
+        assert!(index.search(&query("[", Mode::Glob)).is_err());
+
+        let mut in_path = query("root/pub/*", Mode::Glob);
+        assert_eq!(index.search(&in_path).unwrap().total, 0);
+        in_path.in_path = true;
+        assert_eq!(index.search(&in_path).unwrap().total, 3);
+    }
+
+    #[test]
+    fn test_fuzzy() {
+        let index = index();
+        assert_eq!(
+            paths(index.search(&query("ubuiso", Mode::Fuzzy)).unwrap()),
+            vec![
+                "root/pub/ubuntu-24.04.iso",
+                "root/pub/ubuntu-24.04.iso.sha256"
+            ]
+        );
+        // the words of the name rank first
+        let hits = paths(index.search(&query("ug", Mode::Fuzzy)).unwrap());
+        assert_eq!(hits[0], "root/docs/ubuntu-guide.pdf");
+        assert!(fuzzy_score("rdme", "readme").is_some());
+        assert!(fuzzy_score("rdme", "dream").is_none());
+    }
+
+    #[test]
+    fn test_filters() {
+        let index = index();
+        let query: Query = serde_json::from_str(
+            r#"{"min_size": 100, "max_size": 5000, "extensions": "ISO, .pdf"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            paths(index.search(&query).unwrap()),
+            vec!["root/docs/ubuntu-guide.pdf", "root/pub/debian-12.iso"]
+        );
+
+        let query: Query = serde_json::from_str(
+            r#"{"modified_after": "1970-01-02T12:00:00Z", "modified_before": "1970-01-04T00:00:00Z"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            paths(index.search(&query).unwrap()),
+            vec!["root/pub/debian-12.iso"]
+        );
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut index = index();
+        let old = CombinableDir::new(
+            "root".to_string(),
+            vec![dated_file("README.md", 10, 0)],
+            vec![],
+        );
+        let new = CombinableDir::new(
+            "root".to_string(),
+            vec![
+                dated_file("README.md", 20, 0),
+                dated_file("ubuntu-26.04.iso", 7000, 4),
+            ],
+            vec![],
+        );
+        index.apply(&crate::diff::diff(&old, &new));
+        assert_eq!(index.len(), 6);
+        let hits = index.search(&query("readme", Mode::Prefix)).unwrap().hits;
+        assert_eq!(hits[0].file.size, 20);
+        assert_eq!(
+            paths(index.search(&query("*.iso", Mode::Glob)).unwrap()),
+            vec![
+                "root/pub/debian-12.iso",
+                "root/pub/ubuntu-24.04.iso",
+                "root/ubuntu-26.04.iso"
+            ]
+        );
+
+        index.apply(&crate::diff::diff(&new, &old));
+        assert_eq!(index.len(), 5);
+        assert_eq!(
+            index
+                .search(&query("26.04", Mode::Substring))
+                .unwrap()
+                .total,
+            0
+        );
+    }
+
+    #[test]
+    fn test_postings() {
+        let mut index = index();
+        let paths = |text| {
+            index
+                .candidates(text)
+                .map(|x| x.iter().map(|x| x.to_string()).collect::<Vec<_>>())
+        };
+        assert_eq!(
+            paths("ubuntu-2").unwrap(),
+            vec![
+                "root/pub/ubuntu-24.04.iso",
+                "root/pub/ubuntu-24.04.iso.sha256"
+            ]
+        );
+        assert_eq!(paths("zzz").unwrap(), Vec::<String>::new());
+        assert_eq!(paths("ub"), None);
+
+        // the lists follow the files that changed
+        let old = CombinableDir::new(
+            "root".to_string(),
+            vec![dated_file("README.md", 10, 0)],
+            vec![],
+        );
+        let new = CombinableDir::new(
+            "root".to_string(),
+            vec![dated_file("ubuntu-26.04.iso", 7000, 4)],
+            vec![],
+        );
+        let before = index.clone();
+        index.apply(&crate::diff::diff(&old, &new));
+        assert!(!index.postings.contains_key(&['m', 'e', '.']));
+        assert_eq!(index.candidates("ubuntu").unwrap().len(), 4);
+        // the clone is not changed
+        assert_eq!(before.candidates("ubuntu").unwrap().len(), 3);
+        assert_eq!(before.candidates("readme").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_pagination() {
+        let index = index();
+        let mut query = Query {
+            limit: Some(2),
+            ..Query::default()
+        };
+        let first = index.search(&query).unwrap();
+        assert_eq!(first.total, 5);
+        assert_eq!(
+            paths(first),
+            vec!["root/README.md", "root/docs/ubuntu-guide.pdf"]
+        );
+        query.offset = 4;
+        let last = index.search(&query).unwrap();
+        assert_eq!(last.total, 5);
+        assert_eq!(paths(last), vec!["root/pub/ubuntu-24.04.iso.sha256"]);
+    }
+}
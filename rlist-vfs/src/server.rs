@@ -0,0 +1,632 @@
+use crate::acl::{AccessControl, Identity, IdentityProvider};
+use crate::health::HealthChecker;
+use crate::password::Lookup;
+use crate::proxy::Proxy;
+use crate::search;
+use crate::signed_url::{SignedUrlError, SignedUrls};
+use crate::webdav;
+use crate::{VfsBasicMeta, Wheel};
+use axum::body::Body;
+use axum::extract::{FromRef, Path, Query, RawQuery, State};
+use axum::http::{header, HeaderMap, Method, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use futures::StreamExt;
+use serde::Deserialize;
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio::net::{TcpListener, ToSocketAddrs};
+
+/// Header carrying the password of a protected directory, see [Wheel::set_passwords].
+pub const PASSWORD_HEADER: &str = "x-rlist-password";
+
+/// The HTTP service of a [Wheel]:
+/// - `GET /api/tree`: the listed tree as JSON, streamed in chunks, see [Wheel::tree_chunks]
+/// - `GET /api/list?path=root/dir`: one listed directory as JSON, the root if `path` is empty
+/// - `GET /api/search?text=iso&mode=fuzzy&limit=20`: the listed files found by a
+///   [search::Query] as JSON, see [Wheel::search], or `400 Bad Request` if it is invalid
+/// - `GET /d/<path>`: `302 Found` to a download link of the file, see [Proxy::pick], or the
+///   file itself if it is proxied, see [Proxy]
+/// - `/dav/`: the read-only WebDAV service, see [webdav::router](crate::webdav::router)
+/// - `GET /metrics`: the metrics of the `Wheel` in the Prometheus text format, see
+///   `Wheel::metrics`, with the `metrics` feature
+///
+/// Protected paths answer `401 Unauthorized` with `{"password_required": "/dir"}` unless the
+/// password is sent in the [PASSWORD_HEADER] header or by basic authentication.
+///
+/// Callers are not identified, so an [AccessPolicy](crate::acl::AccessPolicy) is not enforced,
+/// see [RouterBuilder::with_access_control].
+pub fn router(wheel: Arc<Wheel>) -> Router {
+    RouterBuilder::new(wheel).build()
+}
+
+/// Like [router], with the links picked in the order of `health`, see [HealthChecker].
+///
+/// The probes are not served, since they reveal the links of hidden and proxied files. Show
+/// [HealthChecker::report] to administrators only.
+pub fn router_with_health(wheel: Arc<Wheel>, health: Arc<HealthChecker>) -> Router {
+    RouterBuilder::new(wheel).with_health(health).build()
+}
+
+/// Builds the service of [router] with the optional parts.
+pub struct RouterBuilder {
+    wheel: Arc<Wheel>,
+    proxy: Proxy,
+    access: Option<Access>,
+    signed_urls: Option<Arc<SignedUrls>>,
+}
+
+/// The [AccessControl] of the routes and who the callers are.
+#[derive(Clone)]
+struct Access {
+    control: Arc<AccessControl>,
+    identities: Arc<dyn IdentityProvider>,
+}
+
+impl RouterBuilder {
+    pub fn new(wheel: Arc<Wheel>) -> Self {
+        Self {
+            wheel,
+            proxy: Proxy::default(),
+            access: None,
+            signed_urls: None,
+        }
+    }
+
+    /// Pick the links in the order of `health`, see [router_with_health].
+    pub fn with_health(self, health: Arc<HealthChecker>) -> Self {
+        Self {
+            proxy: self.proxy.with_selector(health),
+            ..self
+        }
+    }
+
+    /// Serve `/api/tree`, `/api/list`, `/api/search` and `/d/<path>` through `access`, which
+    /// must be of the same [Wheel], to the caller `identities` tell from the bearer token in
+    /// the `Authorization` header.
+    ///
+    /// Callers without a token are anonymous, and those with an unknown one answered
+    /// `401 Unauthorized`. Send the password of a protected directory in the
+    /// [PASSWORD_HEADER] header then. `/dav/` is not served, since WebDAV clients cannot
+    /// send a token.
+    pub fn with_access_control(
+        self,
+        access: Arc<AccessControl>,
+        identities: Arc<dyn IdentityProvider>,
+    ) -> Self {
+        Self {
+            access: Some(Access {
+                control: access,
+                identities,
+            }),
+            ..self
+        }
+    }
+
+    /// Serve `GET /s/<path>?expires=<time>&sign=<signature>`, the URLs of `signed_urls` which
+    /// must be of the same [Wheel], like `/d/<path>` but without a password or an
+    /// [AccessPolicy](crate::acl::AccessPolicy).
+    ///
+    /// Only URLs bound to no client resolve. Invalid signatures answer `403 Forbidden`,
+    /// expired and revoked URLs `410 Gone`.
+    pub fn with_signed_urls(self, signed_urls: Arc<SignedUrls>) -> Self {
+        Self {
+            signed_urls: Some(signed_urls),
+            ..self
+        }
+    }
+
+    pub fn build(self) -> Router {
+        let wheel = self.wheel;
+        #[cfg(feature = "metrics")]
+        let proxy = self.proxy.with_metrics(wheel.metrics().clone());
+        #[cfg(not(feature = "metrics"))]
+        let proxy = self.proxy;
+        let proxy = proxy.with_headers(wheel.clone());
+        let state = AppState {
+            wheel: wheel.clone(),
+            proxy: proxy.clone(),
+            access: self.access.clone(),
+            signed_urls: self.signed_urls.clone(),
+        };
+        let mut router = Router::new()
+            .route("/api/tree", get(tree))
+            .route("/api/list", get(list))
+            .route("/api/search", get(search))
+            .route("/d/*path", get(download));
+        if self.signed_urls.is_some() {
+            router = router.route("/s/*path", get(signed));
+        }
+        let router = router.with_state(state);
+        let router = match self.access {
+            Some(_) => router,
+            None => router.merge(webdav::router_with_proxy(wheel.clone(), "/dav", proxy)),
+        };
+        router.merge(metrics(wheel))
+    }
+}
+
+#[cfg(feature = "metrics")]
+fn metrics(wheel: Arc<Wheel>) -> Router {
+    let render = move || async move {
+        (
+            [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+            wheel.metrics().render(),
+        )
+    };
+    Router::new().route("/metrics", get(render))
+}
+
+#[cfg(not(feature = "metrics"))]
+fn metrics(_wheel: Arc<Wheel>) -> Router {
+    Router::new()
+}
+
+#[derive(Clone)]
+struct AppState {
+    wheel: Arc<Wheel>,
+    proxy: Proxy,
+    access: Option<Access>,
+    signed_urls: Option<Arc<SignedUrls>>,
+}
+
+impl FromRef<AppState> for Arc<Wheel> {
+    fn from_ref(state: &AppState) -> Self {
+        state.wheel.clone()
+    }
+}
+
+impl FromRef<AppState> for Proxy {
+    fn from_ref(state: &AppState) -> Self {
+        state.proxy.clone()
+    }
+}
+
+impl FromRef<AppState> for Option<Access> {
+    fn from_ref(state: &AppState) -> Self {
+        state.access.clone()
+    }
+}
+
+impl FromRef<AppState> for Option<Arc<SignedUrls>> {
+    fn from_ref(state: &AppState) -> Self {
+        state.signed_urls.clone()
+    }
+}
+
+/// Serve [router] on `addr` until the process exits.
+pub async fn serve(wheel: Arc<Wheel>, addr: impl ToSocketAddrs) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    axum::serve(listener, router(wheel)).await
+}
+
+async fn tree(
+    State(wheel): State<Arc<Wheel>>,
+    State(access): State<Option<Access>>,
+    headers: HeaderMap,
+) -> Response {
+    if let Some(access) = access {
+        return match access.identify(&headers).await {
+            Ok(identity) => (
+                [(header::CONTENT_TYPE, "application/json")],
+                access.control.tree(&identity),
+            )
+                .into_response(),
+            Err(response) => response,
+        };
+    }
+    let chunks = wheel.tree_chunks().map(Ok::<_, Infallible>);
+    let body = Body::from_stream(chunks);
+    ([(header::CONTENT_TYPE, "application/json")], body).into_response()
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+    #[serde(default)]
+    path: String,
+}
+
+async fn list(
+    State(wheel): State<Arc<Wheel>>,
+    State(access): State<Option<Access>>,
+    Query(query): Query<ListQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let path = match query.path.trim_matches('/') {
+        "" => wheel.listing.read().name().to_string(),
+        path => path.to_string(),
+    };
+    let password = password(&headers);
+    let lookup = match access {
+        Some(access) => match access.identify(&headers).await {
+            Ok(identity) => {
+                access
+                    .control
+                    .dir(&identity, &path, password.as_deref())
+                    .await
+            }
+            Err(response) => return response,
+        },
+        None => wheel.list(&path, password.as_deref()).await,
+    };
+    respond(lookup, |dir| Json(dir).into_response())
+}
+
+async fn search(
+    State(wheel): State<Arc<Wheel>>,
+    State(access): State<Option<Access>>,
+    Query(query): Query<search::Query>,
+    headers: HeaderMap,
+) -> Response {
+    let results = match access {
+        Some(access) => match access.identify(&headers).await {
+            Ok(identity) => access.control.search(&identity, &query),
+            Err(response) => return response,
+        },
+        None => wheel.search(&query),
+    };
+    match results {
+        Ok(results) => Json(results).into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, e).into_response(),
+    }
+}
+
+async fn download(
+    State(wheel): State<Arc<Wheel>>,
+    State(proxy): State<Proxy>,
+    State(access): State<Option<Access>>,
+    Path(path): Path<String>,
+    method: Method,
+    headers: HeaderMap,
+) -> Response {
+    let password = password(&headers);
+    let lookup = match access {
+        Some(access) => match access.identify(&headers).await {
+            Ok(identity) => {
+                access
+                    .control
+                    .file(&identity, &path, password.as_deref())
+                    .await
+            }
+            Err(response) => return response,
+        },
+        None => wheel.lookup(&path, password.as_deref()).await,
+    };
+    match lookup {
+        Lookup::Found(file) if file.proxy => proxy.serve(&file, &method, &headers).await,
+        lookup => respond(lookup, |file| proxy.redirect(&file)),
+    }
+}
+
+async fn signed(
+    State(proxy): State<Proxy>,
+    State(signed_urls): State<Option<Arc<SignedUrls>>>,
+    Path(path): Path<String>,
+    RawQuery(query): RawQuery,
+    method: Method,
+    headers: HeaderMap,
+) -> Response {
+    // only routed with signed URLs
+    let Some(signed_urls) = signed_urls else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let url = format!("{}?{}", path, query.unwrap_or_default());
+    let file = match signed_urls.file(&url, None) {
+        Ok(file) => file,
+        Err(e) => {
+            let status = match e {
+                SignedUrlError::Invalid => StatusCode::FORBIDDEN,
+                SignedUrlError::Expired | SignedUrlError::Revoked => StatusCode::GONE,
+                SignedUrlError::NotFound => StatusCode::NOT_FOUND,
+            };
+            return (status, e.to_string()).into_response();
+        }
+    };
+    match file.proxy {
+        true => proxy.serve(&file, &method, &headers).await,
+        false => proxy.redirect(&file),
+    }
+}
+
+impl Access {
+    /// The caller of the bearer token in `headers`, anonymous without one, or else the
+    /// `401 Unauthorized` to answer.
+    async fn identify(&self, headers: &HeaderMap) -> Result<Identity, Response> {
+        let token = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.strip_prefix("Bearer "));
+        let Some(token) = token else {
+            return Ok(Identity::anonymous());
+        };
+        self.identities
+            .identify(token)
+            .await
+            .ok_or_else(|| StatusCode::UNAUTHORIZED.into_response())
+    }
+}
+
+/// The password in the [PASSWORD_HEADER] header, or else in basic authentication, whose user
+/// name is ignored.
+pub(crate) fn password(headers: &HeaderMap) -> Option<String> {
+    if let Some(password) = headers.get(PASSWORD_HEADER) {
+        return password.to_str().ok().map(|x| x.to_string());
+    }
+    let authorization = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let credentials = STANDARD
+        .decode(authorization.strip_prefix("Basic ")?)
+        .ok()?;
+    let credentials = String::from_utf8(credentials).ok()?;
+    let (_, password) = credentials.split_once(':')?;
+    Some(password.to_string())
+}
+
+fn respond<T>(lookup: Lookup<T>, found: impl FnOnce(T) -> Response) -> Response {
+    match lookup {
+        Lookup::Found(x) => found(x),
+        Lookup::NotFound => StatusCode::NOT_FOUND.into_response(),
+        Lookup::PasswordRequired { protected } => {
+            let body = serde_json::json!({ "password_required": protected });
+            (StatusCode::UNAUTHORIZED, Json(body)).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acl::{Acl, StaticIdentities};
+    use crate::driver::CloudDriver;
+    use crate::static_driver::StaticDriver;
+    use axum::body::to_bytes;
+    use axum::http::Request;
+    use std::time::Duration;
+    use tower::ServiceExt;
+
+    const CONFIG: &str = r#"
+    {
+        "name": "root",
+        "size": 2048,
+        "last_modified": "2021-01-01T00:00:00Z",
+        "files": [
+            {
+                "name": "readme",
+                "size": 1024,
+                "last_modified": "2021-01-01T00:00:00Z",
+                "links": ["https://example.com/readme"]
+            }
+        ],
+        "subdirectories": [
+            {
+                "name": "sub dir",
+                "size": 1024,
+                "last_modified": "2021-01-01T00:00:00Z",
+                "files": [
+                    {
+                        "name": "file",
+                        "size": 1024,
+                        "last_modified": "2021-01-01T00:00:00Z",
+                        "links": ["https://example.com/file"]
+                    }
+                ],
+                "subdirectories": []
+            }
+        ]
+    }
+    "#;
+
+    async fn wheel() -> Arc<Wheel> {
+        let driver = StaticDriver::new(serde_json::from_str(CONFIG).unwrap()).await;
+        Wheel::new(vec![Box::new(driver)]).await
+    }
+
+    async fn get(wheel: &Arc<Wheel>, uri: &str, password: Option<&str>) -> (StatusCode, Response) {
+        let mut request = Request::builder().uri(uri);
+        if let Some(password) = password {
+            request = request.header(PASSWORD_HEADER, password);
+        }
+        let request = request.body(Body::empty()).unwrap();
+        let response = router(wheel.clone()).oneshot(request).await.unwrap();
+        (response.status(), response)
+    }
+
+    async fn body(response: Response) -> serde_json::Value {
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_tree() {
+        let wheel = wheel().await;
+        let (status, response) = get(&wheel, "/api/tree", None).await;
+        assert_eq!(status, StatusCode::OK);
+        let tree = body(response).await;
+        assert_eq!(tree["name"], "root");
+        assert_eq!(tree["subdirectories"][0]["name"], "sub dir");
+    }
+
+    #[tokio::test]
+    async fn test_list() {
+        let wheel = wheel().await;
+        let (status, response) = get(&wheel, "/api/list", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body(response).await["files"][0]["name"], "readme");
+
+        let (status, response) = get(&wheel, "/api/list?path=root/sub%20dir", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body(response).await["files"][0]["name"], "file");
+
+        let (status, _) = get(&wheel, "/api/list?path=root/missing", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_search() {
+        let wheel = wheel().await;
+        let (status, response) = get(&wheel, "/api/search?text=fl&mode=fuzzy", None).await;
+        assert_eq!(status, StatusCode::OK);
+        let results = body(response).await;
+        assert_eq!(results["total"], 1);
+        assert_eq!(results["hits"][0]["path"], "root/sub dir/file");
+        assert_eq!(results["hits"][0]["size"], 1024);
+
+        let (status, response) = get(&wheel, "/api/search?min_size=2048", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body(response).await["total"], 0);
+
+        let (status, _) = get(&wheel, "/api/search?text=%5B&mode=glob", None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_download() {
+        let wheel = wheel().await;
+        let (status, response) = get(&wheel, "/d/root/sub%20dir/file", None).await;
+        assert_eq!(status, StatusCode::FOUND);
+        assert_eq!(
+            response.headers()[header::LOCATION],
+            "https://example.com/file"
+        );
+
+        let (status, _) = get(&wheel, "/d/root/missing", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_health() {
+        let wheel = wheel().await;
+        let health = Arc::new(HealthChecker::new(wheel.clone()));
+        let router = router_with_health(wheel, health);
+        let request = Request::builder()
+            .uri("/d/root/readme")
+            .body(Body::empty())
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FOUND);
+        let request = Request::builder()
+            .uri("/api/health")
+            .body(Body::empty())
+            .unwrap();
+        let response = router.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn test_metrics() {
+        let wheel = wheel().await;
+        get(&wheel, "/d/root/sub%20dir/file", None).await;
+        let (status, response) = get(&wheel, "/metrics", None).await;
+        assert_eq!(status, StatusCode::OK);
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let text = String::from_utf8(bytes.to_vec()).unwrap();
+        let lines = text.lines().collect::<Vec<_>>();
+        assert!(lines.contains(&"rlist_driver_get_vfs_total{driver=\"driver-0\",result=\"ok\"} 1"));
+        assert!(lines.contains(&"rlist_link_selected_total{origin=\"https://example.com\"} 1"));
+        assert!(text.contains("# TYPE rlist_rcu_swap_seconds histogram"));
+    }
+
+    async fn get_with(router: &Router, uri: &str, token: Option<&str>) -> Response {
+        let mut request = Request::builder().uri(uri);
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let request = request.body(Body::empty()).unwrap();
+        router.clone().oneshot(request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_access_control() {
+        let wheel = wheel().await;
+        let acl: Acl = serde_json::from_str(r#"["/sub dir/** -> group:staff"]"#).unwrap();
+        let access = Arc::new(AccessControl::new(wheel.clone(), Arc::new(acl)));
+        let identities: StaticIdentities =
+            serde_json::from_str(r#"{"token": {"user": "alice", "groups": ["staff"]}}"#).unwrap();
+        let router = RouterBuilder::new(wheel)
+            .with_access_control(access, Arc::new(identities))
+            .build();
+
+        let response = get_with(&router, "/api/tree", None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let tree = body(response).await;
+        assert_eq!(tree["size"], 1024);
+        assert_eq!(tree["subdirectories"].as_array().unwrap().len(), 0);
+        let tree = body(get_with(&router, "/api/tree", Some("token")).await).await;
+        assert_eq!(tree["subdirectories"][0]["name"], "sub dir");
+
+        let uri = "/api/list?path=root/sub%20dir";
+        let response = get_with(&router, uri, None).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = get_with(&router, uri, Some("token")).await;
+        assert_eq!(body(response).await["files"][0]["name"], "file");
+        let root = body(get_with(&router, "/api/list", None).await).await;
+        assert_eq!(root["subdirectories"].as_array().unwrap().len(), 0);
+
+        let response = get_with(&router, "/api/search?text=file", None).await;
+        assert_eq!(body(response).await["total"], 0);
+        let response = get_with(&router, "/api/search?text=file", Some("token")).await;
+        assert_eq!(body(response).await["total"], 1);
+
+        let uri = "/d/root/sub%20dir/file";
+        let response = get_with(&router, uri, None).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = get_with(&router, uri, Some("token")).await;
+        assert_eq!(response.status(), StatusCode::FOUND);
+        let response = get_with(&router, uri, Some("unknown")).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // WebDAV would bypass the policy
+        let response = get_with(&router, "/dav/sub%20dir/file", Some("token")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_signed_urls() {
+        let wheel = wheel().await;
+        let signed_urls = Arc::new(SignedUrls::new(wheel.clone(), "key"));
+        let router = RouterBuilder::new(wheel.clone())
+            .with_signed_urls(signed_urls.clone())
+            .build();
+        let url = signed_urls.sign("root/sub dir/file", Duration::from_secs(60), None);
+        let uri = format!("/s/{}", url.replace(' ', "%20"));
+
+        let response = get_with(&router, &uri, None).await;
+        assert_eq!(response.status(), StatusCode::FOUND);
+        assert_eq!(
+            response.headers()[header::LOCATION],
+            "https://example.com/file"
+        );
+        let forged = uri.replace("sub%20dir/file", "readme");
+        let response = get_with(&router, &forged, None).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        signed_urls.revoke(&url, None).await.unwrap();
+        let response = get_with(&router, &uri, None).await;
+        assert_eq!(response.status(), StatusCode::GONE);
+
+        // not routed without signed URLs
+        let response = get_with(&super::router(wheel), &uri, None).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_password_required() {
+        let wheel = wheel().await;
+        let hash = bcrypt::hash("secret", 4).unwrap();
+        let passwords = serde_json::from_str(&format!(r#"{{"/sub dir": "{}"}}"#, hash)).unwrap();
+        wheel.set_passwords(passwords).await;
+
+        let (status, response) = get(&wheel, "/d/root/sub%20dir/file", None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body(response).await["password_required"], "/sub dir");
+        let (status, _) = get(&wheel, "/d/root/sub%20dir/file", Some("secret")).await;
+        assert_eq!(status, StatusCode::FOUND);
+
+        let (status, _) = get(&wheel, "/api/list?path=root/sub%20dir", None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = get(&wheel, "/api/list?path=root/sub%20dir", Some("secret")).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+}
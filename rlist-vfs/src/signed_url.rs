@@ -0,0 +1,388 @@
+use crate::rcu::ReadCopyUpdate;
+use crate::snapshot;
+use crate::static_combinable::StaticCombinableFile;
+use crate::{VfsFileMeta, Wheel};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Why a signed URL was not resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignedUrlError {
+    /// Malformed, signed with another key, or bound to another client.
+    Invalid,
+    Expired,
+    Revoked,
+    /// The signature is valid, but there is no such file anymore, or it has no link.
+    NotFound,
+}
+
+impl fmt::Display for SignedUrlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            SignedUrlError::Invalid => "invalid signature",
+            SignedUrlError::Expired => "link expired",
+            SignedUrlError::Revoked => "link revoked",
+            SignedUrlError::NotFound => "file not found",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+/// Download URLs minted by rlist, like `root/a.iso?expires=1700000000&sign=<hex>`, that only
+/// resolve to a link of the file, see [VfsFileMeta::on_download], once their HMAC-SHA256
+/// signature is verified.
+///
+/// A URL can be bound to a client, like its IP address, and then only resolves for the same
+/// client. The client is part of the signature but not of the URL. Changing the key revokes
+/// every URL at once, [SignedUrls::revoke] revokes a single one.
+///
+/// The signature is the only check, so mint URLs only for callers that may download the file,
+/// see [AccessControl](crate::acl::AccessControl) and [Wheel::lookup].
+pub struct SignedUrls {
+    wheel: Arc<Wheel>,
+    key: Vec<u8>,
+    /// Signatures of the revoked URLs, with their expiry.
+    revoked: ReadCopyUpdate<HashMap<Vec<u8>, u64>>,
+    /// Where `revoked` is saved after every revocation, see [SignedUrls::with_revocations].
+    ///
+    /// Held while saving, so revocations are saved in the order they are made.
+    revocations: Mutex<Option<PathBuf>>,
+}
+
+impl SignedUrls {
+    pub fn new(wheel: Arc<Wheel>, key: impl Into<Vec<u8>>) -> Self {
+        Self {
+            wheel,
+            key: key.into(),
+            revoked: ReadCopyUpdate::default(),
+            revocations: Mutex::new(None),
+        }
+    }
+
+    /// Keep the revoked URLs in the file at `path`, like next to the snapshot of the [Wheel],
+    /// so they stay revoked after a restart.
+    ///
+    /// The revocations already in the file are loaded, a missing file has none.
+    pub async fn with_revocations(self, path: impl Into<PathBuf>) -> Result<Self, String> {
+        let path = path.into();
+        self.revoked.update(load_revocations(&path).await?);
+        *self.revocations.lock().await = Some(path);
+        Ok(self)
+    }
+
+    /// A URL for `path`, a key of [Wheel::path_map], valid for `ttl`.
+    pub fn sign(&self, path: &str, ttl: Duration, client: Option<&str>) -> String {
+        self.sign_until(path, now() + ttl.as_secs(), client)
+    }
+
+    /// A URL for `path` valid until `expires`, in seconds since the Unix epoch.
+    pub fn sign_until(&self, path: &str, expires: u64, client: Option<&str>) -> String {
+        let signature = self.mac(path, expires, client).finalize().into_bytes();
+        format!("{}?expires={}&sign={}", path, expires, to_hex(&signature))
+    }
+
+    /// Verify `url` and pick a download link of its file.
+    pub fn resolve(&self, url: &str, client: Option<&str>) -> Result<String, SignedUrlError> {
+        let link = self.file(url, client)?.on_download();
+        match link {
+            Some(link) => {
+                #[cfg(feature = "metrics")]
+                self.wheel.metrics().link_selected(&link);
+                Ok(link)
+            }
+            None => Err(SignedUrlError::NotFound),
+        }
+    }
+
+    /// Verify `url` and look its file up, for files that are proxied instead of redirected to.
+    pub fn file(
+        &self,
+        url: &str,
+        client: Option<&str>,
+    ) -> Result<StaticCombinableFile, SignedUrlError> {
+        let (path, expires, signature) = self.verify(url, client)?;
+        if expires <= now() {
+            return Err(SignedUrlError::Expired);
+        }
+        if self.revoked.read().contains_key(&signature) {
+            return Err(SignedUrlError::Revoked);
+        }
+        let file = self.wheel.path_map.read().get(path).cloned();
+        file.ok_or(SignedUrlError::NotFound)
+    }
+
+    /// Stop `url`, signed for `client`, from resolving before it expires.
+    ///
+    /// Only URLs signed with the key can be revoked. Revoked URLs are kept until they expire,
+    /// and forgotten on the next revocation after.
+    ///
+    /// Revocations are only kept in memory, and forgotten on restart, unless they are saved
+    /// with [SignedUrls::with_revocations]. A revocation that cannot be saved is logged and
+    /// still applies until then.
+    pub async fn revoke(&self, url: &str, client: Option<&str>) -> Result<(), SignedUrlError> {
+        let (_, expires, signature) = self.verify(url, client)?;
+        let revocations = self.revocations.lock().await;
+        let now = now();
+        self.revoked.rcu(|revoked| {
+            let mut revoked = revoked.clone();
+            revoked.retain(|_, expires| *expires > now);
+            if expires > now {
+                revoked.insert(signature.clone(), expires);
+            }
+            revoked
+        });
+        if let Some(path) = &*revocations {
+            if let Err(error) = save_revocations(path, &self.revoked.read()).await {
+                tracing::warn!(%error, "cannot save revocations");
+            }
+        }
+        Ok(())
+    }
+
+    /// Split `url` into its path, expiry and signature, once the signature is verified.
+    fn verify<'a>(
+        &self,
+        url: &'a str,
+        client: Option<&str>,
+    ) -> Result<(&'a str, u64, Vec<u8>), SignedUrlError> {
+        let (path, expires, signature) = parse(url).ok_or(SignedUrlError::Invalid)?;
+        let signature = from_hex(signature).ok_or(SignedUrlError::Invalid)?;
+        self.mac(path, expires, client)
+            .verify_slice(&signature)
+            .map_err(|_| SignedUrlError::Invalid)?;
+        Ok((path, expires, signature))
+    }
+
+    fn mac(&self, path: &str, expires: u64, client: Option<&str>) -> HmacSha256 {
+        // HMAC accepts keys of any length
+        let mut mac = HmacSha256::new_from_slice(&self.key).unwrap();
+        // every field is length prefixed, so fields cannot run into each other, and a URL
+        // bound to no client is tagged apart from one bound to an empty client
+        update_field(&mut mac, path.as_bytes());
+        update_field(&mut mac, &expires.to_be_bytes());
+        match client {
+            None => mac.update(&[0]),
+            Some(client) => {
+                mac.update(&[1]);
+                update_field(&mut mac, client.as_bytes());
+            }
+        }
+        mac
+    }
+}
+
+/// The revocations saved at `path` that did not expire, keyed by signature in hex.
+async fn load_revocations(path: &Path) -> Result<HashMap<Vec<u8>, u64>, String> {
+    let json = match tokio::fs::read(path).await {
+        Ok(json) => json,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(format!("cannot read revocations {}: {}", path.display(), e)),
+    };
+    let saved: HashMap<String, u64> =
+        serde_json::from_slice(&json).map_err(|e| format!("invalid revocations: {}", e))?;
+    let now = now();
+    saved
+        .into_iter()
+        .filter(|(_, expires)| *expires > now)
+        .map(|(signature, expires)| match from_hex(&signature) {
+            Some(signature) => Ok((signature, expires)),
+            None => Err(format!("invalid revoked signature {}", signature)),
+        })
+        .collect()
+}
+
+async fn save_revocations(path: &Path, revoked: &HashMap<Vec<u8>, u64>) -> Result<(), String> {
+    let saved = revoked
+        .iter()
+        .map(|(signature, expires)| (to_hex(signature), *expires))
+        .collect::<HashMap<_, _>>();
+    let json = serde_json::to_vec(&saved).map_err(|e| e.to_string())?;
+    snapshot::write_atomic(path, &json).await
+}
+
+fn update_field(mac: &mut HmacSha256, field: &[u8]) {
+    mac.update(&(field.len() as u64).to_be_bytes());
+    mac.update(field);
+}
+
+/// Seconds since the Unix epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Split `url` into its path, expiry and signature.
+fn parse(url: &str) -> Option<(&str, u64, &str)> {
+    // file names may contain `?`, the query may not
+    let (path, query) = url.rsplit_once('?')?;
+    let mut expires = None;
+    let mut signature = None;
+    for pair in query.split('&') {
+        match pair.split_once('=')? {
+            ("expires", x) => expires = Some(x.parse().ok()?),
+            ("sign", x) => signature = Some(x),
+            _ => {}
+        }
+    }
+    Some((path, expires?, signature?))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+/// The bytes of lowercase `hex`, as written by [to_hex], so every signature has a single
+/// spelling.
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    let lowercase = hex.bytes().all(|x| matches!(x, b'0'..=b'9' | b'a'..=b'f'));
+    if !hex.len().is_multiple_of(2) || !lowercase {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::static_combinable::TestDriver;
+
+    async fn signed_urls(key: &str) -> SignedUrls {
+        SignedUrls::new(
+            Wheel::new(vec![Box::new(TestDriver::file("a?.iso"))]).await,
+            key,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_sign_and_resolve() {
+        let urls = signed_urls("key").await;
+        let url = urls.sign("root/a?.iso", Duration::from_secs(60), None);
+        assert!(url.starts_with("root/a?.iso?expires="));
+        assert_eq!(
+            urls.resolve(&url, None).unwrap(),
+            "https://example.com/a?.iso"
+        );
+
+        let missing = urls.sign("root/missing", Duration::from_secs(60), None);
+        assert_eq!(urls.resolve(&missing, None), Err(SignedUrlError::NotFound));
+        let expired = urls.sign_until("root/a?.iso", 1, None);
+        assert_eq!(urls.resolve(&expired, None), Err(SignedUrlError::Expired));
+    }
+
+    #[tokio::test]
+    async fn test_tampered() {
+        let urls = signed_urls("key").await;
+        let url = urls.sign("root/a?.iso", Duration::from_secs(60), None);
+        let (_, signature) = url.split_once("&sign=").unwrap();
+        let forged = format!("root/a?.iso?expires={}&sign={}", u64::MAX, signature);
+        assert_eq!(urls.resolve(&forged, None), Err(SignedUrlError::Invalid));
+        assert_eq!(
+            urls.resolve("root/a?.iso", None),
+            Err(SignedUrlError::Invalid)
+        );
+
+        let other_key = signed_urls("other").await;
+        assert_eq!(other_key.resolve(&url, None), Err(SignedUrlError::Invalid));
+    }
+
+    #[tokio::test]
+    async fn test_client_binding() {
+        let urls = signed_urls("key").await;
+        let url = urls.sign("root/a?.iso", Duration::from_secs(60), Some("192.0.2.1"));
+        assert!(urls.resolve(&url, Some("192.0.2.1")).is_ok());
+        assert_eq!(
+            urls.resolve(&url, Some("192.0.2.2")),
+            Err(SignedUrlError::Invalid)
+        );
+        assert_eq!(urls.resolve(&url, None), Err(SignedUrlError::Invalid));
+
+        let unbound = urls.sign("root/a?.iso", Duration::from_secs(60), None);
+        assert_eq!(
+            urls.resolve(&unbound, Some("")),
+            Err(SignedUrlError::Invalid)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fields_do_not_run_into_each_other() {
+        let urls = signed_urls("key").await;
+        let a = urls.mac("p", 1, Some("2\n3")).finalize().into_bytes();
+        let b = urls.mac("p\n1", 2, Some("3")).finalize().into_bytes();
+        assert_ne!(a, b);
+        let none = urls.mac("p", 1, None).finalize().into_bytes();
+        let empty = urls.mac("p", 1, Some("")).finalize().into_bytes();
+        assert_ne!(none, empty);
+    }
+
+    #[tokio::test]
+    async fn test_revoke() {
+        let urls = signed_urls("key").await;
+        let revoked = urls.sign("root/a?.iso", Duration::from_secs(60), None);
+        let kept = urls.sign("root/a?.iso", Duration::from_secs(120), None);
+        urls.revoke(&revoked, None).await.unwrap();
+        assert_eq!(urls.resolve(&revoked, None), Err(SignedUrlError::Revoked));
+        assert!(urls.resolve(&kept, None).is_ok());
+
+        // the signature has a single spelling
+        let (head, signature) = revoked.split_once("&sign=").unwrap();
+        let uppercase = format!("{}&sign={}", head, signature.to_uppercase());
+        assert_eq!(urls.resolve(&uppercase, None), Err(SignedUrlError::Invalid));
+
+        // only URLs signed with the key are kept
+        let junk = format!("root/a?.iso?expires={}&sign=00", u64::MAX);
+        assert_eq!(urls.revoke(&junk, None).await, Err(SignedUrlError::Invalid));
+        assert_eq!(
+            urls.revoke(&uppercase, None).await,
+            Err(SignedUrlError::Invalid)
+        );
+        assert_eq!(urls.revoked.read().len(), 1);
+
+        urls.revoke(&urls.sign_until("root/a?.iso", 1, None), None)
+            .await
+            .unwrap();
+        assert_eq!(urls.revoked.read().len(), 1);
+        urls.revoked.update(HashMap::from([(vec![0], 1)]));
+        urls.revoke(&kept, None).await.unwrap();
+        let (_, signature) = kept.split_once("&sign=").unwrap();
+        let revoked = urls.revoked.read();
+        assert_eq!(revoked.len(), 1);
+        assert!(revoked.contains_key(&from_hex(signature).unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_revocations_survive_restart() {
+        let path = std::env::temp_dir().join(format!("rlist-revoked-{}.json", std::process::id()));
+        let _ = tokio::fs::remove_file(&path).await;
+        let urls = signed_urls("key")
+            .await
+            .with_revocations(&path)
+            .await
+            .unwrap();
+        let revoked = urls.sign("root/a?.iso", Duration::from_secs(60), None);
+        urls.revoke(&revoked, None).await.unwrap();
+
+        let restarted = signed_urls("key")
+            .await
+            .with_revocations(&path)
+            .await
+            .unwrap();
+        tokio::fs::remove_file(&path).await.unwrap();
+        assert_eq!(
+            restarted.resolve(&revoked, None),
+            Err(SignedUrlError::Revoked)
+        );
+    }
+}
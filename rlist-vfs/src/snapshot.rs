@@ -0,0 +1,214 @@
+use crate::combinable_dir::CombinableDir;
+use crate::static_combinable::StaticCombinableFile;
+use crate::static_driver::StaticDir;
+use crate::visibility::Visibility;
+use crate::{VfsBasicMeta, VfsDirMeta};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize, Serializer};
+use std::path::{Path, PathBuf};
+
+type Tree = CombinableDir<StaticCombinableFile>;
+
+/// Bump this whenever the on-disk layout changes, older snapshots are then ignored.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotFile {
+    version: u32,
+    saved_at: DateTime<Utc>,
+    root: StaticDir,
+}
+
+/// Only the version is read first, so a snapshot with a different layout fails with a clear
+/// message instead of a field error.
+#[derive(Deserialize)]
+struct SnapshotHeader {
+    version: u32,
+}
+
+/// Written like [SnapshotFile], but borrowing the tree.
+#[derive(Serialize)]
+struct SavedSnapshot<'a> {
+    version: u32,
+    saved_at: DateTime<Utc>,
+    root: SavedDir<'a>,
+}
+
+/// Serialized like [StaticDir], but borrowed from the tree, so saving does not copy it.
+#[derive(Serialize)]
+struct SavedDir<'a> {
+    name: &'a str,
+    size: u64,
+    last_modified: DateTime<Utc>,
+    #[serde(serialize_with = "saved_files")]
+    files: &'a [StaticCombinableFile],
+    #[serde(serialize_with = "saved_dirs")]
+    subdirectories: &'a [Tree],
+    #[serde(skip_serializing_if = "Visibility::is_public")]
+    visibility: Visibility,
+}
+
+/// Serialized like [StaticFile](crate::static_driver::StaticFile).
+#[derive(Serialize)]
+struct SavedFile<'a> {
+    name: &'a str,
+    size: u64,
+    last_modified: DateTime<Utc>,
+    links: &'a [String],
+    #[serde(skip_serializing_if = "Visibility::is_public")]
+    visibility: Visibility,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    proxy: bool,
+}
+
+impl<'a> From<&'a Tree> for SavedDir<'a> {
+    fn from(dir: &'a Tree) -> Self {
+        SavedDir {
+            name: dir.name(),
+            size: dir.size(),
+            last_modified: dir.last_modified().into(),
+            files: dir.files(),
+            subdirectories: dir.subdirectories(),
+            visibility: dir.visibility(),
+        }
+    }
+}
+
+impl<'a> From<&'a StaticCombinableFile> for SavedFile<'a> {
+    fn from(file: &'a StaticCombinableFile) -> Self {
+        SavedFile {
+            name: &file.name,
+            size: file.size,
+            last_modified: file.last_modified.into(),
+            links: &file.links,
+            visibility: file.visibility,
+            proxy: file.proxy,
+        }
+    }
+}
+
+fn saved_files<S: Serializer>(
+    files: &&[StaticCombinableFile],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(files.iter().map(SavedFile::from))
+}
+
+fn saved_dirs<S: Serializer>(dirs: &&[Tree], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(dirs.iter().map(SavedDir::from))
+}
+
+/// Write the combined tree to `path`.
+///
+/// The snapshot is written to a sibling temporary file and renamed into place, so a crash
+/// while saving never leaves a truncated snapshot behind.
+pub async fn save(path: &Path, root: &Tree) -> Result<(), String> {
+    let snapshot = SavedSnapshot {
+        version: SNAPSHOT_VERSION,
+        saved_at: Utc::now(),
+        root: root.into(),
+    };
+    let json = serde_json::to_vec(&snapshot).map_err(|e| e.to_string())?;
+    write_atomic(path, &json).await
+}
+
+/// Write `content` to a sibling temporary file of `path` and rename it into place, so a crash
+/// while writing never leaves a truncated file behind.
+pub(crate) async fn write_atomic(path: &Path, content: &[u8]) -> Result<(), String> {
+    let temp = temp_path(path);
+    tokio::fs::write(&temp, content)
+        .await
+        .map_err(|e| format!("cannot write {}: {}", temp.display(), e))?;
+    tokio::fs::rename(&temp, path)
+        .await
+        .map_err(|e| format!("cannot move {} into place: {}", path.display(), e))
+}
+
+/// Read the combined tree saved by [save].
+pub async fn load(path: &Path) -> Result<Tree, String> {
+    let json = tokio::fs::read(path)
+        .await
+        .map_err(|e| format!("cannot read snapshot {}: {}", path.display(), e))?;
+    let header: SnapshotHeader = serde_json::from_slice(&json).map_err(|e| e.to_string())?;
+    if header.version != SNAPSHOT_VERSION {
+        return Err(format!(
+            "snapshot version {} is not supported, expected {}",
+            header.version, SNAPSHOT_VERSION
+        ));
+    }
+    let snapshot: SnapshotFile = serde_json::from_slice(&json).map_err(|e| e.to_string())?;
+    Ok(snapshot.root.into())
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::static_combinable::StaticDownloadLinkFile;
+    use std::time::{Duration, SystemTime};
+
+    fn temp_snapshot(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rlist-{}-{}.json", name, std::process::id()))
+    }
+
+    fn generate_tree() -> CombinableDir<StaticCombinableFile> {
+        // 2023-1-1 00:00:00 UTC-0
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1672531200);
+        let file = StaticCombinableFile::new(
+            "file".to_string(),
+            1024,
+            time,
+            vec![
+                "https://example.com/file".to_string(),
+                "https://example.org/file".to_string(),
+            ],
+        );
+        let sub = CombinableDir::new("sub".to_string(), vec![file], vec![]);
+        CombinableDir::new("root".to_string(), vec![], vec![sub])
+    }
+
+    #[tokio::test]
+    async fn test_save_and_load() {
+        let path = temp_snapshot("save-and-load");
+        save(&path, &generate_tree()).await.unwrap();
+        let loaded = load(&path).await.unwrap();
+        tokio::fs::remove_file(&path).await.unwrap();
+
+        assert_eq!(loaded.name(), "root");
+        assert_eq!(loaded.size(), 1024);
+        let sub = &loaded.subdirectories()[0];
+        assert_eq!(sub.name(), "sub");
+        assert_eq!(
+            sub.files()[0].links,
+            vec!["https://example.com/file", "https://example.org/file"]
+        );
+        assert_eq!(
+            sub.files()[0].last_modified,
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1672531200)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reject_other_version() {
+        let path = temp_snapshot("other-version");
+        tokio::fs::write(&path, r#"{"version":0,"root":{}}"#)
+            .await
+            .unwrap();
+        let result = load(&path).await;
+        tokio::fs::remove_file(&path).await.unwrap();
+        assert_eq!(
+            result.err().unwrap(),
+            "snapshot version 0 is not supported, expected 1"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_load_missing() {
+        assert!(load(&temp_snapshot("missing")).await.is_err());
+    }
+}
@@ -1,6 +1,14 @@
 use crate::combinable::Combinable;
-use crate::{VfsBasicMeta, VfsFileMeta};
-use rand::{thread_rng, Rng};
+#[cfg(test)]
+use crate::combinable_dir::CombinableDir;
+#[cfg(test)]
+use crate::driver::GetVfs;
+use crate::visibility::Visibility;
+use crate::{DriverId, VfsBasicMeta, VfsFileMeta};
+use rand::seq::SliceRandom;
+use rand::thread_rng;
+#[cfg(test)]
+use std::collections::HashMap;
 use std::time::SystemTime;
 
 /// The download link can be determined **when instance is created**.
@@ -16,24 +24,67 @@ pub trait StaticDownloadLinkFile: VfsBasicMeta {
     /// 3. last_modified
     /// 4. links
     fn destruct(self) -> (String, u64, SystemTime, Vec<String>);
+
+    /// Files are public unless the implementation stores a visibility.
+    fn visibility(&self) -> Visibility {
+        Visibility::Public
+    }
+
+    fn with_visibility(self, _visibility: Visibility) -> Self {
+        self
+    }
+
+    /// Whether downloads are streamed through rlist instead of redirected to a link.
+    ///
+    /// Files are redirected unless the implementation stores this.
+    fn proxy(&self) -> bool {
+        false
+    }
+
+    fn with_proxy(self, _proxy: bool) -> Self {
+        self
+    }
+
+    /// The driver each of the [links](StaticDownloadLinkFile::links) came from, in the same
+    /// order, set by [Wheel](crate::Wheel) before combining.
+    ///
+    /// Empty if unknown, or if the implementation does not store it.
+    fn sources(&self) -> &[DriverId] {
+        &[]
+    }
+
+    fn with_sources(self, _sources: Vec<DriverId>) -> Self {
+        self
+    }
 }
 
 impl<T: StaticDownloadLinkFile> Combinable for T {
     /// Combine **same** files which have different download links to one file.
+    ///
+    /// The combined file has the most restricted visibility of `from`, and is proxied if any
+    /// of `from` is, since some of its links may only be reachable through rlist. Its sources
+    /// are only known if they are known for every link.
     fn combine(from: Vec<Self>) -> Self {
+        let visibility = from
+            .iter()
+            .map(|x| x.visibility())
+            .max()
+            .unwrap_or_default();
+        let proxy = from.iter().any(|x| x.proxy());
+        let sources = match from.iter().all(|x| x.sources().len() == x.links().len()) {
+            true => from.iter().flat_map(|x| x.sources().to_vec()).collect(),
+            false => vec![],
+        };
         let destructed: Vec<(String, u64, SystemTime, Vec<String>)> =
             from.into_iter().map(|x| x.destruct()).collect::<Vec<_>>();
         let new_name = destructed[0].0.clone();
         let new_size = destructed.iter().map(|x| x.1).max().unwrap();
         let new_last_modified = destructed.iter().map(|x| x.2).max().unwrap();
-        let download_links: Vec<String> =
-            destructed.iter().map(|x| x.3.clone()).flatten().collect();
-        return Self::new(
-            new_name,
-            new_size,
-            new_last_modified.clone(),
-            download_links,
-        );
+        let download_links: Vec<String> = destructed.iter().flat_map(|x| x.3.clone()).collect();
+        Self::new(new_name, new_size, new_last_modified, download_links)
+            .with_visibility(visibility)
+            .with_proxy(proxy)
+            .with_sources(sources)
     }
 }
 
@@ -42,26 +93,32 @@ where
     T: StaticDownloadLinkFile,
 {
     /// return a random link in list
-    fn on_download(&self) -> String {
-        let links = self.links();
-        let index = thread_rng().gen_range(0..links.len());
-        return links[index].clone();
+    fn on_download(&self) -> Option<String> {
+        self.links().choose(&mut thread_rng()).cloned()
     }
 }
 
 #[derive(Clone)]
 /// minimal implementation of `StaticDownloadLinkFile`
+///
+/// Built with [StaticDownloadLinkFile::new] and the `with_*` methods, so that new fields do
+/// not break callers.
+#[non_exhaustive]
 pub struct StaticCombinableFile {
     pub name: String,
     pub size: u64,
     pub last_modified: SystemTime,
     pub links: Vec<String>,
+    pub visibility: Visibility,
+    /// See [StaticDownloadLinkFile::proxy].
+    pub proxy: bool,
+    /// See [StaticDownloadLinkFile::sources].
+    pub sources: Vec<DriverId>,
 }
 
 impl StaticCombinableFile {
-    pub fn random_link(&self) -> String {
-        let index = thread_rng().gen_range(0..self.links.len());
-        return self.links[index].clone();
+    pub fn random_link(&self) -> Option<String> {
+        self.links.choose(&mut thread_rng()).cloned()
     }
 }
 
@@ -86,6 +143,9 @@ impl StaticDownloadLinkFile for StaticCombinableFile {
             size,
             last_modified,
             links,
+            visibility: Visibility::Public,
+            proxy: false,
+            sources: vec![],
         }
     }
 
@@ -96,6 +156,102 @@ impl StaticDownloadLinkFile for StaticCombinableFile {
     fn destruct(self) -> (String, u64, SystemTime, Vec<String>) {
         (self.name, self.size, self.last_modified, self.links)
     }
+
+    fn visibility(&self) -> Visibility {
+        self.visibility
+    }
+
+    fn with_visibility(mut self, visibility: Visibility) -> Self {
+        self.visibility = visibility;
+        self
+    }
+
+    fn proxy(&self) -> bool {
+        self.proxy
+    }
+
+    fn with_proxy(mut self, proxy: bool) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
+    fn sources(&self) -> &[DriverId] {
+        &self.sources
+    }
+
+    fn with_sources(mut self, sources: Vec<DriverId>) -> Self {
+        self.sources = sources;
+        self
+    }
+}
+
+/// A public file of `size` bytes modified at the Unix epoch, with a link to
+/// `https://example.com/<name>`, to build trees in tests with.
+#[cfg(test)]
+pub(crate) fn test_file(name: &str, size: u64) -> StaticCombinableFile {
+    let link = format!("https://example.com/{}", name);
+    StaticCombinableFile::new(name.to_string(), size, SystemTime::UNIX_EPOCH, vec![link])
+}
+
+/// A directory named `root` with `files`, the tree most drivers in tests report.
+#[cfg(test)]
+pub(crate) fn test_tree(files: Vec<StaticCombinableFile>) -> CombinableDir<StaticCombinableFile> {
+    CombinableDir::new("root".to_string(), files, vec![])
+}
+
+/// The paths of `files`, sorted.
+#[cfg(test)]
+pub(crate) fn test_paths<T>(files: &HashMap<String, T>) -> Vec<String> {
+    let mut paths = files.keys().cloned().collect::<Vec<_>>();
+    paths.sort();
+    paths
+}
+
+/// A driver that always reports the same tree, or the same error.
+#[cfg(test)]
+pub(crate) struct TestDriver {
+    tree: Result<CombinableDir<StaticCombinableFile>, String>,
+    proxy_headers: Vec<(String, String)>,
+}
+
+#[cfg(test)]
+impl TestDriver {
+    pub(crate) fn new(tree: CombinableDir<StaticCombinableFile>) -> Self {
+        Self {
+            tree: Ok(tree),
+            proxy_headers: vec![],
+        }
+    }
+
+    /// Reports a [test_tree] with the [test_file] `name`.
+    pub(crate) fn file(name: &str) -> Self {
+        Self::new(test_tree(vec![test_file(name, 1024)]))
+    }
+
+    pub(crate) fn failing(error: &str) -> Self {
+        Self {
+            tree: Err(error.to_string()),
+            proxy_headers: vec![],
+        }
+    }
+
+    #[cfg(feature = "server")]
+    pub(crate) fn with_proxy_headers(mut self, proxy_headers: Vec<(String, String)>) -> Self {
+        self.proxy_headers = proxy_headers;
+        self
+    }
+}
+
+#[cfg(test)]
+#[async_trait::async_trait]
+impl GetVfs for TestDriver {
+    async fn get_vfs(&self) -> Result<CombinableDir<StaticCombinableFile>, String> {
+        self.tree.clone()
+    }
+
+    fn proxy_headers(&self) -> &[(String, String)] {
+        &self.proxy_headers
+    }
 }
 
 #[cfg(test)]
@@ -107,15 +263,15 @@ mod tests {
     fn test_static_combinable_file() {
         // last modified: 2023-1-1 00:00:00 UTC-0
         let time = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1672531200);
-        let file = StaticCombinableFile {
-            name: "test".to_string(),
-            size: 1024,
-            last_modified: time,
-            links: vec![
+        let file = StaticCombinableFile::new(
+            "test".to_string(),
+            1024,
+            time,
+            vec![
                 "https://example.com".to_string(),
                 "https://example.org".to_string(),
             ],
-        };
+        );
         assert_eq!(file.name(), "test");
         assert_eq!(file.size(), 1024);
         assert_eq!(file.last_modified(), time);
@@ -135,26 +291,26 @@ mod tests {
         let name = "test".to_string();
         let size = 1024;
 
-        let file1 = StaticCombinableFile {
-            name: name.clone(),
+        let file1 = StaticCombinableFile::new(
+            name.clone(),
             size,
-            last_modified: time,
-            links: vec!["https://example.com".to_string()],
-        };
+            time,
+            vec!["https://example.com".to_string()],
+        );
 
-        let file2 = StaticCombinableFile {
-            name: name.clone(),
+        let file2 = StaticCombinableFile::new(
+            name.clone(),
             size,
-            last_modified: time,
-            links: vec!["https://example.org".to_string()],
-        };
+            time,
+            vec!["https://example.org".to_string()],
+        );
 
-        let file3 = StaticCombinableFile {
-            name: name.clone(),
+        let file3 = StaticCombinableFile::new(
+            name.clone(),
             size,
-            last_modified: time,
-            links: vec!["https://example.net".to_string()],
-        };
+            time,
+            vec!["https://example.net".to_string()],
+        );
 
         let combined = combine![file1, file2, file3];
         assert_eq!(combined.name(), "test");
@@ -169,4 +325,29 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn combine_sources() {
+        let file = |link: &str, sources: Vec<DriverId>| {
+            StaticCombinableFile::new(
+                "test".to_string(),
+                1024,
+                SystemTime::UNIX_EPOCH,
+                vec![link.to_string()],
+            )
+            .with_sources(sources)
+        };
+        let combined = combine![
+            file("https://example.com", vec![DriverId(0)]),
+            file("https://example.org", vec![DriverId(1)])
+        ];
+        assert_eq!(combined.sources(), &[DriverId(0), DriverId(1)]);
+
+        // not known for every link
+        let combined = combine![
+            file("https://example.com", vec![DriverId(0)]),
+            file("https://example.org", vec![])
+        ];
+        assert!(combined.sources().is_empty());
+    }
 }
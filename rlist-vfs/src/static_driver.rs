@@ -1,25 +1,32 @@
 use crate::combinable_dir::CombinableDir;
 use crate::driver::{CloudDriver, GetVfs};
-use crate::static_combinable::StaticCombinableFile;
+use crate::static_combinable::{StaticCombinableFile, StaticDownloadLinkFile};
+use crate::visibility::Visibility;
 use crate::VfsBasicMeta;
 use async_trait::async_trait;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct StaticFile {
     name: String,
     size: u64,
     last_modified: chrono::DateTime<chrono::Utc>,
     links: Vec<String>,
+    #[serde(default, skip_serializing_if = "Visibility::is_public")]
+    visibility: Visibility,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    proxy: bool,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct StaticDir {
     name: String,
     size: u64,
     last_modified: chrono::DateTime<chrono::Utc>,
     files: Vec<StaticFile>,
     subdirectories: Vec<StaticDir>,
+    #[serde(default, skip_serializing_if = "Visibility::is_public")]
+    visibility: Visibility,
 }
 
 impl VfsBasicMeta for StaticFile {
@@ -50,24 +57,51 @@ impl VfsBasicMeta for StaticDir {
     }
 }
 
-impl Into<StaticCombinableFile> for StaticFile {
-    fn into(self) -> StaticCombinableFile {
-        StaticCombinableFile {
-            name: self.name,
-            size: self.size,
-            last_modified: self.last_modified.into(),
-            links: self.links,
-        }
+impl From<StaticFile> for StaticCombinableFile {
+    fn from(file: StaticFile) -> Self {
+        StaticCombinableFile::new(file.name, file.size, file.last_modified.into(), file.links)
+            .with_visibility(file.visibility)
+            .with_proxy(file.proxy)
     }
 }
 
-impl Into<CombinableDir<StaticCombinableFile>> for StaticDir {
-    fn into(self) -> CombinableDir<StaticCombinableFile> {
+impl From<StaticDir> for CombinableDir<StaticCombinableFile> {
+    fn from(dir: StaticDir) -> Self {
         let subdirectories: Vec<CombinableDir<StaticCombinableFile>> =
-            self.subdirectories.into_iter().map(|x| x.into()).collect();
-        let files: Vec<StaticCombinableFile> = self.files.into_iter().map(|x| x.into()).collect();
-        let name = self.name;
-        CombinableDir::new(name, files, subdirectories)
+            dir.subdirectories.into_iter().map(|x| x.into()).collect();
+        let files: Vec<StaticCombinableFile> = dir.files.into_iter().map(|x| x.into()).collect();
+        let name = dir.name;
+        CombinableDir::new(name, files, subdirectories).with_visibility(dir.visibility)
+    }
+}
+
+impl From<StaticCombinableFile> for StaticFile {
+    fn from(file: StaticCombinableFile) -> Self {
+        StaticFile {
+            name: file.name,
+            size: file.size,
+            last_modified: file.last_modified.into(),
+            links: file.links,
+            visibility: file.visibility,
+            proxy: file.proxy,
+        }
+    }
+}
+
+impl From<CombinableDir<StaticCombinableFile>> for StaticDir {
+    fn from(dir: CombinableDir<StaticCombinableFile>) -> Self {
+        let size = dir.size();
+        let last_modified = dir.last_modified().into();
+        let visibility = dir.visibility();
+        let (name, files, subdirectories) = dir.destruct();
+        StaticDir {
+            name,
+            size,
+            last_modified,
+            files: files.into_iter().map(|x| x.into()).collect(),
+            subdirectories: subdirectories.into_iter().map(|x| x.into()).collect(),
+            visibility,
+        }
     }
 }
 
@@ -137,7 +171,8 @@ mod test {
                     "size": 1024,
                     "last_modified": "2021-01-01T00:00:00Z",
                     "files": [],
-                    "subdirectories": []
+                    "subdirectories": [],
+                    "visibility": "hidden"
                 }
             ]
         }
@@ -148,5 +183,7 @@ mod test {
         assert_eq!(dir.last_modified.to_rfc3339(), "2021-01-01T00:00:00+00:00");
         assert_eq!(dir.files.len(), 1);
         assert_eq!(dir.subdirectories.len(), 1);
+        assert_eq!(dir.files[0].visibility, super::Visibility::Public);
+        assert_eq!(dir.subdirectories[0].visibility, super::Visibility::Hidden);
     }
 }
@@ -0,0 +1,226 @@
+use crate::combinable_dir::CombinableDir;
+use crate::static_combinable::StaticCombinableFile;
+use crate::{DriverId, VfsBasicMeta, VfsDirMeta};
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap};
+
+type Tree = CombinableDir<StaticCombinableFile>;
+
+/// How many files [TreeStats::largest] keeps.
+pub const LARGEST: usize = 10;
+
+/// How many files and how many bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Usage {
+    pub files: u64,
+    pub size: u64,
+}
+
+impl Usage {
+    /// The files of `dir` and below, and their size.
+    pub fn of(dir: &Tree) -> Self {
+        let files = dir.files().len() as u64
+            + dir
+                .subdirectories()
+                .iter()
+                .map(|x| Usage::of(x).files)
+                .sum::<u64>();
+        Usage {
+            files,
+            size: dir.size(),
+        }
+    }
+
+    fn add(&mut self, size: u64) {
+        self.files += 1;
+        self.size += size;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LargeFile {
+    pub path: String,
+    pub size: u64,
+}
+
+/// Counts of a combined tree, for dashboards and capacity planning.
+///
+/// Every file counts, whatever its [Visibility](crate::visibility::Visibility).
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TreeStats {
+    /// Files and bytes, counting a file once however many links it has.
+    pub total: Usage,
+    /// Directories, the root included.
+    pub directories: u64,
+    /// The [LARGEST] largest files, the largest first.
+    pub largest: Vec<LargeFile>,
+    /// Files and bytes by lowercase extension, `""` for files without one.
+    pub extensions: BTreeMap<String, Usage>,
+    /// Files with more than one link, like the same file on several mirrors.
+    pub mirrored: Usage,
+    /// Bytes stored more than once: the size of every file times its links but one.
+    pub redundant_size: u64,
+    /// What each driver contributes before its tree is combined, by [DriverId] like
+    /// `driver-0`. Files several drivers provide count for each of them.
+    pub drivers: BTreeMap<String, Usage>,
+}
+
+impl TreeStats {
+    /// Count `combined`, with the [Usage] of each driver it was combined from.
+    pub fn new(combined: &Tree, drivers: &[(DriverId, Usage)]) -> Self {
+        let mut stats = Self::default();
+        let mut largest = BinaryHeap::new();
+        stats.count(combined.name(), combined, &mut largest);
+        stats.largest = largest
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse((size, path))| LargeFile { path, size })
+            .collect();
+        stats.drivers = drivers
+            .iter()
+            .map(|(id, usage)| (id.to_string(), *usage))
+            .collect();
+        stats
+    }
+
+    fn count(&mut self, path: &str, dir: &Tree, largest: &mut BinaryHeap<Reverse<(u64, String)>>) {
+        self.directories += 1;
+        for file in dir.files() {
+            self.total.add(file.size);
+            let extension = match file.name.rsplit_once('.') {
+                Some((_, extension)) => extension.to_lowercase(),
+                None => String::new(),
+            };
+            self.extensions.entry(extension).or_default().add(file.size);
+            if file.links.len() > 1 {
+                self.mirrored.add(file.size);
+                self.redundant_size += file.size * (file.links.len() as u64 - 1);
+            }
+            // a min-heap of the largest files so far
+            if largest.len() < LARGEST || largest.peek().is_some_and(|x| x.0 .0 < file.size) {
+                largest.push(Reverse((file.size, format!("{}/{}", path, file.name))));
+                if largest.len() > LARGEST {
+                    largest.pop();
+                }
+            }
+        }
+        for subdirectory in dir.subdirectories() {
+            let path = format!("{}/{}", path, subdirectory.name());
+            self.count(&path, subdirectory, largest);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::static_combinable::StaticDownloadLinkFile;
+    use std::time::SystemTime;
+
+    fn generate_file(name: &str, size: u64, links: usize) -> StaticCombinableFile {
+        StaticCombinableFile::new(
+            name.to_string(),
+            size,
+            SystemTime::UNIX_EPOCH,
+            (0..links)
+                .map(|i| format!("https://mirror-{}.example.com/{}", i, name))
+                .collect(),
+        )
+    }
+
+    // root
+    // ├── README
+    // └── pub
+    //     ├── a.iso (3 links)
+    //     ├── b.ISO
+    //     └── small
+    //         └── 0..12.txt
+    #[test]
+    fn test_stats() {
+        let small = CombinableDir::new(
+            "small".to_string(),
+            (0..12)
+                .map(|i| generate_file(&format!("{}.txt", i), i, 1))
+                .collect(),
+            vec![],
+        );
+        let public = CombinableDir::new(
+            "pub".to_string(),
+            vec![
+                generate_file("a.iso", 1000, 3),
+                generate_file("b.ISO", 500, 1),
+            ],
+            vec![small],
+        );
+        let readme = CombinableDir::new(
+            "root".to_string(),
+            vec![generate_file("README", 100, 1)],
+            vec![],
+        );
+        let root = CombinableDir::new("root".to_string(), vec![], vec![public.clone()]);
+        let combined = CombinableDir::new(
+            "root".to_string(),
+            vec![generate_file("README", 100, 1)],
+            vec![public],
+        );
+
+        let drivers = [
+            (DriverId(0), Usage::of(&root)),
+            (DriverId(1), Usage::of(&readme)),
+        ];
+        let stats = TreeStats::new(&combined, &drivers);
+        assert_eq!(
+            stats.total,
+            Usage {
+                files: 15,
+                size: 1666
+            }
+        );
+        assert_eq!(stats.directories, 3);
+        assert_eq!(stats.largest.len(), LARGEST);
+        assert_eq!(
+            stats.largest[..3],
+            [
+                LargeFile {
+                    path: "root/pub/a.iso".to_string(),
+                    size: 1000
+                },
+                LargeFile {
+                    path: "root/pub/b.ISO".to_string(),
+                    size: 500
+                },
+                LargeFile {
+                    path: "root/README".to_string(),
+                    size: 100
+                },
+            ]
+        );
+        assert_eq!(stats.largest[9].size, 5);
+        assert_eq!(
+            stats.extensions["iso"],
+            Usage {
+                files: 2,
+                size: 1500
+            }
+        );
+        assert_eq!(stats.extensions[""].files, 1);
+        assert_eq!(stats.extensions["txt"].files, 12);
+        assert_eq!(
+            stats.mirrored,
+            Usage {
+                files: 1,
+                size: 1000
+            }
+        );
+        assert_eq!(stats.redundant_size, 2000);
+        assert_eq!(stats.drivers["driver-0"].files, 14);
+        assert_eq!(
+            stats.drivers["driver-1"],
+            Usage {
+                files: 1,
+                size: 100
+            }
+        );
+    }
+}
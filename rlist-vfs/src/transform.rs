@@ -0,0 +1,436 @@
+use crate::combinable::Combinable;
+use crate::combinable_dir::CombinableDir;
+use crate::driver::GetVfs;
+use crate::filter::Filter;
+use crate::static_combinable::{StaticCombinableFile, StaticDownloadLinkFile};
+use crate::VfsBasicMeta;
+use async_trait::async_trait;
+use serde::Deserialize;
+
+type Tree = CombinableDir<StaticCombinableFile>;
+
+/// A change to the tree of a driver, applied before it is combined with the other drivers.
+///
+/// Paths are directory paths relative to the driver's root, like `/pub/releases`. In the
+/// config file a transform is written like `{"select": "/pub"}` or
+/// `{"rename": {"from": "/old", "to": "/new"}}`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Transform {
+    /// Use the content of this directory as the root, everything else is dropped.
+    Select(String),
+    /// Move a directory, merging it with what is already at `to`.
+    Rename { from: String, to: String },
+    /// Move the content of this directory to the root, merging it with the root.
+    StripPrefix(String),
+    /// Replace the subdirectories of this directory by their content.
+    Flatten(String),
+    /// Move the content of the root under this path, see [mount_content].
+    Mount(String),
+    /// Drop the entries matched by the filter, see [Filter].
+    Filter(Filter),
+    /// Stream every file through rlist, see [StaticDownloadLinkFile::proxy], written `"proxy"`.
+    Proxy,
+}
+
+impl Transform {
+    pub fn apply(&self, root: Tree) -> Result<Tree, String> {
+        match self {
+            Transform::Select(path) => {
+                let name = root.name().to_string();
+                let visibility = root.visibility();
+                let (_, selected) = take(root, &parse_path(path));
+                let (files, subdirectories) = selected
+                    .ok_or_else(|| format!("cannot select {}: no such directory", path))?
+                    .into_content();
+                Ok(CombinableDir::new(name, files, subdirectories).with_visibility(visibility))
+            }
+            Transform::Rename { from, to } => Ok(rename(root, &parse_path(from), &parse_path(to))),
+            Transform::StripPrefix(path) => Ok(rename(root, &parse_path(path), &[])),
+            Transform::Flatten(path) => {
+                let path = parse_path(path);
+                let (rest, taken) = take(root, &path);
+                let Some(taken) = taken else {
+                    return Ok(rest);
+                };
+                let name = rest.name().to_string();
+                let (files, subdirectories) = flatten(taken).into_content();
+                let flattened = CombinableDir::new(name, files, subdirectories);
+                Ok(CombinableDir::combine(vec![
+                    rest,
+                    mount_content(flattened, &path),
+                ]))
+            }
+            Transform::Mount(path) => Ok(mount_content(root, &parse_path(path))),
+            Transform::Filter(filter) => Ok(filter.apply(root)),
+            Transform::Proxy => Ok(proxy(root)),
+        }
+    }
+}
+
+/// Split a path like `/mirrors/eu` into its segments, empty segments are ignored.
+pub fn parse_path(path: &str) -> Vec<String> {
+    path.split('/')
+        .filter(|x| !x.is_empty())
+        .map(|x| x.to_string())
+        .collect()
+}
+
+/// Move the content of `root` under `path`, the root itself keeps its name.
+///
+/// Unlike [CombinableDir::mount], which moves the whole root, this keeps the tree combinable
+/// with the roots of other drivers.
+pub fn mount_content(root: Tree, path: &[String]) -> Tree {
+    let Some((leaf, parents)) = path.split_last() else {
+        return root;
+    };
+    let visibility = root.visibility();
+    let (name, files, subdirectories) = root.destruct();
+    let content = CombinableDir::new(leaf.clone(), files, subdirectories).mount(parents.to_vec());
+    CombinableDir::new(name, vec![], vec![content]).with_visibility(visibility)
+}
+
+/// Detach the directory at `path`, returns the rest of the tree and the detached directory.
+///
+/// An empty `path` detaches everything but the name of the root.
+fn take(dir: Tree, path: &[String]) -> (Tree, Option<Tree>) {
+    let visibility = dir.visibility();
+    let (name, files, subdirectories) = dir.destruct();
+    let Some((first, rest)) = path.split_first() else {
+        let taken = CombinableDir::new(name.clone(), files, subdirectories);
+        return (
+            CombinableDir::new(name, vec![], vec![]).with_visibility(visibility),
+            Some(taken.with_visibility(visibility)),
+        );
+    };
+    let mut taken = None;
+    let subdirectories = subdirectories
+        .into_iter()
+        .filter_map(|subdirectory| {
+            if taken.is_some() || subdirectory.name() != first {
+                return Some(subdirectory);
+            }
+            if rest.is_empty() {
+                taken = Some(subdirectory);
+                return None;
+            }
+            let (subdirectory, found) = take(subdirectory, rest);
+            taken = found;
+            Some(subdirectory)
+        })
+        .collect();
+    let rest = CombinableDir::new(name, files, subdirectories).with_visibility(visibility);
+    // the detached directory keeps the restrictions of the directories it was in
+    let taken = taken.map(|x| {
+        let restricted = x.visibility().max(visibility);
+        x.with_visibility(restricted)
+    });
+    (rest, taken)
+}
+
+fn rename(root: Tree, from: &[String], to: &[String]) -> Tree {
+    let (rest, taken) = take(root, from);
+    let Some(taken) = taken else {
+        return rest;
+    };
+    let (files, subdirectories) = taken.into_content();
+    let moved = CombinableDir::new(rest.name().to_string(), files, subdirectories);
+    CombinableDir::combine(vec![rest, mount_content(moved, to)])
+}
+
+/// Merge the content of every subdirectory into `dir`.
+fn flatten(dir: Tree) -> Tree {
+    let visibility = dir.visibility();
+    let (name, files, subdirectories) = dir.destruct();
+    let mut dirs = vec![CombinableDir::new(name.clone(), files, vec![])];
+    for subdirectory in subdirectories {
+        let (files, subdirectories) = subdirectory.into_content();
+        dirs.push(CombinableDir::new(name.clone(), files, subdirectories));
+    }
+    CombinableDir::combine(dirs).with_visibility(visibility)
+}
+
+/// Mark every file of `dir` as proxied.
+fn proxy(dir: Tree) -> Tree {
+    let visibility = dir.visibility();
+    let (name, files, subdirectories) = dir.destruct();
+    let files = files.into_iter().map(|x| x.with_proxy(true)).collect();
+    let subdirectories = subdirectories.into_iter().map(proxy).collect();
+    CombinableDir::new(name, files, subdirectories).with_visibility(visibility)
+}
+
+/// A driver whose tree goes through `transforms`, in order, before it is combined.
+pub struct Transformed {
+    driver: Box<dyn GetVfs>,
+    transforms: Vec<Transform>,
+    /// See [GetVfs::proxy_headers], those of `driver` if empty.
+    proxy_headers: Vec<(String, String)>,
+}
+
+impl Transformed {
+    pub fn new(driver: Box<dyn GetVfs>, transforms: Vec<Transform>) -> Self {
+        Self {
+            driver,
+            transforms,
+            proxy_headers: vec![],
+        }
+    }
+
+    pub fn with_proxy_headers(self, proxy_headers: Vec<(String, String)>) -> Self {
+        Self {
+            proxy_headers,
+            ..self
+        }
+    }
+}
+
+#[async_trait]
+impl GetVfs for Transformed {
+    async fn get_vfs(&self) -> Result<Tree, String> {
+        let mut tree = self.driver.get_vfs().await?;
+        for transform in &self.transforms {
+            tree = transform.apply(tree)?;
+        }
+        Ok(tree)
+    }
+
+    fn name(&self) -> &str {
+        self.driver.name()
+    }
+
+    fn proxy_headers(&self) -> &[(String, String)] {
+        match self.proxy_headers.is_empty() {
+            true => self.driver.proxy_headers(),
+            false => &self.proxy_headers,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::static_combinable::test_file;
+    use crate::visibility::{self, Visibility};
+
+    // root
+    // ├── file1
+    // ├── pub
+    // │   ├── file2
+    // │   └── 2023
+    // │       └── file3
+    // └── 2024
+    //     └── file4
+    fn generate_tree() -> Tree {
+        let dir_2023 =
+            CombinableDir::new("2023".to_string(), vec![test_file("file3", 1024)], vec![]);
+        let public = CombinableDir::new(
+            "pub".to_string(),
+            vec![test_file("file2", 1024)],
+            vec![dir_2023],
+        );
+        let dir_2024 =
+            CombinableDir::new("2024".to_string(), vec![test_file("file4", 1024)], vec![]);
+        CombinableDir::new(
+            "root".to_string(),
+            vec![test_file("file1", 1024)],
+            vec![public, dir_2024],
+        )
+    }
+
+    fn paths(tree: Tree) -> Vec<String> {
+        let mut paths = tree.compress_path().into_keys().collect::<Vec<_>>();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn test_parse_path() {
+        assert_eq!(parse_path("/mirrors/eu/"), vec!["mirrors", "eu"]);
+        assert_eq!(parse_path("mirrors//eu"), vec!["mirrors", "eu"]);
+        assert!(parse_path("/").is_empty());
+    }
+
+    #[test]
+    fn test_mount_content() {
+        let sub = CombinableDir::new("sub".to_string(), vec![test_file("file2", 1024)], vec![]);
+        let root = CombinableDir::new(
+            "root".to_string(),
+            vec![test_file("file1", 1024)],
+            vec![sub],
+        );
+        let other = CombinableDir::new("root".to_string(), vec![test_file("file3", 1024)], vec![]);
+
+        let mounted = mount_content(root, &parse_path("/mirrors/eu"));
+        let combined = CombinableDir::combine(vec![mounted, other]);
+        assert_eq!(
+            paths(combined),
+            vec![
+                "root/file3",
+                "root/mirrors/eu/file1",
+                "root/mirrors/eu/sub/file2"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mount_content_at_root() {
+        let root = CombinableDir::new("root".to_string(), vec![test_file("file1", 1024)], vec![]);
+        let mounted = mount_content(root, &parse_path("/"));
+        assert_eq!(paths(mounted), vec!["root/file1"]);
+    }
+
+    #[test]
+    fn test_select() {
+        let selected = Transform::Select("/pub".to_string())
+            .apply(generate_tree())
+            .unwrap();
+        assert_eq!(selected.name(), "root");
+        assert_eq!(paths(selected), vec!["root/2023/file3", "root/file2"]);
+
+        let missing = Transform::Select("/missing".to_string()).apply(generate_tree());
+        assert_eq!(
+            missing.err().unwrap(),
+            "cannot select /missing: no such directory"
+        );
+    }
+
+    #[test]
+    fn test_rename() {
+        let renamed = Transform::Rename {
+            from: "/pub/2023".to_string(),
+            to: "/archive/2023".to_string(),
+        }
+        .apply(generate_tree())
+        .unwrap();
+        assert_eq!(
+            paths(renamed),
+            vec![
+                "root/2024/file4",
+                "root/archive/2023/file3",
+                "root/file1",
+                "root/pub/file2"
+            ]
+        );
+
+        // renaming a missing directory changes nothing
+        let unchanged = Transform::Rename {
+            from: "/missing".to_string(),
+            to: "/other".to_string(),
+        }
+        .apply(generate_tree())
+        .unwrap();
+        assert_eq!(paths(unchanged), paths(generate_tree()));
+    }
+
+    #[test]
+    fn test_strip_prefix() {
+        let stripped = Transform::StripPrefix("/pub".to_string())
+            .apply(generate_tree())
+            .unwrap();
+        assert_eq!(
+            paths(stripped),
+            vec![
+                "root/2023/file3",
+                "root/2024/file4",
+                "root/file1",
+                "root/file2"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_flatten() {
+        let flattened = Transform::Flatten("/".to_string())
+            .apply(generate_tree())
+            .unwrap();
+        assert_eq!(
+            paths(flattened),
+            vec!["root/2023/file3", "root/file1", "root/file2", "root/file4"]
+        );
+
+        let flattened = Transform::Flatten("/pub".to_string())
+            .apply(generate_tree())
+            .unwrap();
+        assert_eq!(
+            paths(flattened),
+            vec![
+                "root/2024/file4",
+                "root/file1",
+                "root/pub/file2",
+                "root/pub/file3"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_deserialize() {
+        let json = r#"[
+            {"select": "/pub"},
+            {"rename": {"from": "/a", "to": "/b"}},
+            {"strip_prefix": "/c"},
+            {"flatten": "/"},
+            {"mount": "/mirrors/eu"}
+        ]"#;
+        let transforms: Vec<Transform> = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            transforms,
+            vec![
+                Transform::Select("/pub".to_string()),
+                Transform::Rename {
+                    from: "/a".to_string(),
+                    to: "/b".to_string()
+                },
+                Transform::StripPrefix("/c".to_string()),
+                Transform::Flatten("/".to_string()),
+                Transform::Mount("/mirrors/eu".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_keep_visibility() {
+        let hidden = Transform::Rename {
+            from: "/pub/2023".to_string(),
+            to: "/2023".to_string(),
+        };
+        let tree = generate_tree();
+        let (name, files, subdirectories) = tree.destruct();
+        let subdirectories = subdirectories
+            .into_iter()
+            .map(|x| match x.name() {
+                "pub" => x.with_visibility(Visibility::Hidden),
+                _ => x,
+            })
+            .collect();
+        let tree = CombinableDir::new(name, files, subdirectories);
+
+        // moved out of a hidden directory, but still hidden
+        let renamed = hidden.apply(tree.clone()).unwrap();
+        let listed = visibility::restrict(renamed, Visibility::Public);
+        assert_eq!(paths(listed), vec!["root/2024/file4", "root/file1"]);
+
+        let selected = Transform::Select("/pub".to_string()).apply(tree).unwrap();
+        let listed = visibility::restrict(selected.clone(), Visibility::Public);
+        assert!(paths(listed).is_empty());
+        assert_eq!(
+            paths(visibility::restrict(selected, Visibility::Hidden)),
+            vec!["root/2023/file3", "root/file2"]
+        );
+    }
+
+    #[test]
+    fn test_proxy() {
+        let transform: Transform = serde_json::from_str(r#""proxy""#).unwrap();
+        let proxied = transform.apply(generate_tree()).unwrap();
+        assert!(proxied.compress_path().values().all(|x| x.proxy));
+
+        let combined = CombinableDir::combine(vec![
+            Transform::Proxy.apply(generate_tree()).unwrap(),
+            generate_tree(),
+        ]);
+        assert!(combined
+            .compress_path()
+            .values()
+            .all(|x| x.proxy && x.links.len() == 2));
+    }
+}
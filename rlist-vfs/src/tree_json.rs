@@ -0,0 +1,642 @@
+use crate::combinable_dir::CombinableDir;
+use crate::diff::TreeDiff;
+use crate::password::Passwords;
+use crate::static_combinable::StaticCombinableFile;
+use crate::{VfsBasicMeta, VfsDirMeta};
+use chrono::{DateTime, Utc};
+use futures::stream::{self, Stream};
+use std::borrow::Borrow;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+type Tree = CombinableDir<StaticCombinableFile>;
+
+/// The size the chunks of [chunks] and [Fragments::chunks] are written up to, a chunk ends
+/// after the first file or directory that reaches it, and the last one may be smaller.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Write `root` as JSON, as seen without any password.
+///
+/// The output is the JSON of the [DirWithoutLink](crate::without_link::DirWithoutLink) of
+/// [Passwords::lock], but nothing is copied from the tree: protected directories are emptied
+/// while they are written, and the sizes above them computed on the way back up.
+#[tracing::instrument(level = "debug", name = "serialize", skip_all)]
+pub fn write(out: &mut impl Write, root: &Tree, passwords: &Passwords) -> io::Result<()> {
+    for chunk in TreeChunks::new(root, passwords) {
+        out.write_all(&chunk)?;
+    }
+    Ok(())
+}
+
+/// Like [write], without the files and directories, and everything below them, whose path
+/// relative to the root, like `/internal/report.pdf`, is not `allowed`.
+pub(crate) fn write_filtered(
+    out: &mut impl Write,
+    root: &Tree,
+    passwords: &Passwords,
+    allowed: impl Fn(&str) -> bool,
+) -> io::Result<()> {
+    for chunk in TreeChunks::filtered(root, passwords, Some(allowed)) {
+        out.write_all(&chunk)?;
+    }
+    Ok(())
+}
+
+/// [write] to a string.
+pub fn to_string(root: &Tree, passwords: &Passwords) -> String {
+    let mut out = Vec::new();
+    // writing to a `Vec` cannot fail
+    write(&mut out, root, passwords).unwrap();
+    // `serde_json` only writes UTF-8
+    String::from_utf8(out).unwrap()
+}
+
+/// [write] in chunks of about [CHUNK_SIZE] bytes, each written when the stream is polled for
+/// it, so the JSON of a large tree is never in memory at once and no thread waits on a slow
+/// consumer.
+pub fn chunks(
+    root: Arc<Tree>,
+    passwords: Arc<Passwords>,
+) -> impl Stream<Item = Vec<u8>> + Send + 'static {
+    stream::iter(TreeChunks::new(root, passwords))
+}
+
+/// The chunks of [write], where it left off.
+struct TreeChunks<T, P, F = fn(&str) -> bool> {
+    root: T,
+    passwords: P,
+    /// Which paths are written, all of them if `None`, see [write_filtered].
+    allowed: Option<F>,
+    /// The directories being written, from the root down, empty before the root is opened.
+    stack: Vec<Frame>,
+    done: bool,
+}
+
+/// A directory being written.
+struct Frame {
+    /// Its index in the subdirectories of its parent, `0` for the root.
+    index: usize,
+    path: String,
+    locked: bool,
+    stage: Stage,
+    /// Whether nothing was written yet at this stage.
+    first: bool,
+    /// Whether a file or subdirectory was left out.
+    filtered: bool,
+    /// The size and the latest modification written so far.
+    size: u64,
+    last_modified: Option<SystemTime>,
+}
+
+/// The next file or subdirectory of a [Frame] to write.
+#[derive(Clone, Copy)]
+enum Stage {
+    Files(usize),
+    Subdirectories(usize),
+}
+
+impl<T: Borrow<Tree>, P: Borrow<Passwords>> TreeChunks<T, P> {
+    fn new(root: T, passwords: P) -> Self {
+        Self::filtered(root, passwords, None)
+    }
+}
+
+impl<T: Borrow<Tree>, P: Borrow<Passwords>, F: Fn(&str) -> bool> TreeChunks<T, P, F> {
+    fn filtered(root: T, passwords: P, allowed: Option<F>) -> Self {
+        Self {
+            root,
+            passwords,
+            allowed,
+            stack: vec![],
+            done: false,
+        }
+    }
+}
+
+/// Open `dir`, the subdirectory `index` of the top of `stack`, at `path`.
+fn open(
+    stack: &mut Vec<Frame>,
+    passwords: &Passwords,
+    out: &mut Vec<u8>,
+    dir: &Tree,
+    index: usize,
+    path: String,
+) {
+    let locked = passwords.protecting(&path).is_some();
+    // writing to a `Vec` cannot fail
+    write_open(out, dir).unwrap();
+    stack.push(Frame {
+        index,
+        path,
+        locked,
+        stage: Stage::Files(0),
+        first: true,
+        filtered: false,
+        size: 0,
+        last_modified: None,
+    });
+}
+
+/// The directory at the top of `stack`.
+fn resolve<'a>(root: &'a Tree, stack: &[Frame]) -> &'a Tree {
+    stack
+        .iter()
+        .skip(1)
+        .fold(root, |dir, frame| &dir.subdirectories()[frame.index])
+}
+
+/// Whether `name` in the directory at `parent` is `allowed`.
+fn allows(allowed: &Option<impl Fn(&str) -> bool>, parent: &str, name: &str) -> bool {
+    allowed
+        .as_ref()
+        .is_none_or(|allowed| allowed(&format!("{}/{}", parent, name)))
+}
+
+impl<T, P, F> Iterator for TreeChunks<T, P, F>
+where
+    T: Borrow<Tree>,
+    P: Borrow<Passwords>,
+    F: Fn(&str) -> bool,
+{
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        if self.done {
+            return None;
+        }
+        let mut out = Vec::with_capacity(CHUNK_SIZE);
+        let (root, passwords, allowed) =
+            (self.root.borrow(), self.passwords.borrow(), &self.allowed);
+        if self.stack.is_empty() {
+            open(&mut self.stack, passwords, &mut out, root, 0, String::new());
+        }
+        while out.len() < CHUNK_SIZE {
+            let dir = resolve(root, &self.stack);
+            let frame = self.stack.last_mut().unwrap();
+            // writing to a `Vec` cannot fail
+            match frame.stage {
+                Stage::Files(i) if !frame.locked && i < dir.files().len() => {
+                    let file = &dir.files()[i];
+                    frame.stage = Stage::Files(i + 1);
+                    if !allows(allowed, &frame.path, &file.name) {
+                        frame.filtered = true;
+                        continue;
+                    }
+                    if !std::mem::take(&mut frame.first) {
+                        out.push(b',');
+                    }
+                    write_file(&mut out, file).unwrap();
+                    frame.size += file.size;
+                    frame.last_modified = frame.last_modified.max(Some(file.last_modified));
+                }
+                Stage::Files(_) => {
+                    out.extend_from_slice(b"],\"subdirectories\":[");
+                    frame.stage = Stage::Subdirectories(0);
+                    frame.first = true;
+                }
+                Stage::Subdirectories(i) if !frame.locked && i < dir.subdirectories().len() => {
+                    let subdirectory = &dir.subdirectories()[i];
+                    frame.stage = Stage::Subdirectories(i + 1);
+                    if !allows(allowed, &frame.path, subdirectory.name()) {
+                        frame.filtered = true;
+                        continue;
+                    }
+                    if !std::mem::take(&mut frame.first) {
+                        out.push(b',');
+                    }
+                    let path = format!("{}/{}", frame.path, subdirectory.name());
+                    open(&mut self.stack, passwords, &mut out, subdirectory, i, path);
+                }
+                Stage::Subdirectories(_) => {
+                    let frame = self.stack.pop().unwrap();
+                    let emptied = frame.locked || frame.filtered;
+                    let last_modified = effective_last_modified(dir, emptied, frame.last_modified);
+                    write_tail(&mut out, frame.size, last_modified).unwrap();
+                    match self.stack.last_mut() {
+                        Some(parent) => {
+                            parent.size += frame.size;
+                            parent.last_modified = parent.last_modified.max(Some(last_modified));
+                        }
+                        None => {
+                            self.done = true;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        Some(out)
+    }
+}
+
+/// The JSON of [write], kept by directory, so a new tree only has the directories that
+/// changed written again.
+///
+/// Costs about the size of the JSON in memory, in exchange for serving it without walking the
+/// tree.
+#[derive(Clone)]
+pub struct Fragments(Arc<Fragment>);
+
+/// A directory, with the JSON of its subdirectories kept apart.
+struct Fragment {
+    name: String,
+    /// `{"name":...,"files":[...],"subdirectories":[`
+    head: String,
+    subdirectories: Vec<Arc<Fragment>>,
+    /// `],"size":...,"last_modified":...}`
+    tail: String,
+    size: u64,
+    last_modified: SystemTime,
+}
+
+impl Fragments {
+    #[tracing::instrument(level = "debug", name = "fragments", skip_all)]
+    pub fn new(root: &Tree, passwords: &Passwords) -> Self {
+        Self(fragment(
+            None,
+            root,
+            "",
+            root.name(),
+            passwords,
+            &HashSet::new(),
+        ))
+    }
+
+    /// The fragments of `root`, which differs from the tree of `self` by `diff`.
+    ///
+    /// Only the directories with a changed file below them, or whose subdirectories changed,
+    /// are written again. `passwords` must be the ones `self` was built with.
+    #[tracing::instrument(level = "debug", name = "fragments", skip_all)]
+    pub fn update(&self, root: &Tree, passwords: &Passwords, diff: &TreeDiff) -> Self {
+        let changed = diff
+            .added
+            .iter()
+            .chain(&diff.modified)
+            .map(|(path, _)| path.as_str())
+            .chain(diff.removed.iter().map(|x| x.as_str()));
+        let mut dirty = HashSet::new();
+        for path in changed {
+            dirty.extend(path.match_indices('/').map(|(i, _)| &path[..i]));
+        }
+        Self(fragment(
+            Some(&self.0),
+            root,
+            "",
+            root.name(),
+            passwords,
+            &dirty,
+        ))
+    }
+
+    pub fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        write_fragment(out, &self.0)
+    }
+
+    /// Like [chunks], without walking the tree again.
+    pub fn chunks(&self) -> impl Stream<Item = Vec<u8>> + Send + 'static {
+        stream::iter(FragmentChunks {
+            root: Some(self.0.clone()),
+            stack: vec![],
+        })
+    }
+}
+
+/// Writes the fragments straight to the formatter, so `to_string` builds the JSON once.
+impl std::fmt::Display for Fragments {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn fmt(fragment: &Fragment, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str(&fragment.head)?;
+            for (i, subdirectory) in fragment.subdirectories.iter().enumerate() {
+                if i > 0 {
+                    f.write_str(",")?;
+                }
+                fmt(subdirectory, f)?;
+            }
+            f.write_str(&fragment.tail)
+        }
+        fmt(&self.0, f)
+    }
+}
+
+/// The fragment of `dir`, at `path` relative to the root for `passwords` and at `full` with
+/// the name of the root for the `dirty` directories, reusing `old` where nothing changed.
+fn fragment(
+    old: Option<&Arc<Fragment>>,
+    dir: &Tree,
+    path: &str,
+    full: &str,
+    passwords: &Passwords,
+    dirty: &HashSet<&str>,
+) -> Arc<Fragment> {
+    if let Some(old) = old.filter(|old| !dirty.contains(full) && same_shape(old, dir)) {
+        return old.clone();
+    }
+    let locked = passwords.protecting(path).is_some();
+    let mut head = Vec::new();
+    // writing to a `Vec` cannot fail
+    let (mut size, mut last_modified) = write_head(&mut head, dir, locked).unwrap();
+    let mut subdirectories = vec![];
+    if !locked {
+        let old = old
+            .map(|x| {
+                x.subdirectories
+                    .iter()
+                    .map(|x| (x.name.as_str(), x))
+                    .collect::<HashMap<_, _>>()
+            })
+            .unwrap_or_default();
+        for subdirectory in dir.subdirectories() {
+            let name = subdirectory.name();
+            let fragment = fragment(
+                old.get(name).copied(),
+                subdirectory,
+                &format!("{}/{}", path, name),
+                &format!("{}/{}", full, name),
+                passwords,
+                dirty,
+            );
+            size += fragment.size;
+            last_modified = last_modified.max(Some(fragment.last_modified));
+            subdirectories.push(fragment);
+        }
+    }
+    let last_modified = effective_last_modified(dir, locked, last_modified);
+    let mut tail = Vec::new();
+    write_tail(&mut tail, size, last_modified).unwrap();
+    Arc::new(Fragment {
+        name: dir.name().to_string(),
+        // `serde_json` only writes UTF-8
+        head: String::from_utf8(head).unwrap(),
+        subdirectories,
+        tail: String::from_utf8(tail).unwrap(),
+        size,
+        last_modified,
+    })
+}
+
+/// Whether `fragment` has the name and subdirectories of `dir`, all the way down.
+///
+/// Added and removed files show in the diff, but empty directories do not.
+fn same_shape(fragment: &Fragment, dir: &Tree) -> bool {
+    fragment.name == dir.name()
+        && fragment.subdirectories.len() == dir.subdirectories().len()
+        && fragment
+            .subdirectories
+            .iter()
+            .zip(dir.subdirectories())
+            .all(|(x, y)| same_shape(x, y))
+}
+
+/// The chunks of [write_fragment], where it left off.
+struct FragmentChunks {
+    /// The root, until it is opened.
+    root: Option<Arc<Fragment>>,
+    /// The fragments being written, with the next subdirectory to write.
+    stack: Vec<(Arc<Fragment>, usize)>,
+}
+
+impl Iterator for FragmentChunks {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        let mut out = Vec::with_capacity(CHUNK_SIZE);
+        if let Some(root) = self.root.take() {
+            out.extend_from_slice(root.head.as_bytes());
+            self.stack.push((root, 0));
+        }
+        while out.len() < CHUNK_SIZE {
+            let Some((fragment, next)) = self.stack.last_mut() else {
+                break;
+            };
+            match fragment.subdirectories.get(*next).cloned() {
+                Some(subdirectory) => {
+                    if *next > 0 {
+                        out.push(b',');
+                    }
+                    *next += 1;
+                    out.extend_from_slice(subdirectory.head.as_bytes());
+                    self.stack.push((subdirectory, 0));
+                }
+                None => {
+                    out.extend_from_slice(fragment.tail.as_bytes());
+                    self.stack.pop();
+                }
+            }
+        }
+        (!out.is_empty()).then_some(out)
+    }
+}
+
+fn write_fragment(out: &mut impl Write, fragment: &Fragment) -> io::Result<()> {
+    out.write_all(fragment.head.as_bytes())?;
+    for (i, subdirectory) in fragment.subdirectories.iter().enumerate() {
+        if i > 0 {
+            out.write_all(b",")?;
+        }
+        write_fragment(out, subdirectory)?;
+    }
+    out.write_all(fragment.tail.as_bytes())
+}
+
+/// Write the name and the files of `dir`, none if it is `locked`, up to its subdirectories,
+/// and return the size and the latest modification of the files.
+fn write_head(
+    out: &mut impl Write,
+    dir: &Tree,
+    locked: bool,
+) -> io::Result<(u64, Option<SystemTime>)> {
+    write_open(out, dir)?;
+    let (mut size, mut last_modified) = (0, None);
+    if !locked {
+        for (i, file) in dir.files().iter().enumerate() {
+            if i > 0 {
+                out.write_all(b",")?;
+            }
+            write_file(out, file)?;
+            size += file.size;
+            last_modified = last_modified.max(Some(file.last_modified));
+        }
+    }
+    out.write_all(b"],\"subdirectories\":[")?;
+    Ok((size, last_modified))
+}
+
+/// Write the name of `dir`, up to its files.
+fn write_open(out: &mut impl Write, dir: &Tree) -> io::Result<()> {
+    out.write_all(b"{\"name\":")?;
+    serde_json::to_writer(&mut *out, dir.name())?;
+    out.write_all(b",\"files\":[")
+}
+
+fn write_file(out: &mut impl Write, file: &StaticCombinableFile) -> io::Result<()> {
+    out.write_all(b"{\"name\":")?;
+    serde_json::to_writer(&mut *out, &file.name)?;
+    write!(out, ",\"size\":{},\"last_modified\":", file.size)?;
+    write_time(out, file.last_modified)?;
+    out.write_all(b"}")
+}
+
+fn write_tail(out: &mut impl Write, size: u64, last_modified: SystemTime) -> io::Result<()> {
+    write!(out, "],\"size\":{},\"last_modified\":", size)?;
+    write_time(out, last_modified)?;
+    out.write_all(b"}")
+}
+
+/// The latest modification below `dir`, or, like [CombinableDir::new] for an empty directory,
+/// the time it was emptied if it was locked or filtered.
+fn effective_last_modified(dir: &Tree, emptied: bool, latest: Option<SystemTime>) -> SystemTime {
+    match (latest, emptied) {
+        (Some(latest), _) => latest,
+        (None, true) => SystemTime::now(),
+        (None, false) => dir.last_modified(),
+    }
+}
+
+fn write_time(out: &mut impl Write, time: SystemTime) -> io::Result<()> {
+    let time: DateTime<Utc> = time.into();
+    Ok(serde_json::to_writer(out, &time)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff;
+    use crate::password::PasswordHash;
+    use crate::static_combinable::test_file;
+    use crate::without_link::DirWithoutLink;
+    use futures::StreamExt;
+    use std::time::Duration;
+
+    fn generate_file(name: &str, size: u64) -> StaticCombinableFile {
+        let mut file = test_file(name, size);
+        file.last_modified += Duration::from_secs(1673702400 + size);
+        file
+    }
+
+    fn dir(name: &str, files: Vec<StaticCombinableFile>, subdirectories: Vec<Tree>) -> Tree {
+        CombinableDir::new(name.to_string(), files, subdirectories)
+    }
+
+    // root
+    // ├── "quoted" (1)
+    // ├── public
+    // │   ├── a (2)
+    // │   └── deep
+    // │       └── b (3)
+    // └── internal
+    //     └── c (4)
+    fn tree() -> Tree {
+        let deep = dir("deep", vec![generate_file("b", 3)], vec![]);
+        dir(
+            "root",
+            vec![generate_file("\"quoted\"", 1)],
+            vec![
+                dir("public", vec![generate_file("a", 2)], vec![deep]),
+                dir("internal", vec![generate_file("c", 4)], vec![]),
+            ],
+        )
+    }
+
+    fn expected(root: &Tree, passwords: &Passwords) -> String {
+        let dir: DirWithoutLink = passwords.lock(root.clone()).into();
+        serde_json::to_string(&dir).unwrap()
+    }
+
+    /// `json` without the modification times, which are the time of locking above the
+    /// protected directories.
+    fn without_times(json: &[u8]) -> serde_json::Value {
+        fn strip(value: &mut serde_json::Value) {
+            if let Some(object) = value.as_object_mut() {
+                object.remove("last_modified");
+                object.values_mut().for_each(strip);
+            } else if let Some(array) = value.as_array_mut() {
+                array.iter_mut().for_each(strip);
+            }
+        }
+        let mut value = serde_json::from_slice(json).unwrap();
+        strip(&mut value);
+        value
+    }
+
+    fn passwords(path: &str) -> Passwords {
+        let hash = PasswordHash::try_from(bcrypt::hash("secret", 4).unwrap()).unwrap();
+        Passwords::new(vec![(path.to_string(), hash)])
+    }
+
+    #[test]
+    fn test_write() {
+        let root = tree();
+        let passwords = Passwords::default();
+        assert_eq!(to_string(&root, &passwords), expected(&root, &passwords));
+        let passwords = self::passwords("/public/deep");
+        let json = to_string(&root, &passwords);
+        assert_eq!(
+            without_times(json.as_bytes()),
+            without_times(expected(&root, &passwords).as_bytes())
+        );
+        let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(json["size"], 7);
+        assert_eq!(json["subdirectories"][0]["subdirectories"][0]["size"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_chunks() {
+        let files = (0..2000)
+            .map(|i| generate_file(&format!("{:040}", i), i))
+            .collect();
+        let root = Arc::new(dir("root", files, vec![tree()]));
+        let passwords = Arc::new(self::passwords("/root/internal"));
+        let chunks = chunks(root.clone(), passwords.clone())
+            .collect::<Vec<_>>()
+            .await;
+        assert!(chunks.len() > 1);
+        assert!(chunks[..chunks.len() - 1]
+            .iter()
+            .all(|x| x.len() >= CHUNK_SIZE));
+        let expected = without_times(expected(&root, &passwords).as_bytes());
+        assert_eq!(without_times(&chunks.concat()), expected);
+
+        let fragments = Fragments::new(&root, &passwords);
+        let chunks = fragments.chunks().collect::<Vec<_>>().await;
+        assert_eq!(without_times(&chunks.concat()), expected);
+    }
+
+    #[test]
+    fn test_fragments() {
+        let passwords = self::passwords("/internal");
+        let old = tree();
+        let fragments = Fragments::new(&old, &passwords);
+        let same = |fragments: &Fragments, tree: &Tree| {
+            let expected = expected(tree, &passwords);
+            without_times(fragments.to_string().as_bytes()) == without_times(expected.as_bytes())
+        };
+        assert!(same(&fragments, &old));
+
+        // `a` resized, `e` added in a new directory, `deep` emptied out
+        let new = dir(
+            "root",
+            vec![generate_file("\"quoted\"", 1)],
+            vec![
+                dir("public", vec![generate_file("a", 5)], vec![]),
+                dir("internal", vec![generate_file("c", 4)], vec![]),
+                dir("new", vec![generate_file("e", 6)], vec![]),
+            ],
+        );
+        let updated = fragments.update(&new, &passwords, &diff::diff(&old, &new));
+        assert!(same(&updated, &new));
+        // untouched directories are shared
+        assert!(Arc::ptr_eq(
+            &fragments.0.subdirectories[1],
+            &updated.0.subdirectories[1]
+        ));
+
+        // only an empty directory added
+        let (name, files, mut subdirectories) = new.clone().destruct();
+        subdirectories.push(dir("empty", vec![], vec![]));
+        let with_empty = dir(&name, files, subdirectories);
+        let updated = updated.update(&with_empty, &passwords, &diff::diff(&new, &with_empty));
+        assert!(same(&updated, &with_empty));
+    }
+}
@@ -0,0 +1,190 @@
+use crate::combinable_dir::CombinableDir;
+use crate::static_combinable::{StaticCombinableFile, StaticDownloadLinkFile};
+use crate::{VfsBasicMeta, VfsDirMeta};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+type Tree = CombinableDir<StaticCombinableFile>;
+
+/// Who can see an entry of the tree.
+///
+/// An entry is at least as restricted as the directory it is in. When the same file comes from
+/// several drivers the most restricted visibility wins, and when the same directory does, the
+/// least restricted, with the content of each driver as restricted as its directory was.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Visibility {
+    /// Listed in the tree and downloadable.
+    #[default]
+    Public,
+    /// Left out of the tree, but still downloadable by its path.
+    Hidden,
+    /// Neither listed nor downloadable.
+    Private,
+}
+
+impl Visibility {
+    pub fn is_public(&self) -> bool {
+        *self == Visibility::Public
+    }
+}
+
+/// Drop every entry more restricted than `max`, the root itself is always kept.
+///
+/// `restrict(root, Visibility::Public)` is what is listed, and `restrict(root, Visibility::Hidden)`
+/// is what can be downloaded. Directory sizes only count the entries that are kept.
+pub fn restrict(root: Tree, max: Visibility) -> Tree {
+    let visibility = root.visibility();
+    let name = root.name().to_string();
+    if visibility > max {
+        return CombinableDir::new(name, vec![], vec![]).with_visibility(visibility);
+    }
+    let (files, subdirectories) = root.into_content();
+    let files = files
+        .into_iter()
+        .filter(|file| file.visibility() <= max)
+        .collect();
+    let subdirectories = subdirectories
+        .into_iter()
+        .filter(|dir| dir.visibility() <= max)
+        .map(|dir| restrict(dir, max))
+        .collect();
+    CombinableDir::new(name, files, subdirectories).with_visibility(visibility)
+}
+
+/// The files of `root` no more restricted than `max` by their path, like
+/// `restrict(root.clone(), max).compress_path()` but copying only the files it keeps.
+pub fn restricted_paths(root: &Tree, max: Visibility) -> HashMap<String, StaticCombinableFile> {
+    fn insert(
+        path: &str,
+        dir: &Tree,
+        visibility: Visibility,
+        max: Visibility,
+        map: &mut HashMap<String, StaticCombinableFile>,
+    ) {
+        for file in dir.files() {
+            let restricted = file.visibility().max(visibility);
+            if restricted <= max {
+                let file = file.clone().with_visibility(restricted);
+                map.insert(format!("{}/{}", path, file.name()), file);
+            }
+        }
+        for subdirectory in dir.subdirectories() {
+            let restricted = subdirectory.visibility().max(visibility);
+            if restricted <= max {
+                let path = format!("{}/{}", path, subdirectory.name());
+                insert(&path, subdirectory, restricted, max, map);
+            }
+        }
+    }
+    let mut map = HashMap::new();
+    if root.visibility() <= max {
+        insert(root.name(), root, root.visibility(), max, &mut map);
+    }
+    map
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::combinable::Combinable;
+    use crate::static_combinable::test_file;
+
+    // root
+    // ├── public
+    // ├── hidden (hidden)
+    // ├── private (private)
+    // └── drafts (hidden)
+    //     └── draft
+    fn generate_tree() -> Tree {
+        let drafts =
+            CombinableDir::new("drafts".to_string(), vec![test_file("draft", 1024)], vec![])
+                .with_visibility(Visibility::Hidden);
+        CombinableDir::new(
+            "root".to_string(),
+            vec![
+                test_file("public", 1024),
+                test_file("hidden", 1024).with_visibility(Visibility::Hidden),
+                test_file("private", 1024).with_visibility(Visibility::Private),
+            ],
+            vec![drafts],
+        )
+    }
+
+    fn paths(tree: Tree) -> Vec<String> {
+        let mut paths = tree.compress_path().into_keys().collect::<Vec<_>>();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn test_restrict() {
+        let listed = restrict(generate_tree(), Visibility::Public);
+        assert_eq!(listed.size(), 1024);
+        assert_eq!(paths(listed), vec!["root/public"]);
+
+        let downloadable = restrict(generate_tree(), Visibility::Hidden);
+        assert_eq!(
+            paths(downloadable),
+            vec!["root/drafts/draft", "root/hidden", "root/public"]
+        );
+    }
+
+    #[test]
+    fn test_restricted_paths() {
+        for max in [Visibility::Public, Visibility::Hidden, Visibility::Private] {
+            let expected = restrict(generate_tree(), max).compress_path();
+            let map = restricted_paths(&generate_tree(), max);
+            let mut keys = map.keys().collect::<Vec<_>>();
+            keys.sort();
+            let mut expected_keys = expected.keys().collect::<Vec<_>>();
+            expected_keys.sort();
+            assert_eq!(keys, expected_keys);
+            assert!(map
+                .iter()
+                .all(|(path, file)| file.visibility() == expected[path].visibility()));
+        }
+    }
+
+    #[test]
+    fn test_combine_most_restricted() {
+        let public = CombinableDir::new("root".to_string(), vec![test_file("file", 1024)], vec![]);
+        let private = CombinableDir::new(
+            "root".to_string(),
+            vec![test_file("file", 1024).with_visibility(Visibility::Private)],
+            vec![],
+        );
+        let combined = CombinableDir::combine(vec![public, private]);
+        assert_eq!(combined.files()[0].visibility(), Visibility::Private);
+        assert_eq!(combined.files()[0].links.len(), 2);
+    }
+
+    #[test]
+    fn test_combine_directory_least_restricted() {
+        // root/media is hidden by one driver and public in the other
+        let media = |file: &str| {
+            CombinableDir::new("media".to_string(), vec![test_file(file, 1024)], vec![])
+        };
+        let hidden = CombinableDir::new(
+            "root".to_string(),
+            vec![],
+            vec![media("hidden").with_visibility(Visibility::Hidden)],
+        );
+        let public = CombinableDir::new("root".to_string(), vec![], vec![media("public")]);
+        let combined = CombinableDir::combine(vec![hidden, public]);
+        assert_eq!(
+            combined.subdirectories()[0].visibility(),
+            Visibility::Public
+        );
+        assert_eq!(
+            paths(restrict(combined.clone(), Visibility::Public)),
+            vec!["root/media/public"]
+        );
+        assert_eq!(
+            paths(restrict(combined, Visibility::Hidden)),
+            vec!["root/media/hidden", "root/media/public"]
+        );
+    }
+}
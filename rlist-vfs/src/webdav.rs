@@ -0,0 +1,420 @@
+use crate::password::Lookup;
+use crate::proxy::Proxy;
+use crate::server::password;
+use crate::static_combinable::StaticCombinableFile;
+use crate::wheel::ListedDir;
+use crate::{VfsBasicMeta, VfsDirMeta, Wheel};
+use axum::extract::{Path, State};
+use axum::http::{header, HeaderMap, Method, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::any;
+use axum::Router;
+use chrono::{DateTime, Utc};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+#[derive(Clone)]
+struct Dav {
+    wheel: Arc<Wheel>,
+    /// Where the root of the tree is served, like `/dav`.
+    prefix: String,
+    proxy: Proxy,
+}
+
+/// A read-only WebDAV service of the listed tree of a [Wheel], under `prefix`, like `/dav`,
+/// which cannot be the root.
+///
+/// It answers `OPTIONS`, `PROPFIND` with a depth of 0 or 1, and `GET` or `HEAD` of a file with
+/// `302 Found` to a download link of it, or with the file itself if it is proxied, see
+/// [Proxy]. A `PROPFIND` of infinite depth, which is what a missing `Depth` header means, is
+/// refused with `403 Forbidden` and the `propfind-finite-depth` precondition of RFC 4918.
+/// Protected directories answer `401 Unauthorized` until the password is sent by basic
+/// authentication.
+///
+/// Callers are not identified, so an [AccessPolicy](crate::acl::AccessPolicy) is not enforced
+/// here, see [AccessControl](crate::acl::AccessControl).
+///
+/// `Err` if `prefix` does not start with `/`, is the root, or has a segment starting with `:`
+/// or `*`, which the router would take for a parameter.
+pub fn router(wheel: Arc<Wheel>, prefix: &str) -> Result<Router, String> {
+    let trimmed = prefix.trim_end_matches('/');
+    if !trimmed.starts_with('/') {
+        return Err(format!(
+            "invalid WebDAV prefix {:?}: it must start with / and cannot be the root",
+            prefix
+        ));
+    }
+    if trimmed
+        .split('/')
+        .any(|x| x.starts_with(':') || x.starts_with('*'))
+    {
+        return Err(format!(
+            "invalid WebDAV prefix {:?}: segments cannot start with : or *",
+            prefix
+        ));
+    }
+    Ok(router_with_proxy(wheel, prefix, Proxy::default()))
+}
+
+/// Like [router], with the links picked and proxied by `proxy`, for a `prefix` known to be
+/// valid.
+pub(crate) fn router_with_proxy(wheel: Arc<Wheel>, prefix: &str, proxy: Proxy) -> Router {
+    let prefix = prefix.trim_end_matches('/').to_string();
+    #[cfg(feature = "metrics")]
+    let proxy = proxy.with_metrics(wheel.metrics().clone());
+    let proxy = proxy.with_headers(wheel.clone());
+    let state = Dav {
+        wheel,
+        prefix: prefix.clone(),
+        proxy,
+    };
+    Router::new()
+        .route(&prefix, any(root))
+        .route(&format!("{}/", prefix), any(root))
+        .route(&format!("{}/*path", prefix), any(entry))
+        .with_state(state)
+}
+
+async fn root(State(dav): State<Dav>, method: Method, headers: HeaderMap) -> Response {
+    handle(dav, method, headers, String::new()).await
+}
+
+async fn entry(
+    State(dav): State<Dav>,
+    method: Method,
+    headers: HeaderMap,
+    Path(path): Path<String>,
+) -> Response {
+    handle(dav, method, headers, path).await
+}
+
+async fn handle(dav: Dav, method: Method, headers: HeaderMap, path: String) -> Response {
+    let relative = path.trim_matches('/');
+    let root = dav.wheel.listing.read().name().to_string();
+    let path = match relative {
+        "" => root,
+        relative => format!("{}/{}", root, relative),
+    };
+    let password = password(&headers);
+    let password = password.as_deref();
+    match method.as_str() {
+        "OPTIONS" => (
+            StatusCode::OK,
+            [
+                (header::HeaderName::from_static("dav"), "1"),
+                (header::ALLOW, "OPTIONS, PROPFIND, GET, HEAD"),
+            ],
+        )
+            .into_response(),
+        "PROPFIND" => {
+            let depth = match headers.get("depth").map(|x| x.as_bytes()) {
+                Some(b"0") => 0,
+                Some(b"1") => 1,
+                Some(b"infinity") | None => return finite_depth_required(),
+                Some(_) => return StatusCode::BAD_REQUEST.into_response(),
+            };
+            let href = format!("{}/{}", dav.prefix, encode_path(relative));
+            match dav.wheel.lookup(&path, password).await {
+                Lookup::Found(file) => multistatus(vec![file_response(&href, &file)]),
+                Lookup::NotFound => match dav.wheel.find_dir(&path, password).await {
+                    Lookup::Found(dir) => multistatus(dir_responses(&href, &dir, depth)),
+                    lookup => not_found(lookup),
+                },
+                lookup => not_found(lookup),
+            }
+        }
+        "GET" | "HEAD" => match dav.wheel.lookup(&path, password).await {
+            Lookup::Found(file) if file.proxy => dav.proxy.serve(&file, &method, &headers).await,
+            Lookup::Found(file) => dav.proxy.redirect(&file),
+            Lookup::NotFound if dav.wheel.find_dir(&path, password).await.found().is_some() => {
+                StatusCode::METHOD_NOT_ALLOWED.into_response()
+            }
+            lookup => not_found(lookup),
+        },
+        _ => StatusCode::METHOD_NOT_ALLOWED.into_response(),
+    }
+}
+
+/// The response to a lookup that found nothing.
+fn not_found<T>(lookup: Lookup<T>) -> Response {
+    match lookup {
+        Lookup::PasswordRequired { .. } => (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Basic realm=\"rlist\"")],
+        )
+            .into_response(),
+        _ => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// The answer to a `PROPFIND` of infinite depth, see RFC 4918, section 9.1.
+fn finite_depth_required() -> Response {
+    let body = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+        <D:error xmlns:D=\"DAV:\"><D:propfind-finite-depth/></D:error>";
+    (
+        StatusCode::FORBIDDEN,
+        [(header::CONTENT_TYPE, "application/xml; charset=utf-8")],
+        body,
+    )
+        .into_response()
+}
+
+fn multistatus(responses: Vec<String>) -> Response {
+    let body = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">{}</D:multistatus>",
+        responses.concat()
+    );
+    (
+        StatusCode::MULTI_STATUS,
+        [(header::CONTENT_TYPE, "application/xml; charset=utf-8")],
+        body,
+    )
+        .into_response()
+}
+
+fn dir_responses(href: &str, listed: &ListedDir, depth: u8) -> Vec<String> {
+    let href = format!("{}/", href.trim_end_matches('/'));
+    let dir = listed.dir();
+    let mut responses = vec![response(&href, dir.name(), listed.usage(), true)];
+    if depth > 0 {
+        for subdirectory in dir.subdirectories() {
+            let href = format!("{}{}/", href, encode_segment(subdirectory.name()));
+            let usage = listed.subdirectory_usage(subdirectory);
+            responses.push(response(&href, subdirectory.name(), usage, true));
+        }
+        for file in dir.files() {
+            let href = format!("{}{}", href, encode_segment(file.name()));
+            responses.push(file_response(&href, file));
+        }
+    }
+    responses
+}
+
+fn file_response(href: &str, file: &StaticCombinableFile) -> String {
+    let usage = (file.size(), file.last_modified());
+    response(href, file.name(), usage, false)
+}
+
+/// The response for the entry `name`, with its size and modification time in `usage`.
+fn response(href: &str, name: &str, usage: (u64, SystemTime), collection: bool) -> String {
+    let resource_type = if collection { "<D:collection/>" } else { "" };
+    let (size, last_modified) = usage;
+    let last_modified: DateTime<Utc> = last_modified.into();
+    format!(
+        "<D:response><D:href>{}</D:href><D:propstat><D:prop>\
+        <D:displayname>{}</D:displayname>\
+        <D:resourcetype>{}</D:resourcetype>\
+        <D:getcontentlength>{}</D:getcontentlength>\
+        <D:getlastmodified>{}</D:getlastmodified>\
+        </D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>",
+        escape_xml(href),
+        escape_xml(name),
+        resource_type,
+        size,
+        last_modified.format("%a, %d %b %Y %H:%M:%S GMT"),
+    )
+}
+
+fn encode_path(path: &str) -> String {
+    path.split('/')
+        .map(encode_segment)
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Percent-encode everything but the unreserved characters of RFC 3986.
+fn encode_segment(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|x| match x {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (x as char).to_string()
+            }
+            _ => format!("%{:02X}", x),
+        })
+        .collect()
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::driver::CloudDriver;
+    use crate::static_driver::StaticDriver;
+    use axum::body::{to_bytes, Body};
+    use axum::http::Request;
+    use base64::Engine;
+    use tower::ServiceExt;
+
+    const CONFIG: &str = r#"
+    {
+        "name": "root",
+        "size": 2048,
+        "last_modified": "2021-01-01T00:00:00Z",
+        "files": [
+            {
+                "name": "a&b",
+                "size": 1024,
+                "last_modified": "2021-01-01T00:00:00Z",
+                "links": ["https://example.com/a"]
+            }
+        ],
+        "subdirectories": [
+            {
+                "name": "sub dir",
+                "size": 1024,
+                "last_modified": "2021-01-01T00:00:00Z",
+                "files": [
+                    {
+                        "name": "file",
+                        "size": 1024,
+                        "last_modified": "2021-01-01T00:00:00Z",
+                        "links": ["https://example.com/file"]
+                    }
+                ],
+                "subdirectories": []
+            }
+        ]
+    }
+    "#;
+
+    async fn wheel() -> Arc<Wheel> {
+        let driver = StaticDriver::new(serde_json::from_str(CONFIG).unwrap()).await;
+        Wheel::new(vec![Box::new(driver)]).await
+    }
+
+    async fn request(
+        wheel: &Arc<Wheel>,
+        method: &str,
+        uri: &str,
+        headers: Vec<(&str, String)>,
+    ) -> (StatusCode, HeaderMap, String) {
+        let mut request = Request::builder().method(method).uri(uri);
+        for (name, value) in headers {
+            request = request.header(name, value);
+        }
+        let request = request.body(Body::empty()).unwrap();
+        let response = router(wheel.clone(), "/dav")
+            .unwrap()
+            .oneshot(request)
+            .await
+            .unwrap();
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, headers, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    fn depth(depth: &str) -> Vec<(&'static str, String)> {
+        vec![("depth", depth.to_string())]
+    }
+
+    #[tokio::test]
+    async fn test_prefix() {
+        let wheel = wheel().await;
+        for prefix in ["", "/", "//", "dav", "/:dav", "/dav/*path"] {
+            let error = router(wheel.clone(), prefix).err().unwrap();
+            assert!(error.starts_with("invalid WebDAV prefix"), "{}", error);
+        }
+        assert!(router(wheel.clone(), "/dav/").is_ok());
+        assert!(router(wheel, "/files/dav").is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_propfind_root() {
+        let wheel = wheel().await;
+        let (status, _, body) = request(&wheel, "PROPFIND", "/dav/", depth("1")).await;
+        assert_eq!(status, StatusCode::MULTI_STATUS);
+        assert_eq!(body.matches("<D:response>").count(), 3);
+        assert!(body.contains("<D:href>/dav/</D:href>"));
+        assert!(body.contains("<D:href>/dav/sub%20dir/</D:href>"));
+        assert!(body.contains("<D:href>/dav/a%26b</D:href>"));
+        assert!(body.contains("<D:displayname>a&amp;b</D:displayname>"));
+        assert!(body.contains("<D:getcontentlength>2048</D:getcontentlength>"));
+        assert!(
+            body.contains("<D:getlastmodified>Fri, 01 Jan 2021 00:00:00 GMT</D:getlastmodified>")
+        );
+
+        let (_, _, body) = request(&wheel, "PROPFIND", "/dav/", depth("0")).await;
+        assert_eq!(body.matches("<D:response>").count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_propfind_infinite_depth() {
+        let wheel = wheel().await;
+        for depth in [depth("infinity"), vec![]] {
+            let (status, _, body) = request(&wheel, "PROPFIND", "/dav/", depth).await;
+            assert_eq!(status, StatusCode::FORBIDDEN);
+            assert!(body.contains("<D:propfind-finite-depth/>"));
+        }
+        let (status, _, _) = request(&wheel, "PROPFIND", "/dav/", depth("2")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_propfind_entries() {
+        let wheel = wheel().await;
+        let (status, _, body) = request(&wheel, "PROPFIND", "/dav/sub%20dir", depth("1")).await;
+        assert_eq!(status, StatusCode::MULTI_STATUS);
+        assert!(body.contains("<D:href>/dav/sub%20dir/file</D:href>"));
+
+        let (status, _, body) =
+            request(&wheel, "PROPFIND", "/dav/sub%20dir/file", depth("1")).await;
+        assert_eq!(status, StatusCode::MULTI_STATUS);
+        assert_eq!(body.matches("<D:response>").count(), 1);
+        assert!(body.contains("<D:resourcetype></D:resourcetype>"));
+
+        let (status, _, _) = request(&wheel, "PROPFIND", "/dav/missing", depth("1")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_get() {
+        let wheel = wheel().await;
+        let (status, headers, _) = request(&wheel, "GET", "/dav/sub%20dir/file", vec![]).await;
+        assert_eq!(status, StatusCode::FOUND);
+        assert_eq!(headers[header::LOCATION], "https://example.com/file");
+        let (status, _, _) = request(&wheel, "HEAD", "/dav/a%26b", vec![]).await;
+        assert_eq!(status, StatusCode::FOUND);
+        let (status, _, _) = request(&wheel, "GET", "/dav/sub%20dir", vec![]).await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        let (status, _, _) = request(&wheel, "DELETE", "/dav/a%26b", vec![]).await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+
+        let (status, headers, _) = request(&wheel, "OPTIONS", "/dav/", vec![]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers["dav"], "1");
+    }
+
+    #[tokio::test]
+    async fn test_basic_auth() {
+        let wheel = wheel().await;
+        let hash = bcrypt::hash("secret", 4).unwrap();
+        let passwords = serde_json::from_str(&format!(r#"{{"/sub dir": "{}"}}"#, hash)).unwrap();
+        wheel.set_passwords(passwords).await;
+
+        let (status, headers, _) = request(&wheel, "PROPFIND", "/dav/sub%20dir", depth("1")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(headers.contains_key(header::WWW_AUTHENTICATE));
+
+        let credentials = base64::engine::general_purpose::STANDARD.encode("anyone:secret");
+        let mut auth = depth("1");
+        auth.push(("authorization", format!("Basic {}", credentials)));
+        let (status, _, body) = request(&wheel, "PROPFIND", "/dav/sub%20dir", auth).await;
+        assert_eq!(status, StatusCode::MULTI_STATUS);
+        assert!(body.contains("file"));
+        assert!(body.contains("<D:getcontentlength>1024</D:getcontentlength>"));
+
+        // the locked directory and the root are listed without its content
+        let (_, _, body) = request(&wheel, "PROPFIND", "/dav/", depth("1")).await;
+        assert!(body.contains("<D:href>/dav/sub%20dir/</D:href>"));
+        assert!(body.contains("<D:getcontentlength>0</D:getcontentlength>"));
+        assert!(!body.contains("<D:getcontentlength>2048</D:getcontentlength>"));
+    }
+}
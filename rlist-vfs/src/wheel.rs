@@ -1,62 +1,702 @@
 use crate::combinable::Combinable;
 use crate::combinable_dir::CombinableDir;
+use crate::diff::{self, TreeDiff};
 use crate::driver::GetVfs;
+use crate::filter::Filter;
+use crate::password::{Lookup, Passwords, Unlocked};
 use crate::rcu::ReadCopyUpdate;
-use crate::static_combinable::StaticCombinableFile;
+use crate::search::{Query, SearchIndex, SearchResults};
+use crate::snapshot;
+use crate::static_combinable::{StaticCombinableFile, StaticDownloadLinkFile};
+use crate::stats::{TreeStats, Usage};
+use crate::tree_json::{self, Fragments};
+use crate::visibility::{self, Visibility};
 use crate::without_link::DirWithoutLink;
+use crate::{VfsBasicMeta, VfsDirMeta};
 use futures::future::join_all;
+use futures::stream::{BoxStream, FuturesUnordered, StreamExt};
+use serde::{Serialize, Serializer};
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::time::{self, Duration};
+use tokio::sync::{watch, Mutex};
+use tokio::time::{self, Duration, Instant};
+
+/// Identifies a driver registered in a [Wheel], never reused during the life of the `Wheel`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct DriverId(pub(crate) u64);
+
+impl fmt::Display for DriverId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "driver-{}", self.0)
+    }
+}
+
+/// Serialized like it is displayed, `driver-0`.
+impl Serialize for DriverId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Which drivers an entry of the combined tree came from, see [Wheel::provenance].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Provenance {
+    /// The drivers that provide the entry, in the order they are combined in.
+    pub drivers: Vec<DriverId>,
+    /// Every link of a file with the driver it came from, empty for a directory.
+    pub links: Vec<(String, DriverId)>,
+}
+
+type Tree = CombinableDir<StaticCombinableFile>;
 
 pub struct Wheel {
-    pub drivers: Vec<Box<dyn GetVfs>>,
+    /// Registered drivers in registration order, which is also the order they are combined in.
+    drivers: ReadCopyUpdate<Vec<(DriverId, Arc<dyn GetVfs>)>>,
+    next_driver_id: AtomicU64,
+    /// The last tree each driver reported successfully.
+    ///
+    /// Held while publishing, so trees are published and saved in the order they are built.
+    trees: Mutex<HashMap<DriverId, Tree>>,
+    /// Applied to every driver's tree before combining, see [Wheel::set_filter].
+    filter: ReadCopyUpdate<Filter>,
+    /// Directories that need a password, see [Wheel::set_passwords].
+    pub(crate) passwords: ReadCopyUpdate<Passwords>,
+    /// The passwords that opened a protected directory of `passwords`.
+    unlocked: Unlocked,
     pub path_map: ReadCopyUpdate<HashMap<String, StaticCombinableFile>>,
-    pub tree: ReadCopyUpdate<String>,
+    /// What [Wheel::tree] is serialized from, protected directories included, for callers
+    /// that only see a part of it.
+    pub(crate) listing: ReadCopyUpdate<Tree>,
+    /// The JSON of [Wheel::tree] by directory, if enabled, see [Wheel::set_fragments].
+    fragments: ReadCopyUpdate<Option<Fragments>>,
+    /// The files of `listing` that are not protected, see [Wheel::search].
+    ///
+    /// Updated with the files that changed, on a copy that shares what did not change, so
+    /// searches never wait for a tree to be published.
+    search: ReadCopyUpdate<SearchIndex>,
+    /// Counts of the combined tree, see [Wheel::stats].
+    stats: ReadCopyUpdate<TreeStats>,
+    /// Where the combined tree is saved after every refresh, see [Wheel::with_snapshot].
+    snapshot: Option<PathBuf>,
+    /// Becomes `true` once every driver has reported its tree or an error at least once.
+    ready: watch::Sender<bool>,
+    #[cfg(feature = "metrics")]
+    metrics: Arc<crate::metrics::Metrics>,
+}
+
+/// A directory of a published listing, see [Wheel::find_dir].
+pub(crate) struct ListedDir {
+    listing: Arc<Tree>,
+    /// The index of every subdirectory on the way from the root to the directory.
+    indices: Vec<usize>,
+    /// Relative to the root, like `a/b`.
+    path: String,
+    passwords: Arc<Passwords>,
+}
+
+impl ListedDir {
+    /// The directory as listed, protected directories below it included.
+    pub(crate) fn dir(&self) -> &Tree {
+        self.indices
+            .iter()
+            .fold(&self.listing, |dir, i| &dir.subdirectories()[*i])
+    }
+
+    #[cfg(feature = "server")]
+    /// The size and modification time of the directory with the protected directories below
+    /// it emptied, like [Passwords::lock_opened] would leave them.
+    pub(crate) fn usage(&self) -> (u64, std::time::SystemTime) {
+        self.passwords.usage(self.opened(), &self.path, self.dir())
+    }
+
+    #[cfg(feature = "server")]
+    /// Like [ListedDir::usage], for one of the subdirectories of the directory.
+    pub(crate) fn subdirectory_usage(&self, subdirectory: &Tree) -> (u64, std::time::SystemTime) {
+        let path = format!("{}/{}", self.path, subdirectory.name());
+        self.passwords.usage(self.opened(), &path, subdirectory)
+    }
+
+    #[cfg(feature = "server")]
+    /// The protected directory whose password opened the directory, if any.
+    fn opened(&self) -> Option<&str> {
+        self.passwords.protecting(&self.path).map(|(x, _)| x)
+    }
+}
+
+/// What the background task does before the regular refresh interval.
+enum Startup {
+    /// The drivers were already awaited.
+    Loaded,
+    /// A stale tree is served, refresh right away.
+    Refresh,
+    /// Nothing is served yet, publish every driver as soon as it finishes.
+    FillIn,
 }
 
 impl Wheel {
+    #[tracing::instrument(name = "Wheel::new", skip_all, fields(drivers = drivers.len()))]
     pub async fn new(drivers: Vec<Box<dyn GetVfs>>) -> Arc<Self> {
-        let dirs = join_all(drivers.iter().map(|x| x.get_vfs()).collect::<Vec<_>>()).await;
-        let dirs = dirs
+        let wheel = Self::build(drivers, empty_tree(), None);
+        wheel.refresh().await;
+        wheel.set_refresh_interval(Startup::Loaded)
+    }
+
+    /// Start without waiting for any driver.
+    ///
+    /// The tree saved at `snapshot` is served if there is one, and replaced once every driver
+    /// has finished. Otherwise the tree starts empty and every driver is added as soon as it
+    /// finishes. Use [Wheel::ready] or [Wheel::is_ready] to know when the tree is complete.
+    pub async fn start(drivers: Vec<Box<dyn GetVfs>>, snapshot: Option<PathBuf>) -> Arc<Self> {
+        let loaded = match &snapshot {
+            Some(snapshot) => snapshot::load(snapshot).await.ok(),
+            None => None,
+        };
+        match loaded {
+            Some(combined) => {
+                Self::build(drivers, combined, snapshot).set_refresh_interval(Startup::Refresh)
+            }
+            None => {
+                Self::build(drivers, empty_tree(), snapshot).set_refresh_interval(Startup::FillIn)
+            }
+        }
+    }
+
+    /// Start from the tree saved at `snapshot` instead of waiting for the drivers.
+    ///
+    /// The stale tree is served right away and replaced when the first refresh, which starts
+    /// immediately, finishes. Every refresh saves the new tree back to `snapshot`. If there is
+    /// no usable snapshot yet, this behaves like [Wheel::new] and saves the first tree.
+    pub async fn with_snapshot(
+        drivers: Vec<Box<dyn GetVfs>>,
+        snapshot: impl Into<PathBuf>,
+    ) -> Arc<Self> {
+        let snapshot = snapshot.into();
+        match snapshot::load(&snapshot).await {
+            Ok(combined) => Self::build(drivers, combined, Some(snapshot))
+                .set_refresh_interval(Startup::Refresh),
+            Err(_) => {
+                let wheel = Self::build(drivers, empty_tree(), Some(snapshot));
+                wheel.refresh().await;
+                wheel.set_refresh_interval(Startup::Loaded)
+            }
+        }
+    }
+
+    fn build(drivers: Vec<Box<dyn GetVfs>>, combined: Tree, snapshot: Option<PathBuf>) -> Self {
+        let drivers = drivers
             .into_iter()
-            .filter(|x| x.is_ok())
-            .map(|x| x.unwrap())
+            .enumerate()
+            .map(|(i, driver)| (DriverId(i as u64), Arc::from(driver)))
             .collect::<Vec<_>>();
-        let combined = CombinableDir::combine(dirs);
-        let combined_clone = combined.clone();
-        let path_map = ReadCopyUpdate::new(combined.compress_path());
-        let tree: DirWithoutLink = combined_clone.into();
-        let tree = serde_json::to_string(&tree).unwrap();
-        let tree = ReadCopyUpdate::new(tree);
+        let next_driver_id = AtomicU64::new(drivers.len() as u64);
+        // nothing to wait for without drivers
+        let ready = watch::Sender::new(drivers.is_empty());
+        // a snapshot does not tell what each driver contributed
+        let stats = ReadCopyUpdate::new(TreeStats::new(&combined, &[]));
+        let (path_map, listing) = published(combined);
+        let search = ReadCopyUpdate::new(SearchIndex::new(&listing));
+        let path_map = ReadCopyUpdate::new(path_map);
+        let listing = ReadCopyUpdate::new(listing);
         Self {
-            drivers,
+            drivers: ReadCopyUpdate::new(drivers),
+            next_driver_id,
+            trees: Mutex::new(HashMap::new()),
+            filter: ReadCopyUpdate::default(),
+            passwords: ReadCopyUpdate::default(),
+            unlocked: Unlocked::default(),
             path_map,
-            tree,
+            listing,
+            fragments: ReadCopyUpdate::default(),
+            search,
+            stats,
+            snapshot,
+            ready,
+            #[cfg(feature = "metrics")]
+            metrics: Arc::default(),
+        }
+    }
+
+    /// Whether every driver has reported its tree or an error at least once.
+    ///
+    /// A driver that fails counts as reported, so it cannot keep the wheel unready. It is
+    /// tried again on every refresh, and its files appear once it succeeds.
+    pub fn is_ready(&self) -> bool {
+        *self.ready.borrow()
+    }
+
+    /// Wait until every driver has reported its tree or an error at least once, see
+    /// [Wheel::is_ready].
+    pub async fn ready(&self) {
+        let mut ready = self.ready.subscribe();
+        // the sender lives as long as `self`, so this cannot fail
+        let _ = ready.wait_for(|ready| *ready).await;
+    }
+
+    /// The registered drivers with their IDs, in the order they are combined.
+    ///
+    /// Replaces the former public `drivers` field. This is a snapshot, drivers added or removed
+    /// later are not in it.
+    pub fn drivers(&self) -> Arc<Vec<(DriverId, Arc<dyn GetVfs>)>> {
+        self.drivers.read()
+    }
+
+    /// IDs of the registered drivers, in the order they are combined.
+    pub fn driver_ids(&self) -> Vec<DriverId> {
+        self.drivers.read().iter().map(|(id, _)| *id).collect()
+    }
+
+    /// Register a driver and publish the tree with its files right away.
+    ///
+    /// The driver is only registered if it can provide its tree, otherwise its error is
+    /// returned.
+    pub async fn add_driver(&self, driver: Box<dyn GetVfs>) -> Result<DriverId, String> {
+        let (mut added, _) = self.replace_drivers(&[], vec![driver]).await;
+        added.remove(0)
+    }
+
+    /// Unregister a driver and publish the tree without its files right away.
+    ///
+    /// Returns `false` if there is no driver with this ID.
+    pub async fn remove_driver(&self, id: DriverId) -> bool {
+        let (_, removed) = self.replace_drivers(&[id], vec![]).await;
+        !removed.is_empty()
+    }
+
+    /// Register the drivers of `add` in place of the ones of `remove`, and publish the tree
+    /// once, right away.
+    ///
+    /// The drivers of `add` provide their trees before anything changes, so the files of
+    /// `remove` are served until their replacements are ready. Those that fail are not
+    /// registered, and then the drivers of `remove` are all kept.
+    ///
+    /// Returns the ID or the error of every driver of `add`, and the IDs that were removed.
+    pub async fn replace_drivers(
+        &self,
+        remove: &[DriverId],
+        add: Vec<Box<dyn GetVfs>>,
+    ) -> (Vec<Result<DriverId, String>>, Vec<DriverId>) {
+        let loaded = join_all(add.into_iter().map(|driver| async move {
+            let id = DriverId(self.next_driver_id.fetch_add(1, Ordering::Relaxed));
+            let dir = self.load(id, driver.as_ref()).await?;
+            Ok((id, Arc::<dyn GetVfs>::from(driver), dir))
+        }))
+        .await;
+        let remove = match loaded.iter().all(|x| x.is_ok()) {
+            true => remove,
+            false => &[],
+        };
+        let mut trees = self.trees.lock().await;
+        let mut removed = vec![];
+        self.drivers.rcu(|drivers| {
+            removed.clear();
+            let mut drivers = drivers.clone();
+            drivers.retain(|(id, _)| match remove.contains(id) {
+                true => {
+                    removed.push(*id);
+                    false
+                }
+                false => true,
+            });
+            drivers.extend(loaded.iter().flatten().map(|(id, x, _)| (*id, x.clone())));
+            drivers
+        });
+        let mut added = Vec::with_capacity(loaded.len());
+        for result in loaded {
+            added.push(result.map(|(id, _, dir)| {
+                trees.insert(id, tagged(dir, id));
+                id
+            }));
+        }
+        for id in &removed {
+            trees.remove(id);
+        }
+        if !removed.is_empty() || added.iter().any(|x| x.is_ok()) {
+            self.publish_locked(&trees, true).await;
+        }
+        self.update_ready(&trees);
+        (added, removed)
+    }
+
+    /// Replace the filter applied to every driver and publish the filtered tree right away.
+    ///
+    /// Per driver filters are set with [Transform::Filter](crate::transform::Transform::Filter).
+    pub async fn set_filter(&self, filter: Filter) {
+        let trees = self.trees.lock().await;
+        self.filter.update(filter);
+        if trees.is_empty() && !self.drivers.read().is_empty() {
+            return;
         }
-        .set_refresh_interval()
+        self.publish_locked(&trees, true).await;
     }
 
+    /// Replace the password protected directories and publish the tree locked by them.
+    pub async fn set_passwords(&self, passwords: Passwords) {
+        let _trees = self.trees.lock().await;
+        self.passwords.update(passwords);
+        self.unlocked.clear();
+        let listing = self.listing.read();
+        let passwords = self.passwords.read();
+        self.search
+            .update(SearchIndex::new(&passwords.lock((*listing).clone())));
+        if self.fragments.read().is_some() {
+            self.fragments
+                .update(Some(Fragments::new(&listing, &passwords)));
+        }
+    }
+
+    /// Keep the JSON of [Wheel::tree] by directory, updated with the directories that change
+    /// every time a tree is published, instead of writing all of it for every request.
+    ///
+    /// Costs about the size of the JSON in memory.
+    pub async fn set_fragments(&self, enabled: bool) {
+        let _trees = self.trees.lock().await;
+        let fragments =
+            enabled.then(|| Fragments::new(&self.listing.read(), &self.passwords.read()));
+        self.fragments.update(fragments);
+    }
+
+    /// The listed tree as JSON, with the content of password protected directories left out,
+    /// like `{"name": "root", "files": [...], "subdirectories": [...], "size": 1024, ...}`.
+    pub fn tree(&self) -> String {
+        match &*self.fragments.read() {
+            Some(fragments) => fragments.to_string(),
+            None => tree_json::to_string(&self.listing.read(), &self.passwords.read()),
+        }
+    }
+
+    /// Like [Wheel::tree], in chunks of about [CHUNK_SIZE](tree_json::CHUNK_SIZE) bytes written
+    /// as they are consumed, so the JSON of a large tree is never in memory at once, unless it
+    /// is kept by [Wheel::set_fragments].
+    pub fn tree_chunks(&self) -> BoxStream<'static, Vec<u8>> {
+        match &*self.fragments.read() {
+            Some(fragments) => fragments.chunks().boxed(),
+            None => tree_json::chunks(self.listing.read(), self.passwords.read()).boxed(),
+        }
+    }
+
+    /// Which drivers the file or directory at `path`, like a key of [Wheel::path_map] or
+    /// `root/dir`, came from, or `None` if nothing is downloadable there.
+    ///
+    /// Directories are seen through the downloadable files below them. Passwords are not
+    /// checked, so only show this to administrators.
+    pub fn provenance(&self, path: &str) -> Option<Provenance> {
+        let path_map = self.path_map.read();
+        if let Some(file) = path_map.get(path) {
+            let links = file
+                .links
+                .iter()
+                .cloned()
+                .zip(file.sources.iter().copied())
+                .collect::<Vec<_>>();
+            let mut drivers = links.iter().map(|(_, id)| *id).collect::<Vec<_>>();
+            drivers.sort();
+            drivers.dedup();
+            return Some(Provenance { drivers, links });
+        }
+        let prefix = format!("{}/", path.trim_end_matches('/'));
+        let mut drivers = path_map
+            .iter()
+            .filter(|(x, _)| x.starts_with(&prefix))
+            .flat_map(|(_, file)| file.sources.iter().copied())
+            .collect::<Vec<_>>();
+        if drivers.is_empty() {
+            return None;
+        }
+        drivers.sort();
+        drivers.dedup();
+        Some(Provenance {
+            drivers,
+            links: vec![],
+        })
+    }
+
+    /// The metrics of this `Wheel`, with the `metrics` feature.
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&self) -> &Arc<crate::metrics::Metrics> {
+        &self.metrics
+    }
+
+    /// Counts of the combined tree, updated every time a tree is published.
+    ///
+    /// They cover hidden and private files too, so only show them to administrators.
+    pub fn stats(&self) -> Arc<TreeStats> {
+        self.stats.read()
+    }
+
+    /// The listed files matching `query`, with the content of protected directories left out,
+    /// as in [Wheel::tree].
+    ///
+    /// The index follows every published tree. `Err` if the query is invalid.
+    pub fn search(&self, query: &Query) -> Result<SearchResults, String> {
+        self.search.read().search(query)
+    }
+
+    /// Like [Wheel::search], with only the files whose path is `allowed`.
+    pub(crate) fn search_filtered(
+        &self,
+        query: &Query,
+        allowed: impl Fn(&str) -> bool,
+    ) -> Result<SearchResults, String> {
+        self.search.read().search_filtered(query, allowed)
+    }
+
+    /// The file at `path`, a key of [Wheel::path_map], if it is not in a protected directory
+    /// or `password` opens it.
+    ///
+    /// A password is hashed on a blocking thread the first time it opens a directory, and
+    /// remembered until [Wheel::set_passwords].
+    pub async fn lookup(&self, path: &str, password: Option<&str>) -> Lookup<StaticCombinableFile> {
+        if let Some(protected) = self.password_required(path, password).await {
+            return Lookup::PasswordRequired { protected };
+        }
+        match self.path_map.read().get(path) {
+            Some(file) => Lookup::Found(file.clone()),
+            None => Lookup::NotFound,
+        }
+    }
+
+    /// The listed directory at `path`, like `root/internal` or `root` for the root, with the
+    /// content of the protected directories below it left out, see [Wheel::lookup].
+    pub async fn list(&self, path: &str, password: Option<&str>) -> Lookup<DirWithoutLink> {
+        self.find_dir(path, password).await.map(|dir| {
+            dir.passwords
+                .lock_opened(&dir.path, dir.dir().clone())
+                .into()
+        })
+    }
+
+    /// Like [Wheel::list], borrowing the directory from the listing instead of copying it.
+    pub(crate) async fn find_dir(&self, path: &str, password: Option<&str>) -> Lookup<ListedDir> {
+        if let Some(protected) = self.password_required(path, password).await {
+            return Lookup::PasswordRequired { protected };
+        }
+        let listing = self.listing.read();
+        let mut segments = path.split('/').filter(|x| !x.is_empty());
+        if segments.next() != Some(listing.name()) {
+            return Lookup::NotFound;
+        }
+        let mut dir: &Tree = &listing;
+        let mut indices = vec![];
+        for segment in segments {
+            match dir
+                .subdirectories()
+                .iter()
+                .position(|x| x.name() == segment)
+            {
+                Some(i) => {
+                    dir = &dir.subdirectories()[i];
+                    indices.push(i);
+                }
+                None => return Lookup::NotFound,
+            }
+        }
+        let relative = path.split_once('/').map_or("", |(_, x)| x);
+        Lookup::Found(ListedDir {
+            listing,
+            indices,
+            path: relative.to_string(),
+            passwords: self.passwords.read(),
+        })
+    }
+
+    /// The protected directory `path` is in, if `password` does not open it.
+    async fn password_required(&self, path: &str, password: Option<&str>) -> Option<String> {
+        // the first segment is the name of the root
+        let relative = path.split_once('/').map_or("", |(_, x)| x);
+        let passwords = self.passwords.read();
+        let (protected, hash) = passwords.protecting(relative)?;
+        match password {
+            Some(password) if self.unlocked.unlocks(protected, hash, password).await => None,
+            _ => Some(protected.to_string()),
+        }
+    }
+
+    /// Reload every driver and publish the combined tree.
+    ///
+    /// A driver that fails keeps contributing the last tree it reported.
+    #[tracing::instrument(skip_all)]
     async fn refresh(&self) {
-        let dirs = join_all(self.drivers.iter().map(|x| x.get_vfs()).collect::<Vec<_>>()).await;
-        let dirs = dirs
-            .into_iter()
-            .filter(|x| x.is_ok())
-            .map(|x| x.unwrap())
+        let drivers = self.drivers.read();
+        let results = join_all(
+            drivers
+                .iter()
+                .map(|(id, driver)| async move { (*id, self.load(*id, driver.as_ref()).await) }),
+        )
+        .await;
+        self.apply(results, true).await;
+        self.round_finished();
+    }
+
+    /// Publish every driver's tree as soon as it arrives, then save the complete one.
+    #[tracing::instrument(skip_all)]
+    async fn fill_in(&self) {
+        let drivers = self.drivers.read();
+        let mut pending = drivers
+            .iter()
+            .map(|(id, driver)| async move { (*id, self.load(*id, driver.as_ref()).await) })
+            .collect::<FuturesUnordered<_>>();
+        while let Some(result) = pending.next().await {
+            self.apply(vec![result], false).await;
+        }
+        let trees = self.trees.lock().await;
+        if !trees.is_empty() {
+            self.save_snapshot(&self.combine(&trees)).await;
+        }
+        self.round_finished();
+    }
+
+    /// Become ready once every registered driver has reported a tree, see [Wheel::is_ready].
+    ///
+    /// `trees` only holds the trees of registered drivers, and a driver is only added once it
+    /// reported one, so a ready wheel stays ready. Drivers that failed are only counted by
+    /// [Wheel::round_finished].
+    fn update_ready(&self, trees: &HashMap<DriverId, Tree>) {
+        if self
+            .drivers
+            .read()
+            .iter()
+            .all(|(id, _)| trees.contains_key(id))
+        {
+            self.ready.send_replace(true);
+        }
+    }
+
+    /// Become ready once every driver of a refresh has reported its tree or an error.
+    ///
+    /// The drivers registered meanwhile were only added with their tree, so every registered
+    /// driver has reported by now.
+    fn round_finished(&self) {
+        self.ready.send_replace(true);
+    }
+
+    /// Store the trees that drivers reported and publish the new combined tree.
+    async fn apply(&self, results: Vec<(DriverId, Result<Tree, String>)>, save_snapshot: bool) {
+        let mut trees = self.trees.lock().await;
+        let drivers = self.drivers.read();
+        for (id, result) in results {
+            // the driver may have been removed while it was loading
+            if let (Ok(dir), true) = (result, drivers.iter().any(|(x, _)| *x == id)) {
+                trees.insert(id, tagged(dir, id));
+            }
+        }
+        // keep serving the previous tree, maybe a snapshot, until some driver reported
+        if trees.is_empty() && !drivers.is_empty() {
+            return;
+        }
+        self.publish_locked(&trees, save_snapshot).await;
+    }
+
+    /// Filter and combine the stored trees in driver order.
+    fn combine(&self, trees: &HashMap<DriverId, Tree>) -> Tree {
+        combine_all(self.filtered(trees))
+    }
+
+    /// The stored trees in driver order, filtered.
+    fn filtered<'a>(&self, trees: &'a HashMap<DriverId, Tree>) -> Vec<(DriverId, Cow<'a, Tree>)> {
+        let filter = self.filter.read();
+        self.drivers
+            .read()
+            .iter()
+            .filter_map(|(id, _)| Some((*id, filter.apply_ref(trees.get(id)?))))
+            .collect()
+    }
+
+    /// Must be called with `trees` locked.
+    async fn publish_locked(&self, trees: &HashMap<DriverId, Tree>, save_snapshot: bool) {
+        let filtered = self.filtered(trees);
+        let usage = filtered
+            .iter()
+            .map(|(id, tree)| (*id, Usage::of(tree)))
             .collect::<Vec<_>>();
-        let combined = CombinableDir::combine(dirs);
-        let combined_clone = combined.clone();
-        let new_path_map = combined.compress_path();
-        let new_tree: DirWithoutLink = combined_clone.into();
-        let new_tree = serde_json::to_string(&new_tree).unwrap();
+        let combined = tracing::debug_span!("combine", drivers = filtered.len())
+            .in_scope(|| combine_all(filtered));
+        let stats = TreeStats::new(&combined, &usage);
+        #[cfg(feature = "metrics")]
+        self.metrics
+            .tree_published(stats.total.files, stats.total.size);
+        self.stats.update(stats);
+        if save_snapshot {
+            self.save_snapshot(&combined).await;
+        }
+        let (new_path_map, new_listing) = published(combined);
+        let diff = diff::diff(&self.listing.read(), &new_listing);
+        if !diff.is_empty() {
+            self.update_search(&diff);
+            if let Some(fragments) = &*self.fragments.read() {
+                let fragments = fragments.update(&new_listing, &self.passwords.read(), &diff);
+                self.fragments.update(Some(fragments));
+            }
+        }
         self.path_map.update(new_path_map);
-        self.tree.update(new_tree);
+        self.listing.update(new_listing);
+    }
+
+    /// Apply the files that changed in the listing to the index, leaving out the protected
+    /// ones, see [Wheel::search].
+    fn update_search(&self, diff: &TreeDiff) {
+        let passwords = self.passwords.read();
+        // the first segment is the name of the root
+        let open = |path: &String| {
+            let relative = path.split_once('/').map_or("", |(_, x)| x);
+            passwords.protecting(relative).is_none()
+        };
+        let diff = TreeDiff {
+            added: diff.added.iter().filter(|x| open(&x.0)).cloned().collect(),
+            modified: diff
+                .modified
+                .iter()
+                .filter(|x| open(&x.0))
+                .cloned()
+                .collect(),
+            removed: diff.removed.clone(),
+        };
+        self.search.rcu(|index| {
+            let mut index = index.clone();
+            index.apply(&diff);
+            index
+        });
+    }
+
+    /// The tree of the driver `id`, logging why it failed.
+    #[tracing::instrument(skip_all, fields(driver = %id, name = driver.name()))]
+    async fn load(&self, id: DriverId, driver: &dyn GetVfs) -> Result<Tree, String> {
+        let start = Instant::now();
+        let result = driver.get_vfs().await;
+        let elapsed = start.elapsed();
+        match &result {
+            Ok(_) => tracing::debug!(elapsed_ms = elapsed.as_millis() as u64, "tree loaded"),
+            Err(error) => tracing::warn!(
+                elapsed_ms = elapsed.as_millis() as u64,
+                %error,
+                "cannot load tree"
+            ),
+        }
+        #[cfg(feature = "metrics")]
+        self.metrics.driver_refreshed(id, elapsed, result.is_ok());
+        result
     }
 
-    fn set_refresh_interval(self) -> Arc<Self> {
+    async fn save_snapshot(&self, combined: &Tree) {
+        if let Some(snapshot) = &self.snapshot {
+            if let Err(error) = snapshot::save(snapshot, combined).await {
+                tracing::warn!(%error, "cannot save snapshot");
+            }
+        }
+    }
+
+    fn set_refresh_interval(self, startup: Startup) -> Arc<Self> {
         let arc_self = Arc::new(self);
         let arc_self_clone = arc_self.clone();
         tokio::spawn(async move {
+            match startup {
+                Startup::Loaded => {}
+                Startup::Refresh => arc_self_clone.refresh().await,
+                Startup::FillIn => arc_self_clone.fill_in().await,
+            }
             loop {
                 time::sleep(Duration::from_secs(60)).await;
                 arc_self_clone.refresh().await;
@@ -65,3 +705,479 @@ impl Wheel {
         arc_self
     }
 }
+
+/// What is downloadable by path and what is listed, see [Visibility].
+fn published(combined: Tree) -> (HashMap<String, StaticCombinableFile>, Tree) {
+    let path_map = visibility::restricted_paths(&combined, Visibility::Hidden);
+    let listing = visibility::restrict(combined, Visibility::Public);
+    (path_map, listing)
+}
+
+/// `dir` with every link of its files marked as coming from the driver `id`.
+fn tagged(dir: Tree, id: DriverId) -> Tree {
+    let visibility = dir.visibility();
+    let (name, files, subdirectories) = dir.destruct();
+    let files = files
+        .into_iter()
+        .map(|file| {
+            let sources = vec![id; file.links.len()];
+            file.with_sources(sources)
+        })
+        .collect();
+    let subdirectories = subdirectories.into_iter().map(|x| tagged(x, id)).collect();
+    CombinableDir::new(name, files, subdirectories).with_visibility(visibility)
+}
+
+/// The trees are only copied here, unless the filter already did.
+fn combine_all(dirs: Vec<(DriverId, Cow<Tree>)>) -> Tree {
+    if dirs.is_empty() {
+        return empty_tree();
+    }
+    CombinableDir::combine(dirs.into_iter().map(|(_, x)| x.into_owned()).collect())
+}
+
+fn empty_tree() -> Tree {
+    CombinableDir::new(String::new(), vec![], vec![])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::static_combinable::{test_file, test_paths, test_tree, TestDriver};
+    use async_trait::async_trait;
+    use tokio::sync::Notify;
+
+    /// Serves a single file named `name`, but only once `ready` is notified.
+    struct SlowDriver {
+        name: &'static str,
+        ready: Arc<Notify>,
+    }
+
+    #[async_trait]
+    impl GetVfs for SlowDriver {
+        async fn get_vfs(&self) -> Result<CombinableDir<StaticCombinableFile>, String> {
+            self.ready.notified().await;
+            Ok(test_tree(vec![test_file(self.name, 1024)]))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_warm_start_from_snapshot() {
+        let path = std::env::temp_dir().join(format!("rlist-wheel-{}.json", std::process::id()));
+        let stale = test_tree(vec![test_file("stale", 1024)]);
+        snapshot::save(&path, &stale).await.unwrap();
+
+        let ready = Arc::new(Notify::new());
+        let driver = SlowDriver {
+            name: "fresh",
+            ready: ready.clone(),
+        };
+        let wheel = Wheel::with_snapshot(vec![Box::new(driver)], &path).await;
+        assert!(wheel.path_map.read().contains_key("root/stale"));
+        assert!(!wheel.is_ready());
+
+        ready.notify_one();
+        while !wheel.path_map.read().contains_key("root/fresh") {
+            tokio::task::yield_now().await;
+        }
+        assert!(!wheel.path_map.read().contains_key("root/stale"));
+        wheel.ready().await;
+
+        let saved = snapshot::load(&path).await.unwrap();
+        tokio::fs::remove_file(&path).await.unwrap();
+        assert_eq!(test_paths(&saved.compress_path()), vec!["root/fresh"]);
+    }
+
+    #[tokio::test]
+    async fn test_start_fills_in_drivers() {
+        let first = Arc::new(Notify::new());
+        let second = Arc::new(Notify::new());
+        let drivers: Vec<Box<dyn GetVfs>> = vec![
+            Box::new(SlowDriver {
+                name: "first",
+                ready: first.clone(),
+            }),
+            Box::new(SlowDriver {
+                name: "second",
+                ready: second.clone(),
+            }),
+        ];
+        let wheel = Wheel::start(drivers, None).await;
+        assert!(wheel.path_map.read().is_empty());
+        assert!(!wheel.is_ready());
+
+        first.notify_one();
+        while !wheel.path_map.read().contains_key("root/first") {
+            tokio::task::yield_now().await;
+        }
+        assert!(!wheel.path_map.read().contains_key("root/second"));
+        assert!(!wheel.is_ready());
+
+        second.notify_one();
+        wheel.ready().await;
+        assert!(wheel.is_ready());
+        assert!(wheel.path_map.read().contains_key("root/first"));
+        assert!(wheel.path_map.read().contains_key("root/second"));
+    }
+
+    #[tokio::test]
+    async fn test_ready_when_a_driver_keeps_failing() {
+        let wheel = Wheel::new(vec![
+            Box::new(TestDriver::file("a")),
+            Box::new(TestDriver::failing("unavailable")),
+        ])
+        .await;
+        assert!(wheel.is_ready());
+        assert_eq!(test_paths(&wheel.path_map.read()), vec!["root/a"]);
+        assert!(Wheel::new(vec![]).await.is_ready());
+
+        let loaded = Arc::new(Notify::new());
+        let drivers: Vec<Box<dyn GetVfs>> = vec![
+            Box::new(SlowDriver {
+                name: "slow",
+                ready: loaded.clone(),
+            }),
+            Box::new(TestDriver::failing("unavailable")),
+        ];
+        let wheel = Wheel::start(drivers, None).await;
+        // the failing driver alone does not make the wheel ready
+        tokio::task::yield_now().await;
+        assert!(!wheel.is_ready());
+
+        loaded.notify_one();
+        wheel.ready().await;
+        assert_eq!(test_paths(&wheel.path_map.read()), vec!["root/slow"]);
+    }
+
+    #[tokio::test]
+    async fn test_add_and_remove_driver() {
+        let wheel = Wheel::new(vec![Box::new(TestDriver::file("a"))]).await;
+        let first = wheel.driver_ids()[0];
+        assert!(wheel.path_map.read().contains_key("root/a"));
+
+        let second = wheel
+            .add_driver(Box::new(TestDriver::file("b")))
+            .await
+            .unwrap();
+        assert_ne!(first, second);
+        assert_eq!(wheel.driver_ids(), vec![first, second]);
+        assert!(wheel.path_map.read().contains_key("root/a"));
+        assert!(wheel.path_map.read().contains_key("root/b"));
+
+        assert!(wheel.remove_driver(first).await);
+        assert!(!wheel.remove_driver(first).await);
+        assert_eq!(wheel.driver_ids(), vec![second]);
+        let drivers = wheel.drivers();
+        assert_eq!(drivers.len(), 1);
+        assert_eq!(drivers[0].0, second);
+        assert!(drivers[0].1.get_vfs().await.is_ok());
+        assert!(!wheel.path_map.read().contains_key("root/a"));
+        assert!(wheel.path_map.read().contains_key("root/b"));
+
+        // IDs are not reused
+        let third = wheel
+            .add_driver(Box::new(TestDriver::file("c")))
+            .await
+            .unwrap();
+        assert!(third != first && third != second);
+
+        assert!(wheel.remove_driver(second).await);
+        assert!(wheel.remove_driver(third).await);
+        assert!(wheel.path_map.read().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_replace_drivers() {
+        let wheel = Wheel::new(vec![Box::new(TestDriver::file("a"))]).await;
+        let old = wheel.driver_ids()[0];
+
+        // a failing replacement keeps the old driver
+        let (added, removed) = wheel
+            .replace_drivers(
+                &[old],
+                vec![
+                    Box::new(TestDriver::file("b")),
+                    Box::new(TestDriver::failing("unavailable")),
+                ],
+            )
+            .await;
+        assert!(added[0].is_ok() && added[1].is_err());
+        assert!(removed.is_empty());
+        assert!(wheel.path_map.read().contains_key("root/a"));
+        assert!(wheel.path_map.read().contains_key("root/b"));
+
+        let (added, removed) = wheel
+            .replace_drivers(&[old], vec![Box::new(TestDriver::file("c"))])
+            .await;
+        let new = added[0].clone().unwrap();
+        assert_eq!(removed, vec![old]);
+        assert!(!wheel.driver_ids().contains(&old));
+        assert!(wheel.driver_ids().contains(&new));
+        assert!(!wheel.path_map.read().contains_key("root/a"));
+        assert!(wheel.path_map.read().contains_key("root/c"));
+    }
+
+    #[tokio::test]
+    async fn test_add_failing_driver() {
+        let wheel = Wheel::new(vec![Box::new(TestDriver::file("a"))]).await;
+        let result = wheel
+            .add_driver(Box::new(TestDriver::failing("unavailable")))
+            .await;
+        assert_eq!(result.err().unwrap(), "unavailable");
+        assert_eq!(wheel.driver_ids().len(), 1);
+    }
+
+    /// Keeps the name and fields of every span and event, like `load{driver=driver-0}`.
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<std::sync::Mutex<Vec<String>>>);
+
+    struct Fields(Vec<String>);
+
+    impl tracing::field::Visit for Fields {
+        fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+            self.0.push(format!("{}={}", field.name(), value));
+        }
+
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn fmt::Debug) {
+            self.0.push(format!("{}={:?}", field.name(), value));
+        }
+    }
+
+    impl Recorder {
+        fn push(&self, name: &str, record: impl FnOnce(&mut Fields)) {
+            let mut fields = Fields(vec![]);
+            record(&mut fields);
+            let entry = format!("{}{{{}}}", name, fields.0.join(","));
+            self.0.lock().unwrap().push(entry);
+        }
+    }
+
+    impl tracing::Subscriber for Recorder {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            self.push(span.metadata().name(), |x| span.record(x));
+            tracing::span::Id::from_u64(self.0.lock().unwrap().len() as u64)
+        }
+
+        fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+        fn event(&self, event: &tracing::Event<'_>) {
+            self.push(event.metadata().level().as_str(), |x| event.record(x));
+        }
+
+        fn enter(&self, _: &tracing::span::Id) {}
+
+        fn exit(&self, _: &tracing::span::Id) {}
+    }
+
+    #[tokio::test]
+    async fn test_tracing() {
+        let recorder = Recorder::default();
+        let _guard = tracing::subscriber::set_default(recorder.clone());
+        let wheel = Wheel::new(vec![Box::new(TestDriver::file("a"))]).await;
+        let _ = wheel
+            .add_driver(Box::new(TestDriver::failing("unavailable")))
+            .await;
+        wheel.tree();
+
+        let entries = recorder.0.lock().unwrap().clone();
+        let name = std::any::type_name::<TestDriver>();
+        for entry in [
+            "Wheel::new{drivers=1}".to_string(),
+            "refresh{}".to_string(),
+            format!("load{{driver=driver-0,name={}}}", name),
+            "combine{drivers=1}".to_string(),
+            "serialize{}".to_string(),
+            format!("load{{driver=driver-1,name={}}}", name),
+        ] {
+            assert!(
+                entries.contains(&entry),
+                "missing {} in {:?}",
+                entry,
+                entries
+            );
+        }
+        assert!(entries
+            .iter()
+            .any(|x| x.starts_with("WARN{") && x.contains("error=unavailable")));
+    }
+
+    #[tokio::test]
+    async fn test_set_filter() {
+        let wheel = Wheel::new(vec![
+            Box::new(TestDriver::file("a.iso")),
+            Box::new(TestDriver::file("b.part")),
+        ])
+        .await;
+        let filter = serde_json::from_str(r#"{"exclude": [{"glob": "*.part"}]}"#).unwrap();
+        wheel.set_filter(filter).await;
+        assert_eq!(test_paths(&wheel.path_map.read()), vec!["root/a.iso"]);
+        assert!(!wheel.tree().contains("b.part"));
+    }
+
+    #[tokio::test]
+    async fn test_visibility() {
+        let wheel = Wheel::new(vec![
+            Box::new(TestDriver::file("a")),
+            Box::new(TestDriver::file("b")),
+            Box::new(TestDriver::file("c")),
+        ])
+        .await;
+        let filter =
+            serde_json::from_str(r#"{"hide": [{"glob": "b"}], "private": [{"glob": "c"}]}"#)
+                .unwrap();
+        wheel.set_filter(filter).await;
+        assert_eq!(test_paths(&wheel.path_map.read()), vec!["root/a", "root/b"]);
+        let tree = wheel.tree();
+        assert!(tree.contains("\"a\"") && !tree.contains("\"b\"") && !tree.contains("\"c\""));
+    }
+
+    #[tokio::test]
+    async fn test_passwords() {
+        let wheel = Wheel::new(vec![Box::new(TestDriver::file("a"))]).await;
+        let hash = bcrypt::hash("secret", 4).unwrap();
+        let passwords = serde_json::from_str(&format!(r#"{{"/": "{}"}}"#, hash)).unwrap();
+        assert!(wheel.lookup("root/a", None).await.found().is_some());
+        wheel.set_passwords(passwords).await;
+
+        assert!(wheel.lookup("root/a", None).await.is_password_required());
+        assert!(wheel
+            .lookup("root/a", Some("wrong"))
+            .await
+            .is_password_required());
+        assert!(wheel
+            .lookup("root/a", Some("secret"))
+            .await
+            .found()
+            .is_some());
+        assert!(wheel
+            .lookup("root/b", Some("secret"))
+            .await
+            .found()
+            .is_none());
+        // not probeable without the password
+        assert!(wheel.lookup("root/b", None).await.is_password_required());
+
+        assert!(!wheel.tree().contains("\"a\""));
+        assert!(wheel.list("root", None).await.is_password_required());
+        let root = wheel.list("root", Some("secret")).await.found().unwrap();
+        assert_eq!(root.files[0].name, "a");
+        assert!(wheel
+            .list("root/missing", Some("secret"))
+            .await
+            .found()
+            .is_none());
+        assert!(wheel.list("other", Some("secret")).await.found().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_tree_fragments() {
+        let wheel = Wheel::new(vec![Box::new(TestDriver::file("a"))]).await;
+        let written = wheel.tree();
+        wheel.set_fragments(true).await;
+        assert_eq!(wheel.tree(), written);
+
+        let id = wheel
+            .add_driver(Box::new(TestDriver::file("b")))
+            .await
+            .unwrap();
+        let tree: serde_json::Value = serde_json::from_str(&wheel.tree()).unwrap();
+        assert_eq!(tree["files"].as_array().unwrap().len(), 2);
+        let chunks = wheel.tree_chunks().collect::<Vec<_>>().await;
+        assert_eq!(chunks.concat(), wheel.tree().into_bytes());
+
+        wheel.remove_driver(id).await;
+        let fragmented = wheel.tree();
+        assert!(!fragmented.contains("\"b\""));
+        wheel.set_fragments(false).await;
+        assert_eq!(wheel.tree(), fragmented);
+    }
+
+    #[tokio::test]
+    async fn test_stats() {
+        let wheel = Wheel::new(vec![
+            Box::new(TestDriver::file("a")),
+            Box::new(TestDriver::file("a")),
+        ])
+        .await;
+        let stats = wheel.stats();
+        assert_eq!(stats.total.files, 1);
+        assert_eq!(stats.drivers.len(), 2);
+        assert_eq!(stats.drivers["driver-1"].size, 1024);
+
+        let filter = serde_json::from_str(r#"{"exclude": [{"glob": "a"}]}"#).unwrap();
+        wheel.set_filter(filter).await;
+        assert_eq!(wheel.stats().total.files, 0);
+        assert_eq!(wheel.stats().drivers["driver-0"].files, 0);
+    }
+
+    #[tokio::test]
+    async fn test_provenance() {
+        let wheel = Wheel::new(vec![
+            Box::new(TestDriver::file("a")),
+            Box::new(TestDriver::file("b")),
+            Box::new(TestDriver::file("a")),
+        ])
+        .await;
+        let [first, second, third] = wheel.driver_ids()[..] else {
+            panic!("three drivers");
+        };
+        let a = wheel.provenance("root/a").unwrap();
+        assert_eq!(a.drivers, vec![first, third]);
+        assert_eq!(
+            a.links,
+            vec![
+                ("https://example.com/a".to_string(), first),
+                ("https://example.com/a".to_string(), third)
+            ]
+        );
+        assert_eq!(wheel.provenance("root/b").unwrap().drivers, vec![second]);
+        let root = wheel.provenance("root").unwrap();
+        assert_eq!(root.drivers, vec![first, second, third]);
+        assert!(root.links.is_empty());
+        assert!(wheel.provenance("root/c").is_none());
+
+        let json = serde_json::to_value(wheel.provenance("root/b").unwrap()).unwrap();
+        assert_eq!(json["drivers"][0], second.to_string());
+    }
+
+    #[tokio::test]
+    async fn test_search() {
+        let wheel = Wheel::new(vec![
+            Box::new(TestDriver::file("a.iso")),
+            Box::new(TestDriver::file("b.iso")),
+        ])
+        .await;
+        let query = Query {
+            text: "iso".to_string(),
+            ..Query::default()
+        };
+        assert_eq!(wheel.search(&query).unwrap().total, 2);
+
+        let filter = serde_json::from_str(r#"{"hide": [{"glob": "b.iso"}]}"#).unwrap();
+        wheel.set_filter(filter).await;
+        let hits = wheel.search(&query).unwrap().hits;
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].path, "root/a.iso");
+
+        let hash = bcrypt::hash("secret", 4).unwrap();
+        let passwords = serde_json::from_str(&format!(r#"{{"/": "{}"}}"#, hash)).unwrap();
+        wheel.set_passwords(passwords).await;
+        assert_eq!(wheel.search(&query).unwrap().total, 0);
+        // protected files stay out of the index as drivers come and go
+        wheel
+            .add_driver(Box::new(TestDriver::file("c.iso")))
+            .await
+            .unwrap();
+        assert_eq!(wheel.search(&query).unwrap().total, 0);
+
+        wheel.set_passwords(Passwords::default()).await;
+        wheel.set_filter(Filter::default()).await;
+        assert_eq!(wheel.search(&query).unwrap().total, 3);
+    }
+}
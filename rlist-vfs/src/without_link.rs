@@ -20,21 +20,21 @@ pub struct DirWithoutLink {
     pub last_modified: DateTime<Utc>,
 }
 
-impl Into<FileWithoutLink> for StaticCombinableFile {
-    fn into(self) -> FileWithoutLink {
+impl From<StaticCombinableFile> for FileWithoutLink {
+    fn from(file: StaticCombinableFile) -> Self {
         FileWithoutLink {
-            name: self.name,
-            size: self.size,
-            last_modified: self.last_modified.into(),
+            name: file.name,
+            size: file.size,
+            last_modified: file.last_modified.into(),
         }
     }
 }
 
-impl Into<DirWithoutLink> for CombinableDir<StaticCombinableFile> {
-    fn into(self) -> DirWithoutLink {
-        let size = self.size();
-        let last_modified = self.last_modified();
-        let (name, files, subdirectories) = self.destruct();
+impl From<CombinableDir<StaticCombinableFile>> for DirWithoutLink {
+    fn from(dir: CombinableDir<StaticCombinableFile>) -> Self {
+        let size = dir.size();
+        let last_modified = dir.last_modified();
+        let (name, files, subdirectories) = dir.destruct();
         let files = files.into_iter().map(|x| x.into()).collect();
         let subdirectories = subdirectories.into_iter().map(|x| x.into()).collect();
         DirWithoutLink {
@@ -50,16 +50,17 @@ impl Into<DirWithoutLink> for CombinableDir<StaticCombinableFile> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::static_combinable::StaticDownloadLinkFile;
     use std::time::SystemTime;
 
     #[test]
     fn test_file_without_link() {
-        let file = StaticCombinableFile {
-            name: "test".to_string(),
-            size: 1024,
-            last_modified: SystemTime::now(),
-            links: vec!["https://example.com".to_string()],
-        };
+        let file = StaticCombinableFile::new(
+            "test".to_string(),
+            1024,
+            SystemTime::now(),
+            vec!["https://example.com".to_string()],
+        );
         let without_link: FileWithoutLink = file.clone().into();
         assert_eq!(without_link.name, "test");
         assert_eq!(without_link.size, 1024);
@@ -67,24 +68,24 @@ mod tests {
 
     #[test]
     fn test_dir_without_link() {
-        let file1 = StaticCombinableFile {
-            name: "test1".to_string(),
-            size: 1024,
-            last_modified: SystemTime::now(),
-            links: vec!["https://example.com/1".to_string()],
-        };
-        let file2 = StaticCombinableFile {
-            name: "test2".to_string(),
-            size: 1024,
-            last_modified: SystemTime::now(),
-            links: vec!["https://example.com/2".to_string()],
-        };
-        let file3 = StaticCombinableFile {
-            name: "test3".to_string(),
-            size: 1024,
-            last_modified: SystemTime::now(),
-            links: vec!["https://example.com/3".to_string()],
-        };
+        let file1 = StaticCombinableFile::new(
+            "test1".to_string(),
+            1024,
+            SystemTime::now(),
+            vec!["https://example.com/1".to_string()],
+        );
+        let file2 = StaticCombinableFile::new(
+            "test2".to_string(),
+            1024,
+            SystemTime::now(),
+            vec!["https://example.com/2".to_string()],
+        );
+        let file3 = StaticCombinableFile::new(
+            "test3".to_string(),
+            1024,
+            SystemTime::now(),
+            vec!["https://example.com/3".to_string()],
+        );
         let dir1 = CombinableDir::new(
             "dir1".to_string(),
             vec![file1.clone(), file2.clone()],
@@ -137,12 +138,7 @@ mod tests {
         let link1 = format!("https://example.com/{}", name);
         let link2 = format!("https://example.org/{}", name);
 
-        StaticCombinableFile {
-            name,
-            size: 1024,
-            last_modified,
-            links: vec![link1, link2],
-        }
+        StaticCombinableFile::new(name, 1024, last_modified, vec![link1, link2])
     }
 
     #[test]